@@ -0,0 +1,125 @@
+//! Record/replay of api traffic, for bug reproduction and offline demos
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    replay: bool,
+    interactions: Vec<Interaction>,
+    used: Vec<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Interaction {
+    pub request: Value,
+    pub reply: String,
+}
+
+impl Cassette {
+    pub fn record(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            replay: false,
+            interactions: vec![],
+            used: vec![],
+        }
+    }
+
+    pub fn replay(path: &Path) -> Result<Self> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to load cassette at {}", path.display()))?;
+        let interactions: Vec<Interaction> = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid cassette at {}", path.display()))?;
+        let used = vec![false; interactions.len()];
+        Ok(Self {
+            path: path.to_path_buf(),
+            replay: true,
+            interactions,
+            used,
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay
+    }
+
+    /// Find the first unused interaction with the same messages
+    pub fn take_reply(&mut self, request: &Value) -> Result<String> {
+        let index = self
+            .interactions
+            .iter()
+            .enumerate()
+            .position(|(i, v)| !self.used[i] && v.request["messages"] == request["messages"])
+            .ok_or_else(|| {
+                anyhow!(
+                    "No recorded interaction matches the request in {}",
+                    self.path.display()
+                )
+            })?;
+        self.used[index] = true;
+        Ok(self.interactions[index].reply.clone())
+    }
+
+    pub fn add(&mut self, request: &Value, reply: &str) -> Result<()> {
+        if self.replay {
+            return Ok(());
+        }
+        let mut request = request.clone();
+        if let Some(map) = request.as_object_mut() {
+            map.remove("stream");
+        }
+        self.interactions.push(Interaction {
+            request,
+            reply: reply.to_string(),
+        });
+        let content = serde_yaml::to_string(&self.interactions)
+            .with_context(|| "Failed to serde cassette")?;
+        write(&self.path, content)
+            .with_context(|| format!("Failed to write cassette to {}", self.path.display()))
+    }
+}
+
+/// Split a reply into chunks, so replaying exercises the streaming render
+pub fn split_chunks(reply: &str) -> Vec<&str> {
+    reply.split_inclusive(char::is_whitespace).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_take_reply() {
+        let request = |text: &str| json!({"messages": [{"role": "user", "content": text}]});
+        let mut cassette = Cassette::record(Path::new("cassette.yaml"));
+        cassette.replay = true;
+        cassette.interactions = vec![
+            Interaction {
+                request: request("hi"),
+                reply: "Hello".into(),
+            },
+            Interaction {
+                request: request("hi"),
+                reply: "Hello again".into(),
+            },
+        ];
+        cassette.used = vec![false; 2];
+        assert_eq!(cassette.take_reply(&request("hi")).unwrap(), "Hello");
+        assert_eq!(cassette.take_reply(&request("hi")).unwrap(), "Hello again");
+        assert!(cassette.take_reply(&request("hi")).is_err());
+        assert!(cassette.take_reply(&request("bye")).is_err());
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        assert_eq!(split_chunks("a b\nc"), vec!["a ", "b\n", "c"]);
+    }
+}
@@ -1,4 +1,9 @@
-use clap::Parser;
+use crate::utils::wrap_input;
+
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Parser, ValueEnum};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -9,16 +14,113 @@ pub struct Cli {
     /// No stream output
     #[clap(short = 'S', long)]
     pub no_stream: bool,
+    /// Print the reply as text, or the response of the api as json without streaming
+    #[clap(short, long, value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Text, conflicts_with = "replay")]
+    pub output: OutputFormat,
+    /// Print the request body that would be sent to the api, without sending it
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Only use local clients and only send requests to local hosts
+    #[clap(long)]
+    pub offline: bool,
+    /// Print the time spent in each phase of the startup to stderr
+    #[clap(long)]
+    pub profile_startup: bool,
+    /// Update aichat to the latest release
+    #[cfg(feature = "self-update")]
+    #[clap(long)]
+    pub update: bool,
+    /// Only log errors
+    #[clap(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Log request summaries, or everything with -vv
+    #[clap(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Open the config file in $EDITOR
+    #[clap(long)]
+    pub edit_config: bool,
+    /// Print the value of a key in the config file, exits with 1 when it is unset
+    #[clap(long, value_name = "KEY")]
+    pub get_config: Option<String>,
+    /// Set a config key in the config file
+    #[clap(long, num_args = 2, value_names = ["KEY", "VALUE"])]
+    pub set_config: Option<Vec<String>>,
+    /// Upgrade the config file to the current version
+    #[clap(long)]
+    pub migrate_config: bool,
+    /// Print a markdown report of the usage log, over YYYY-MM, YYYY-MM-DD, <n>d or <from>..<to>
+    #[clap(long, value_name = "RANGE", num_args = 0..=1, default_missing_value = "")]
+    pub report: Option<String>,
+    /// Print the last saved reply
+    #[clap(long)]
+    pub last: bool,
     /// List all roles
     #[clap(long)]
     pub list_roles: bool,
+    /// List all pipelines
+    #[clap(long)]
+    pub list_pipelines: bool,
+    /// Check the roles for mistakes, exit with 1 on errors
+    #[clap(long)]
+    pub lint_roles: bool,
+    /// Install roles from a url or gist
+    #[clap(long, value_name = "URL")]
+    pub install_role: Option<String>,
     /// Select a role
     #[clap(short, long)]
     pub role: Option<String>,
+    /// Fill a {{KEY}} placeholder of the role prompt, can be given several times
+    #[clap(long, value_name = "KEY=VALUE")]
+    pub var: Vec<String>,
+    /// Generate a shell command for the task and offer to execute it
+    #[clap(short = 'e', long)]
+    pub execute: bool,
+    /// With -e, pick a command run before and offer it again
+    #[clap(long, requires = "execute")]
+    pub history: bool,
+    /// Serve an OpenAI compatible api and a web playground
+    #[clap(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8000")]
+    pub serve: Option<String>,
+    /// Send whatever gets copied to the clipboard, the text is put before it
+    #[clap(long)]
+    pub watch_clipboard: bool,
+    /// With --watch-clipboard, copy each reply back to the clipboard
+    #[clap(long, requires = "watch_clipboard")]
+    pub write_back: bool,
+    /// Select the client and model, as name or name:model
+    #[clap(short, long)]
+    pub model: Option<String>,
+    /// Attach an image or a file to the input, can be given several times
+    #[clap(short, long, value_name = "FILE")]
+    pub file: Vec<String>,
+    /// Run a pipeline
+    #[clap(short, long)]
+    pub pipeline: Option<String>,
+    /// Prepend text to the input
+    #[clap(long)]
+    pub prepend: Option<String>,
+    /// Append text to the input
+    #[clap(long)]
+    pub append: Option<String>,
+    /// Record api traffic to a cassette file
+    #[clap(long, value_name = "FILE", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Replay api traffic from a cassette file
+    #[clap(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+    /// Mirror the reply to a file or named pipe as it streams
+    #[clap(long, value_name = "FILE")]
+    pub stream_to: Option<PathBuf>,
     /// Input text
     text: Vec<String>,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
 impl Cli {
     pub fn text(&self) -> Option<String> {
         let text = self
@@ -32,4 +134,20 @@ impl Cli {
         }
         Some(text)
     }
+
+    /// Values of the `--var` options
+    pub fn vars(&self) -> Result<BTreeMap<String, String>> {
+        let mut vars = BTreeMap::new();
+        for var in &self.var {
+            let (name, value) = var
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid --var '{var}', expected KEY=VALUE"))?;
+            vars.insert(name.trim().to_string(), value.to_string());
+        }
+        Ok(vars)
+    }
+
+    pub fn wrap_input(&self, input: &str) -> String {
+        wrap_input(self.prepend.as_deref(), input, self.append.as_deref())
+    }
 }
@@ -0,0 +1,142 @@
+use super::{parse_data_url, Client};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_URL: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+const MODEL: &str = "claude-3-5-haiku-latest";
+const MAX_TOKENS: u64 = 4096;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnthropicConfig {
+    /// Name used to select the client, defaults to `anthropic`
+    pub name: Option<String>,
+    pub api_key: String,
+    /// Defaults to claude-3-5-haiku-latest
+    pub model: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl Client for AnthropicConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "anthropic".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone().unwrap_or_else(|| MODEL.into())
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = Some(model.into());
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.into();
+    }
+
+    fn url(&self) -> String {
+        API_URL.into()
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        let builder = client
+            .post(self.url())
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&convert_body(body)?);
+        Ok(builder)
+    }
+
+    fn parse_reply(&self, data: &Value) -> Result<String> {
+        data["content"][0]["text"]
+            .as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))
+    }
+
+    fn supports_prefill(&self) -> bool {
+        true
+    }
+
+    fn parse_delta(&self, data: &str) -> Result<Option<String>> {
+        let data: Value = serde_json::from_str(data)?;
+        match data["type"].as_str() {
+            Some("content_block_delta") => {
+                let text = data["delta"]["text"].as_str().unwrap_or_default();
+                Ok(Some(text.to_string()))
+            }
+            Some("message_stop") => Ok(None),
+            Some("error") => bail!(
+                "Request failed, {}",
+                data["error"]["message"].as_str().unwrap_or_default()
+            ),
+            _ => Ok(Some(String::new())),
+        }
+    }
+}
+
+/// System messages go to the top-level `system` field, `max_tokens` is required
+fn convert_body(body: &Value) -> Result<Value> {
+    let mut system = vec![];
+    let mut messages = vec![];
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let content = message["content"].clone();
+        match message["role"].as_str() {
+            Some("system") => system.push(content.as_str().unwrap_or_default().to_string()),
+            Some(role) => {
+                messages.push(json!({ "role": role, "content": convert_content(content) }))
+            }
+            None => bail!("Invalid message {message}"),
+        }
+    }
+    let mut output = json!({
+        "model": body["model"],
+        "messages": messages,
+        "max_tokens": body.get("max_tokens").cloned().unwrap_or(MAX_TOKENS.into()),
+    });
+    let map = output.as_object_mut().unwrap();
+    if !system.is_empty() {
+        map.insert("system".into(), system.join("\n\n").into());
+    }
+    for key in ["temperature", "top_p", "stream"] {
+        if let Some(value) = body.get(key) {
+            map.insert(key.into(), value.clone());
+        }
+    }
+    Ok(output)
+}
+
+/// Images are given as a base64 source or a url source
+fn convert_content(content: Value) -> Value {
+    let parts = match content.as_array() {
+        Some(v) => v,
+        None => return content,
+    };
+    let parts: Vec<Value> = parts
+        .iter()
+        .map(|part| {
+            let url = match part["image_url"]["url"].as_str() {
+                Some(v) => v,
+                None => return part.clone(),
+            };
+            let source = match parse_data_url(url) {
+                Some((mime, data)) => json!({ "type": "base64", "media_type": mime, "data": data }),
+                None => json!({ "type": "url", "url": url }),
+            };
+            json!({ "type": "image", "source": source })
+        })
+        .collect();
+    parts.into()
+}
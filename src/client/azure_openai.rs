@@ -0,0 +1,96 @@
+use super::Client;
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_VERSION: &str = "2023-05-15";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AzureOpenAIConfig {
+    /// Name used to select the client, defaults to `azure-openai`
+    pub name: Option<String>,
+    /// Endpoint of the resource, e.g. https://RESOURCE.openai.azure.com
+    pub api_base: String,
+    pub api_key: String,
+    /// Defaults to 2023-05-15
+    pub api_version: Option<String>,
+    /// Name of the model deployment
+    pub deployment: String,
+    /// Name of the embeddings model deployment, needed for RAG
+    pub embeddings_deployment: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl Client for AzureOpenAIConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "azure-openai".into())
+    }
+
+    fn model(&self) -> String {
+        self.deployment.clone()
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.deployment = model.into();
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.into();
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions",
+            self.api_base.trim_end_matches('/'),
+            self.deployment
+        )
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        // The deployment decides the model
+        let mut body = body.clone();
+        if let Some(map) = body.as_object_mut() {
+            map.remove("model");
+        }
+        let api_version = self.api_version.as_deref().unwrap_or(API_VERSION);
+        let builder = client
+            .post(self.url())
+            .query(&[("api-version", api_version)])
+            .header("api-key", &self.api_key)
+            .json(&body);
+        Ok(builder)
+    }
+
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let deployment = self
+            .embeddings_deployment
+            .as_ref()
+            .ok_or_else(|| anyhow!("No embeddings_deployment in client '{}'", self.name()))?;
+        let url = format!(
+            "{}/openai/deployments/{deployment}/embeddings",
+            self.api_base.trim_end_matches('/'),
+        );
+        let api_version = self.api_version.as_deref().unwrap_or(API_VERSION);
+        let builder = client
+            .post(url)
+            .query(&[("api-version", api_version)])
+            .header("api-key", &self.api_key)
+            .json(&json!({ "input": texts }));
+        Ok(builder)
+    }
+}
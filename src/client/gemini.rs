@@ -0,0 +1,165 @@
+use super::{parse_data_url, parse_vector, Client};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+const MODEL: &str = "gemini-1.5-flash";
+const EMBEDDINGS_MODEL: &str = "text-embedding-004";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GeminiConfig {
+    /// Name used to select the client, defaults to `gemini`
+    pub name: Option<String>,
+    pub api_key: String,
+    /// Defaults to gemini-1.5-flash
+    pub model: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl Client for GeminiConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "gemini".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone().unwrap_or_else(|| MODEL.into())
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = Some(model.into());
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.into();
+    }
+
+    fn url(&self) -> String {
+        format!("{API_BASE}/{}:generateContent", self.model())
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        let builder = if body["stream"].as_bool().unwrap_or_default() {
+            let url = format!("{API_BASE}/{}:streamGenerateContent", self.model());
+            client.post(url).query(&[("alt", "sse")])
+        } else {
+            client.post(self.url())
+        };
+        let builder = builder
+            .header("x-goog-api-key", &self.api_key)
+            .json(&convert_body(body)?);
+        Ok(builder)
+    }
+
+    fn parse_reply(&self, data: &Value) -> Result<String> {
+        data["candidates"][0]["content"]["parts"][0]["text"]
+            .as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))
+    }
+
+    fn parse_delta(&self, data: &str) -> Result<Option<String>> {
+        let data: Value = serde_json::from_str(data)?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let text = data["candidates"][0]["content"]["parts"][0]["text"]
+            .as_str()
+            .unwrap_or_default();
+        Ok(Some(text.to_string()))
+    }
+
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let model = format!("models/{EMBEDDINGS_MODEL}");
+        let requests: Vec<Value> = texts
+            .iter()
+            .map(|v| json!({ "model": model, "content": { "parts": [{ "text": v }] } }))
+            .collect();
+        let builder = client
+            .post(format!("{API_BASE}/{EMBEDDINGS_MODEL}:batchEmbedContents"))
+            .header("x-goog-api-key", &self.api_key)
+            .json(&json!({ "requests": requests }));
+        Ok(builder)
+    }
+
+    fn parse_embeddings(&self, data: &Value) -> Result<Vec<Vec<f32>>> {
+        data["embeddings"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .map(|v| parse_vector(&v["values"]))
+            .collect()
+    }
+}
+
+/// Gemini names the assistant `model` and takes system prompts separately
+fn convert_body(body: &Value) -> Result<Value> {
+    let mut system = vec![];
+    let mut contents = vec![];
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let text = message["content"].as_str().unwrap_or_default();
+        let role = match message["role"].as_str() {
+            Some("system") => {
+                system.push(text.to_string());
+                continue;
+            }
+            Some("assistant") => "model",
+            Some("user") => "user",
+            _ => bail!("Invalid message {message}"),
+        };
+        let parts = match message["content"].as_array() {
+            Some(parts) => parts.iter().map(convert_part).collect::<Result<Vec<_>>>()?,
+            None => vec![json!({ "text": text })],
+        };
+        contents.push(json!({ "role": role, "parts": parts }));
+    }
+    let mut output = json!({ "contents": contents });
+    let map = output.as_object_mut().unwrap();
+    if !system.is_empty() {
+        map.insert(
+            "systemInstruction".into(),
+            json!({ "parts": [{ "text": system.join("\n\n") }] }),
+        );
+    }
+    let mut generation_config = serde_json::Map::new();
+    for (key, name) in [
+        ("temperature", "temperature"),
+        ("top_p", "topP"),
+        ("max_tokens", "maxOutputTokens"),
+    ] {
+        if let Some(value) = body.get(key) {
+            generation_config.insert(name.into(), value.clone());
+        }
+    }
+    if !generation_config.is_empty() {
+        map.insert("generationConfig".into(), generation_config.into());
+    }
+    Ok(output)
+}
+
+/// Images must be sent inline, gemini does not fetch urls
+fn convert_part(part: &Value) -> Result<Value> {
+    let url = match part["image_url"]["url"].as_str() {
+        Some(v) => v,
+        None => return Ok(json!({ "text": part["text"] })),
+    };
+    match parse_data_url(url) {
+        Some((mime, data)) => Ok(json!({ "inline_data": { "mime_type": mime, "data": data } })),
+        None => bail!("Gemini only takes local images, download {url} first"),
+    }
+}
@@ -0,0 +1,98 @@
+use super::Client;
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_BASE: &str = "http://localhost:11434/v1";
+
+/// Local server speaking the OpenAI format, such as Ollama or the llama.cpp server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LocalConfig {
+    /// Name used to select the client, defaults to `local`
+    pub name: Option<String>,
+    /// Defaults to http://localhost:11434/v1, the Ollama server
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    pub model: String,
+    /// Context size of the model
+    pub context_size: Option<usize>,
+    /// Model used to build rag indexes
+    pub embeddings_model: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl LocalConfig {
+    fn api_base(&self) -> String {
+        self.api_base
+            .as_deref()
+            .unwrap_or(API_BASE)
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    fn post(&self, client: &ReqwestClient, url: String, body: &Value) -> RequestBuilder {
+        let builder = client.post(url).json(body);
+        match self.api_key.as_ref() {
+            Some(api_key) => builder.bearer_auth(api_key),
+            None => builder,
+        }
+    }
+}
+
+impl Client for LocalConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "local".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = model.into();
+    }
+
+    fn api_key(&self) -> &str {
+        self.api_key.as_deref().unwrap_or_default()
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn requires_api_key(&self) -> bool {
+        false
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = Some(api_key.into());
+    }
+
+    fn context_size(&self) -> Option<usize> {
+        self.context_size
+    }
+
+    fn url(&self) -> String {
+        format!("{}/chat/completions", self.api_base())
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        Ok(self.post(client, self.url(), body))
+    }
+
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let model = self
+            .embeddings_model
+            .as_ref()
+            .ok_or_else(|| anyhow!("No embeddings_model in client '{}'", self.name()))?;
+        let body = json!({ "model": model, "input": texts });
+        Ok(self.post(client, format!("{}/embeddings", self.api_base()), &body))
+    }
+}
@@ -0,0 +1,72 @@
+use super::Client;
+
+use anyhow::Result;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_URL: &str = "https://api.mistral.ai/v1/chat/completions";
+const MODEL: &str = "mistral-small-latest";
+const EMBEDDINGS_URL: &str = "https://api.mistral.ai/v1/embeddings";
+const EMBEDDINGS_MODEL: &str = "mistral-embed";
+
+/// Mistral speaks the OpenAI chat completions format
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MistralConfig {
+    /// Name used to select the client, defaults to `mistral`
+    pub name: Option<String>,
+    pub api_key: String,
+    /// Defaults to mistral-small-latest
+    pub model: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl Client for MistralConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "mistral".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone().unwrap_or_else(|| MODEL.into())
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = Some(model.into());
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.into();
+    }
+
+    fn url(&self) -> String {
+        API_URL.into()
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        Ok(client
+            .post(self.url())
+            .bearer_auth(&self.api_key)
+            .json(body))
+    }
+
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let body = json!({ "model": EMBEDDINGS_MODEL, "input": texts });
+        Ok(client
+            .post(EMBEDDINGS_URL)
+            .bearer_auth(&self.api_key)
+            .json(&body))
+    }
+}
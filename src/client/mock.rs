@@ -0,0 +1,174 @@
+use super::Client;
+
+use crate::cassette::split_chunks;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{stream, StreamExt};
+use reqwest::{Body, Client as ReqwestClient, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::read_to_string;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Never requested, the replies are made up in place of a response
+const URL: &str = "http://mock.invalid/chat/completions";
+
+/// Client replying from a fixtures file without any server, to develop and test roles offline
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MockConfig {
+    /// Name used to select the client, defaults to `mock`
+    pub name: Option<String>,
+    /// Defaults to `mock`
+    pub model: Option<String>,
+    /// Yaml list of `match` and `reply`, the input is echoed without it
+    pub fixtures: Option<String>,
+    /// Milliseconds before the reply starts
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Milliseconds between the words of a streamed reply
+    #[serde(default)]
+    pub chunk_delay_ms: u64,
+    /// Context size of the model
+    pub context_size: Option<usize>,
+}
+
+/// Canned reply to the inputs containing `match`, or to any input without it
+#[derive(Debug, Clone, Deserialize)]
+struct Fixture {
+    #[serde(rename = "match")]
+    pattern: Option<String>,
+    reply: String,
+}
+
+impl MockConfig {
+    /// Response as a server of the chat completions format would send it, streamed if asked
+    pub async fn respond(&self, body: &Value) -> Result<Response> {
+        let input = last_user_text(body);
+        let reply = match &self.fixtures {
+            Some(path) => {
+                let content = read_to_string(path)
+                    .with_context(|| format!("Failed to load fixtures at {path}"))?;
+                let fixtures: Vec<Fixture> = serde_yaml::from_str(&content)
+                    .with_context(|| format!("Invalid fixtures at {path}"))?;
+                find_reply(&fixtures, &input)
+                    .ok_or_else(|| anyhow!("No fixture in {path} matches the input"))?
+            }
+            None => input,
+        };
+        sleep(Duration::from_millis(self.delay_ms)).await;
+        let response = http::Response::builder();
+        let response = if body["stream"].as_bool() == Some(true) {
+            let delay = Duration::from_millis(self.chunk_delay_ms);
+            let events: Vec<String> = split_chunks(&reply)
+                .into_iter()
+                .map(|v| json!({ "choices": [{ "index": 0, "delta": { "content": v } }] }))
+                .map(|v| format!("data: {v}\n\n"))
+                .chain(["data: [DONE]\n\n".to_string()])
+                .collect();
+            let events = stream::iter(events).then(move |v| async move {
+                sleep(delay).await;
+                Ok::<String, std::io::Error>(v)
+            });
+            response
+                .header("content-type", "text/event-stream")
+                .body(Body::wrap_stream(events))?
+        } else {
+            let data = json!({
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": reply },
+                    "finish_reason": "stop",
+                }],
+            });
+            response
+                .header("content-type", "application/json")
+                .body(Body::from(data.to_string()))?
+        };
+        Ok(response.into())
+    }
+}
+
+impl Client for MockConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "mock".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone().unwrap_or_else(|| "mock".into())
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = Some(model.into());
+    }
+
+    fn api_key(&self) -> &str {
+        ""
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        None
+    }
+
+    fn requires_api_key(&self) -> bool {
+        false
+    }
+
+    fn set_api_key(&mut self, _api_key: &str) {}
+
+    fn context_size(&self) -> Option<usize> {
+        self.context_size
+    }
+
+    fn url(&self) -> String {
+        URL.into()
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        Ok(client.post(URL).json(body))
+    }
+}
+
+fn find_reply(fixtures: &[Fixture], input: &str) -> Option<String> {
+    fixtures
+        .iter()
+        .find(|v| match &v.pattern {
+            Some(pattern) => input.contains(pattern.as_str()),
+            None => true,
+        })
+        .map(|v| v.reply.clone())
+}
+
+/// Text of the last user message of a body in the chat completions format
+fn last_user_text(body: &Value) -> String {
+    let message = body["messages"]
+        .as_array()
+        .and_then(|v| v.iter().rev().find(|v| v["role"] == "user"));
+    match message.map(|v| &v["content"]) {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|v| v["text"].as_str())
+            .collect::<Vec<&str>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_reply() {
+        let fixtures: Vec<Fixture> = serde_yaml::from_str(
+            "- match: list files\n  reply: ls -la\n- match: disk\n  reply: df -h\n- reply: echo ok\n",
+        )
+        .unwrap();
+        let reply = |input| find_reply(&fixtures, input);
+        assert_eq!(reply("list files here").as_deref(), Some("ls -la"));
+        assert_eq!(reply("free disk space").as_deref(), Some("df -h"));
+        assert_eq!(reply("hello").as_deref(), Some("echo ok"));
+        assert_eq!(find_reply(&fixtures[..2], "hello"), None);
+    }
+}
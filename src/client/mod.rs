@@ -0,0 +1,961 @@
+mod anthropic;
+mod azure_openai;
+mod gemini;
+mod local;
+mod mistral;
+mod mock;
+mod openai;
+
+pub use self::anthropic::AnthropicConfig;
+pub use self::azure_openai::AzureOpenAIConfig;
+pub use self::gemini::GeminiConfig;
+pub use self::local::LocalConfig;
+pub use self::mistral::MistralConfig;
+pub use self::mock::MockConfig;
+pub use self::openai::OpenAIConfig;
+
+use crate::cassette::{split_chunks, Cassette};
+use crate::config::{Config, SharedConfig};
+use crate::repl::{ReplyStreamHandler, SharedAbortSignal};
+use crate::term::TerminalTitle;
+use crate::utils::{unmask_text, Unmasker};
+
+use anyhow::{anyhow, bail, Context, Result};
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use parking_lot::Mutex;
+use reqwest::{
+    redirect::Policy, Client as ReqwestClient, NoProxy, Proxy, RequestBuilder, Response,
+    StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+use tracing::{debug, info, trace, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Looked up in order when no proxy is configured, `NO_PROXY` lists the hosts to reach directly
+const PROXY_ENV_VARS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"];
+/// Proxy value that bypasses the configured and environment proxies
+const NO_PROXY: &str = "none";
+const EMBEDDINGS_BATCH_SIZE: usize = 64;
+const MAX_RETRIES: usize = 2;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Doublings of the base delay past which the max delay applies anyway
+const RETRY_MAX_DOUBLINGS: u32 = 6;
+
+/// Api provider serving the chat completions
+pub trait Client {
+    /// Name used to select the client
+    fn name(&self) -> String;
+
+    fn model(&self) -> String;
+
+    fn set_model(&mut self, model: &str);
+
+    fn api_key(&self) -> &str;
+
+    fn set_api_key(&mut self, api_key: &str);
+
+    /// Proxy the requests of this client go through, if set in its config
+    fn proxy(&self) -> Option<&str>;
+
+    /// Whether requests fail without an api key
+    fn requires_api_key(&self) -> bool {
+        true
+    }
+
+    /// Context size of the model, if it differs from the default
+    fn context_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Url of the chat endpoint
+    fn url(&self) -> String;
+
+    /// Build the request, the body is in the OpenAI chat completions format
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder>;
+
+    /// Get the reply from a response
+    fn parse_reply(&self, data: &Value) -> Result<String> {
+        data["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))
+    }
+
+    /// Get the text from a stream event, None once the reply is done
+    fn parse_delta(&self, data: &str) -> Result<Option<String>> {
+        if data == "[DONE]" {
+            return Ok(None);
+        }
+        let data: Value = serde_json::from_str(data)?;
+        let text = data["choices"][0]["delta"]["content"]
+            .as_str()
+            .unwrap_or_default();
+        Ok(Some(text.to_string()))
+    }
+
+    /// Whether a trailing assistant message gets continued, used to resume a dropped stream
+    fn supports_prefill(&self) -> bool {
+        false
+    }
+
+    /// Build the request embedding the texts, for clients with an embeddings endpoint
+    fn embeddings_request_builder(
+        &self,
+        _client: &ReqwestClient,
+        _texts: &[String],
+    ) -> Result<RequestBuilder> {
+        bail!("Client '{}' does not support embeddings", self.name())
+    }
+
+    /// Get the vectors from an embeddings response
+    fn parse_embeddings(&self, data: &Value) -> Result<Vec<Vec<f32>>> {
+        data["data"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .map(|v| parse_vector(&v["embedding"]))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum ClientConfig {
+    #[serde(rename = "openai")]
+    OpenAI(OpenAIConfig),
+    #[serde(rename = "azure-openai")]
+    AzureOpenAI(AzureOpenAIConfig),
+    #[serde(rename = "anthropic")]
+    Anthropic(AnthropicConfig),
+    #[serde(rename = "gemini")]
+    Gemini(GeminiConfig),
+    #[serde(rename = "mistral")]
+    Mistral(MistralConfig),
+    #[serde(rename = "local")]
+    Local(LocalConfig),
+    #[serde(rename = "mock")]
+    Mock(MockConfig),
+}
+
+impl ClientConfig {
+    pub fn is_local(&self) -> bool {
+        matches!(self, ClientConfig::Local(_) | ClientConfig::Mock(_))
+    }
+
+    pub fn as_client(&self) -> &dyn Client {
+        match self {
+            ClientConfig::OpenAI(v) => v,
+            ClientConfig::AzureOpenAI(v) => v,
+            ClientConfig::Anthropic(v) => v,
+            ClientConfig::Gemini(v) => v,
+            ClientConfig::Mistral(v) => v,
+            ClientConfig::Local(v) => v,
+            ClientConfig::Mock(v) => v,
+        }
+    }
+
+    pub fn as_client_mut(&mut self) -> &mut dyn Client {
+        match self {
+            ClientConfig::OpenAI(v) => v,
+            ClientConfig::AzureOpenAI(v) => v,
+            ClientConfig::Anthropic(v) => v,
+            ClientConfig::Gemini(v) => v,
+            ClientConfig::Mistral(v) => v,
+            ClientConfig::Local(v) => v,
+            ClientConfig::Mock(v) => v,
+        }
+    }
+
+    /// `name:model` of the client
+    pub fn id(&self) -> String {
+        let client = self.as_client();
+        format!("{}:{}", client.name(), client.model())
+    }
+}
+
+#[derive(Debug)]
+pub struct ChatGptClient {
+    config: SharedConfig,
+    runtime: Runtime,
+    /// Http clients reused across requests, keyed by the proxy they were built with
+    clients: Mutex<HashMap<Option<String>, ReqwestClient>>,
+    cassette: Mutex<Option<Cassette>>,
+    /// File or named pipe that mirrors the reply as it streams
+    stream_to: Mutex<Option<File>>,
+}
+
+impl ChatGptClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let runtime = init_runtime()?;
+        let s = Self {
+            config,
+            runtime,
+            clients: Mutex::new(HashMap::new()),
+            cassette: Mutex::new(None),
+            stream_to: Mutex::new(None),
+        };
+        let client_config = s.config.lock().client_config()?;
+        let _ = s.get_client(Some(&client_config))?; // check error
+        Ok(s)
+    }
+
+    pub fn set_cassette(&self, cassette: Cassette) {
+        *self.cassette.lock() = Some(cassette);
+    }
+
+    pub fn set_stream_to(&self, file: File) {
+        *self.stream_to.lock() = Some(file);
+    }
+
+    /// Establish a connection to the api server in the background
+    pub fn prewarm(&self) -> Result<()> {
+        if !self.config.lock().prewarm {
+            return Ok(());
+        }
+        let client_config = self.config.lock().client_config()?;
+        if let ClientConfig::Mock(_) = client_config {
+            return Ok(());
+        }
+        let client = self.get_client(Some(&client_config))?;
+        let url = client_config.as_client().url();
+        self.check_offline(&url)?;
+        self.runtime.spawn(async move {
+            let _ = client.head(url).send().await;
+        });
+        Ok(())
+    }
+
+    pub fn fetch_text(&self, url: &str) -> Result<String> {
+        self.check_offline(url)?;
+        let client = self.get_client(None)?;
+        self.runtime
+            .block_on(async {
+                let res = client.get(url).send().await?;
+                if !res.status().is_success() {
+                    bail!("Request failed, {}", res.status());
+                }
+                let text = res.text().await?;
+                Ok(text)
+            })
+            .with_context(|| format!("Failed to fetch {url}"))
+    }
+
+    /// Html of the page with its url after redirects, None if it is another kind of document
+    pub fn fetch_html(&self, url: &str) -> Result<Option<(String, String)>> {
+        self.check_offline(url)?;
+        let client = self.get_client(None)?;
+        self.runtime
+            .block_on(async {
+                let res = client.get(url).send().await?;
+                if !res.status().is_success() {
+                    bail!("Request failed, {}", res.status());
+                }
+                let html = res
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.contains("html"))
+                    .unwrap_or_default();
+                if !html {
+                    return Ok(None);
+                }
+                let url = res.url().to_string();
+                Ok(Some((url, res.text().await?)))
+            })
+            .with_context(|| format!("Failed to fetch {url}"))
+    }
+
+    /// Embed the texts with the client, in batches
+    pub fn embed(&self, client_config: &ClientConfig, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        check_api_key(client_config)?;
+        let client = self.get_client(Some(client_config))?;
+        self.runtime
+            .block_on(async {
+                let mut output = vec![];
+                for batch in texts.chunks(EMBEDDINGS_BATCH_SIZE) {
+                    let res = self
+                        .send_retrying(|| {
+                            client_config
+                                .as_client()
+                                .embeddings_request_builder(&client, batch)
+                        })
+                        .await?;
+                    let data: Value = res.json().await?;
+                    if let Some(err_msg) = data["error"]["message"].as_str() {
+                        bail!("Request failed, {err_msg}");
+                    }
+                    let vectors = client_config.as_client().parse_embeddings(&data)?;
+                    if vectors.len() != batch.len() {
+                        bail!("Unexpected number of embeddings");
+                    }
+                    output.extend(vectors);
+                }
+                Ok(output)
+            })
+            .with_context(|| "Failed to embed")
+    }
+
+    pub fn send_message(&self, input: &str) -> Result<String> {
+        let _title = self.set_terminal_title();
+        self.runtime.block_on(async {
+            self.send_message_inner(input, true)
+                .await
+                .with_context(|| "Failed to fetch")
+        })
+    }
+
+    /// Send a request aichat makes for itself, such as a query rewrite or a summary,
+    /// it is neither mirrored to the stream-to file nor kept in the cassette
+    pub fn send_helper_message(&self, input: &str) -> Result<String> {
+        self.runtime.block_on(async {
+            self.send_message_inner(input, false)
+                .await
+                .with_context(|| "Failed to fetch")
+        })
+    }
+
+    /// Send without streaming, return the response of the api as is and the reply
+    pub fn send_message_json(&self, input: &str) -> Result<(Value, String)> {
+        let _title = self.set_terminal_title();
+        self.runtime.block_on(async {
+            self.send_message_json_inner(input)
+                .await
+                .with_context(|| "Failed to fetch")
+        })
+    }
+
+    pub fn send_message_streaming(
+        &self,
+        input: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        async fn watch_abort(abort: SharedAbortSignal) {
+            loop {
+                if abort.aborted() {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+        let abort = handler.get_abort();
+        let _title = self.set_terminal_title();
+        self.runtime.block_on(async {
+            tokio::select! {
+                ret = self.send_message_streaming_inner(input, handler) => {
+                    handler.done()?;
+                    ret.with_context(|| "Failed to fetch stream")
+                }
+                _ = watch_abort(abort.clone()) => {
+                    handler.done()?;
+                    Ok(())
+                 },
+                _ =  tokio::signal::ctrl_c() => {
+                    abort.set_ctrlc();
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Send without streaming, a reply is replayed, recorded and mirrored unlike a helper request
+    async fn send_message_inner(&self, content: &str, reply: bool) -> Result<String> {
+        if self.config.lock().dry_run {
+            let payload = self.build_payload(content, false)?;
+            return Ok(serde_json::to_string_pretty(&payload)?);
+        }
+        let (content, masks) = self.config.lock().mask_input(content);
+        let body = self.build_body(&content, false)?;
+        if reply {
+            if let Some(reply) = self.replay(&body)? {
+                return Ok(reply);
+            }
+        }
+        let client_config = self.config.lock().client_config()?;
+        let started = Instant::now();
+        let output = self.send_body(&client_config, &body).await?;
+        let output = unmask_text(&output, &masks);
+        self.config
+            .lock()
+            .record_usage(&client_config.id(), None, &body, &output, started)?;
+        if reply {
+            self.record(&body, &output)?;
+            self.mirror(&output)?;
+            self.mirror_end(&output)?;
+        }
+
+        Ok(output)
+    }
+
+    async fn send_message_json_inner(&self, content: &str) -> Result<(Value, String)> {
+        if self.config.lock().dry_run {
+            let payload = self.build_payload(content, false)?;
+            let output = serde_json::to_string_pretty(&payload)?;
+            return Ok((payload, output));
+        }
+        let (content, masks) = self.config.lock().mask_input(content);
+        let body = self.build_body(&content, false)?;
+        let client_config = self.config.lock().client_config()?;
+        let started = Instant::now();
+        let mut data = self.send_body_json(&client_config, &body).await?;
+        unmask_value(&mut data, &masks);
+        let output = client_config.as_client().parse_reply(&data)?;
+        self.config
+            .lock()
+            .record_usage(&client_config.id(), None, &body, &output, started)?;
+        self.record(&body, &output)?;
+        self.mirror(&output)?;
+        self.mirror_end(&output)?;
+
+        Ok((data, output))
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        if self.config.lock().dry_run {
+            let payload = self.build_payload(content, true)?;
+            handler.text(&serde_json::to_string_pretty(&payload)?)?;
+            return Ok(());
+        }
+        let (content, masks) = self.config.lock().mask_input(content);
+        let body = self.build_body(&content, true)?;
+        if let Some(reply) = self.replay(&body)? {
+            for chunk in split_chunks(&reply) {
+                handler.text(chunk)?;
+                self.mirror(chunk)?;
+            }
+            return self.mirror_end(&reply);
+        }
+        let client_config = self.config.lock().client_config()?;
+        let started = Instant::now();
+        let res = self.send_stream_request(&client_config, &body).await?;
+        let mut unmasker = Unmasker::new(masks);
+        if !self
+            .read_stream(&client_config, res, handler, &mut unmasker)
+            .await?
+        {
+            if handler.get_buffer().is_empty() {
+                bail!("The connection dropped before any reply");
+            }
+            let resumed = client_config.as_client().supports_prefill()
+                && self
+                    .resume_stream(&client_config, &body, handler, &mut unmasker)
+                    .await;
+            if !resumed {
+                handler.truncate();
+            }
+        }
+        let rest = unmasker.finish();
+        if !rest.is_empty() && !handler.paused() {
+            handler.text(&rest)?;
+            self.mirror(&rest)?;
+        }
+        self.config.lock().record_usage(
+            &client_config.id(),
+            None,
+            &body,
+            handler.get_buffer(),
+            started,
+        )?;
+        self.record(&body, handler.get_buffer())?;
+        self.mirror_end(handler.get_buffer())?;
+
+        Ok(())
+    }
+
+    /// Pass the deltas to the handler, false if the connection dropped before the end
+    async fn read_stream(
+        &self,
+        client_config: &ClientConfig,
+        res: Response,
+        handler: &mut ReplyStreamHandler,
+        unmasker: &mut Unmasker,
+    ) -> Result<bool> {
+        let mut stream = res.bytes_stream().eventsource();
+        let mut virgin = handler.get_buffer().is_empty();
+        while let Some(part) = stream.next().await {
+            let chunk = match part {
+                Ok(v) => v.data,
+                Err(err) => {
+                    info!("Stream dropped: {err}");
+                    return Ok(false);
+                }
+            };
+            trace!("Event: {chunk}");
+            check_stream_error(&chunk)?;
+            let text = match client_config.as_client().parse_delta(&chunk)? {
+                Some(text) => text,
+                None => break,
+            };
+            if text.is_empty() {
+                continue;
+            }
+            if virgin {
+                virgin = false;
+                if text == "\n\n" {
+                    continue;
+                }
+            }
+            let text = unmasker.push(&text);
+            if text.is_empty() {
+                continue;
+            }
+            handler.text(&text)?;
+            self.mirror(&text)?;
+            if handler.paused() {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Request again with the partial reply as the last assistant message, which the model continues
+    async fn resume_stream(
+        &self,
+        client_config: &ClientConfig,
+        body: &Value,
+        handler: &mut ReplyStreamHandler,
+        unmasker: &mut Unmasker,
+    ) -> bool {
+        let mut body = body.clone();
+        if let Some(messages) = body["messages"].as_array_mut() {
+            // A trailing assistant message must not end with whitespace
+            let partial = handler.get_buffer().trim_end();
+            messages.push(json!({ "role": "assistant", "content": partial }));
+        }
+        info!("Resuming the reply from {}", client_config.id());
+        let ret = match self.send_stream_request(client_config, &body).await {
+            Ok(res) => {
+                self.read_stream(client_config, res, handler, unmasker)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        match ret {
+            Ok(ended) => ended,
+            Err(err) => {
+                warn!("Failed to resume the reply: {err:#}");
+                false
+            }
+        }
+    }
+
+    /// The request body the input would be sent with, as the provider receives it
+    pub fn build_payload(&self, content: &str, stream: bool) -> Result<Value> {
+        let (content, _) = self.config.lock().mask_input(content);
+        let body = self.build_body(&content, stream)?;
+        let client_config = self.config.lock().client_config()?;
+        let client = self.get_client(Some(&client_config))?;
+        let request = client_config
+            .as_client()
+            .request_builder(&client, &body)?
+            .build()?;
+        match request.body().and_then(|v| v.as_bytes()) {
+            Some(bytes) => Ok(serde_json::from_slice(bytes)?),
+            None => Ok(body),
+        }
+    }
+
+    /// Send a body in the chat completions format with the client and return the reply
+    pub async fn send_body(&self, client_config: &ClientConfig, body: &Value) -> Result<String> {
+        let data = self.send_body_json(client_config, body).await?;
+        client_config.as_client().parse_reply(&data)
+    }
+
+    async fn send_body_json(&self, client_config: &ClientConfig, body: &Value) -> Result<Value> {
+        let res = self.send_request(client_config, body).await?;
+        let status = res.status();
+        let data: Value = match res.json().await {
+            Ok(v) => v,
+            Err(_) if !status.is_success() => Value::Null,
+            Err(err) => return Err(err.into()),
+        };
+        debug!("Response: {data}");
+        if !status.is_success() || data["error"]["message"].is_string() {
+            return Err(request_error(client_config, status, &data));
+        }
+        Ok(data)
+    }
+
+    /// Stream the reply to a body in the chat completions format, text by text into the channel
+    pub async fn send_body_streaming(
+        &self,
+        client_config: &ClientConfig,
+        body: &Value,
+        tx: UnboundedSender<String>,
+    ) -> Result<()> {
+        let res = self.send_stream_request(client_config, body).await?;
+        let mut stream = res.bytes_stream().eventsource();
+        while let Some(part) = stream.next().await {
+            let chunk = part?.data;
+            trace!("Event: {chunk}");
+            check_stream_error(&chunk)?;
+            let text = match client_config.as_client().parse_delta(&chunk)? {
+                Some(text) => text,
+                None => break,
+            };
+            if !text.is_empty() && tx.send(text).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    async fn send_stream_request(
+        &self,
+        client_config: &ClientConfig,
+        body: &Value,
+    ) -> Result<Response> {
+        let res = self.send_request(client_config, body).await?;
+        let status = res.status();
+        if !status.is_success() {
+            let data: Value = res.json().await.unwrap_or_default();
+            return Err(request_error(client_config, status, &data));
+        }
+        Ok(res)
+    }
+
+    /// Response of the api server, made up by a mock client without sending anything
+    async fn send_request(&self, client_config: &ClientConfig, body: &Value) -> Result<Response> {
+        if let ClientConfig::Mock(mock) = client_config {
+            debug!("Request to {}: {body}", client_config.id());
+            return mock.respond(body).await;
+        }
+        self.send_retrying(|| self.request_builder(client_config, body))
+            .await
+    }
+
+    /// Send the request, retrying with exponential backoff on rate limits, server errors
+    /// and failed connections. The response of the last attempt is returned as it is
+    async fn send_retrying(&self, build: impl Fn() -> Result<RequestBuilder>) -> Result<Response> {
+        let max_retries = self.config.lock().max_retries.unwrap_or(MAX_RETRIES);
+        let mut attempt = 0;
+        loop {
+            let (client, request) = build()?.build_split();
+            let request = request?;
+            self.check_offline(request.url().as_str())?;
+            let ret = client.execute(request).await;
+            let (reason, retry_after) = match &ret {
+                Ok(res) if retryable(res.status()) => (res.status().to_string(), retry_after(res)),
+                Err(err) if err.is_connect() || err.is_timeout() => {
+                    ("Failed to connect".to_string(), None)
+                }
+                _ => return Ok(ret?),
+            };
+            if attempt >= max_retries {
+                return Ok(ret?);
+            }
+            attempt += 1;
+            let delay = retry_after
+                .unwrap_or(
+                    RETRY_BASE_DELAY * 2u32.pow((attempt as u32 - 1).min(RETRY_MAX_DOUBLINGS)),
+                )
+                .min(RETRY_MAX_DELAY);
+            wait_retry(&reason, delay, attempt, max_retries).await;
+        }
+    }
+
+    fn replay(&self, body: &Value) -> Result<Option<String>> {
+        match self.cassette.lock().as_mut() {
+            Some(cassette) if cassette.is_replay() => cassette.take_reply(body).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn record(&self, body: &Value, reply: &str) -> Result<()> {
+        match self.cassette.lock().as_mut() {
+            Some(cassette) => cassette.add(body, reply),
+            None => Ok(()),
+        }
+    }
+
+    fn mirror(&self, text: &str) -> Result<()> {
+        if let Some(file) = self.stream_to.lock().as_mut() {
+            file.write_all(text.as_bytes())
+                .and_then(|_| file.flush())
+                .with_context(|| "Failed to write stream-to file")?;
+        }
+        Ok(())
+    }
+
+    /// Terminate the reply with a newline so consumers can tell replies apart
+    fn mirror_end(&self, reply: &str) -> Result<()> {
+        if reply.ends_with('\n') {
+            return Ok(());
+        }
+        self.mirror("\n")
+    }
+
+    fn set_terminal_title(&self) -> TerminalTitle {
+        let config = self.config.lock();
+        let model = config
+            .client_config()
+            .map(|v| v.as_client().model())
+            .unwrap_or_default();
+        TerminalTitle::set(
+            config.terminal_title,
+            &format!("aichat: generating… ({model})"),
+        )
+    }
+
+    /// Refuse urls of hosts outside the local network when offline
+    fn check_offline(&self, url: &str) -> Result<()> {
+        if !self.config.lock().offline {
+            return Ok(());
+        }
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|v| v.host_str().map(|v| v.to_string()))
+            .unwrap_or_default();
+        if !is_local_host(&host) {
+            bail!("'{host}' is not a local host, offline only allows local requests");
+        }
+        Ok(())
+    }
+
+    /// Http client for the requests of the client, or for other requests without one
+    fn get_client(&self, client_config: Option<&ClientConfig>) -> Result<ReqwestClient> {
+        let config = self.config.lock();
+        let proxy = config.client_proxy(client_config);
+        let mut cache = self.clients.lock();
+        if let Some(client) = cache.get(&proxy) {
+            return Ok(client.clone());
+        }
+        let client = build_client(&config, proxy.as_deref())?;
+        cache.insert(proxy, client.clone());
+        Ok(client)
+    }
+
+    fn build_body(&self, content: &str, stream: bool) -> Result<Value> {
+        let messages = self.config.lock().build_messages(content)?;
+        let model = self.config.lock().client_config()?.as_client().model();
+        let mut body = json!({
+            "model": model,
+            "messages": messages,
+        });
+
+        if let Some(v) = self.config.lock().get_temperature() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("temperature".into(), json!(v)));
+        }
+
+        if let Some(v) = self.config.lock().get_top_p() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("top_p".into(), json!(v)));
+        }
+
+        if let Some(v) = self.config.lock().get_max_reply_tokens() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("max_tokens".into(), json!(v)));
+        }
+
+        if stream {
+            body.as_object_mut()
+                .and_then(|m| m.insert("stream".into(), json!(true)));
+        }
+
+        Ok(body)
+    }
+
+    fn request_builder(
+        &self,
+        client_config: &ClientConfig,
+        body: &Value,
+    ) -> Result<RequestBuilder> {
+        debug!("Request to {}: {body}", client_config.id());
+        check_api_key(client_config)?;
+        let client = self.get_client(Some(client_config))?;
+        client_config.as_client().request_builder(&client, body)
+    }
+}
+
+fn build_client(config: &Config, proxy: Option<&str>) -> Result<ReqwestClient> {
+    let mut builder = ReqwestClient::builder();
+    match proxy {
+        // Proxies and redirects could take requests outside the local network
+        _ if config.offline => {
+            builder = builder.no_proxy().redirect(Policy::custom(|attempt| {
+                match attempt.url().host_str() {
+                    Some(host) if is_local_host(host) => attempt.follow(),
+                    _ => attempt.error("Redirect to a non-local host while offline"),
+                }
+            }))
+        }
+        Some(NO_PROXY) => builder = builder.no_proxy(),
+        Some(proxy) => {
+            let proxy = Proxy::all(proxy).with_context(|| format!("Invalid proxy '{proxy}'"))?;
+            builder = builder.proxy(proxy);
+        }
+        None => {
+            let env_proxy = PROXY_ENV_VARS
+                .iter()
+                .find_map(|v| env::var(v).ok().filter(|v| !v.is_empty()));
+            if let Some(proxy) = env_proxy {
+                let proxy = Proxy::all(&proxy)
+                    .with_context(|| format!("Invalid proxy '{proxy}' in the environment"))?;
+                builder = builder.proxy(proxy.no_proxy(NoProxy::from_env()));
+            }
+        }
+    }
+    if let Some(secs) = config.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    let client = builder
+        .connect_timeout(CONNECT_TIMEOUT)
+        .tcp_keepalive(KEEPALIVE_INTERVAL)
+        .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+        .http2_keep_alive_while_idle(true)
+        .build()
+        .with_context(|| "Failed to build http client")?;
+    Ok(client)
+}
+
+/// Loopback, private and link-local addresses, and names of the local machine or network
+fn is_local_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => {
+            let segment = ip.segments()[0];
+            ip.is_loopback() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+        }
+        Err(_) => {
+            let host = host.to_ascii_lowercase();
+            host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local")
+        }
+    }
+}
+
+/// Put the masked parts back into the strings of a response
+fn unmask_value(value: &mut Value, masks: &[String]) {
+    match value {
+        Value::String(text) => *text = unmask_text(text, masks),
+        Value::Array(items) => items.iter_mut().for_each(|v| unmask_value(v, masks)),
+        Value::Object(map) => map.values_mut().for_each(|v| unmask_value(v, masks)),
+        _ => {}
+    }
+}
+
+fn parse_vector(data: &Value) -> Result<Vec<f32>> {
+    data.as_array()
+        .ok_or_else(|| anyhow!("Unexpected embedding {data}"))?
+        .iter()
+        .map(|v| {
+            v.as_f64()
+                .map(|v| v as f32)
+                .ok_or_else(|| anyhow!("Unexpected embedding {data}"))
+        })
+        .collect()
+}
+
+fn check_api_key(client_config: &ClientConfig) -> Result<()> {
+    let client = client_config.as_client();
+    if client.requires_api_key() && client.api_key().is_empty() {
+        bail!(
+            "No api key for client '{}', set its `api_key` with `aichat --edit-config` or `.set api_key <key>`",
+            client.name()
+        );
+    }
+    Ok(())
+}
+
+/// Error of a failed request, with a hint at what to check for the usual statuses
+fn request_error(client_config: &ClientConfig, status: StatusCode, data: &Value) -> anyhow::Error {
+    let client = client_config.as_client();
+    let reason = data["error"]["message"]
+        .as_str()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| status.to_string());
+    let hint = match status.as_u16() {
+        401 | 403 => format!(
+            ", check the `api_key` of client '{}' with `aichat --edit-config`",
+            client.name()
+        ),
+        404 => format!(
+            ", check that model '{}' exists, `.model` lists the configured ones",
+            client.model()
+        ),
+        429 => ", the rate limit or the quota of the account was reached".into(),
+        _ => String::new(),
+    };
+    anyhow!("Request failed, {reason}{hint}")
+}
+
+/// Whether the status is worth retrying, rate limits and server errors
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Delay asked for by the `Retry-After` header, in seconds
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res.headers().get("retry-after")?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Count down to the next attempt on a status line, or log it when stderr is not a terminal
+async fn wait_retry(reason: &str, delay: Duration, attempt: usize, max_retries: usize) {
+    if atty::isnt(atty::Stream::Stderr) {
+        warn!(
+            "{reason}, retry {attempt}/{max_retries} in {}s",
+            delay.as_secs_f32().ceil()
+        );
+        sleep(delay).await;
+        return;
+    }
+    let mut left = delay;
+    while !left.is_zero() {
+        let secs = left.as_secs_f32().ceil();
+        eprint!("\r\x1b[2K{reason}, retry {attempt}/{max_retries} in {secs}s");
+        let step = left.min(Duration::from_secs(1));
+        sleep(step).await;
+        left -= step;
+    }
+    eprint!("\r\x1b[2K");
+}
+
+/// Fail on an error sent as a stream event, which comes after a successful status
+fn check_stream_error(data: &str) -> Result<()> {
+    if let Ok(data) = serde_json::from_str::<Value>(data) {
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+    }
+    Ok(())
+}
+
+/// Mime type and base64 data of a `data:` url
+fn parse_data_url(url: &str) -> Option<(&str, &str)> {
+    let (mime, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    Some((mime, data))
+}
+
+fn init_runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to init tokio")
+}
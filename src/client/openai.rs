@@ -0,0 +1,72 @@
+use super::Client;
+
+use anyhow::Result;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_URL: &str = "https://api.openai.com/v1/chat/completions";
+const MODEL: &str = "gpt-3.5-turbo";
+const EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
+const EMBEDDINGS_MODEL: &str = "text-embedding-3-small";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OpenAIConfig {
+    /// Name used to select the client, defaults to `openai`
+    pub name: Option<String>,
+    /// Openai api key
+    pub api_key: String,
+    /// Defaults to gpt-3.5-turbo
+    pub model: Option<String>,
+    /// Proxy of this client, overrides the global `proxy`, `none` to connect directly
+    pub proxy: Option<String>,
+}
+
+impl Client for OpenAIConfig {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "openai".into())
+    }
+
+    fn model(&self) -> String {
+        self.model.clone().unwrap_or_else(|| MODEL.into())
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = Some(model.into());
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.into();
+    }
+
+    fn url(&self) -> String {
+        API_URL.into()
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, body: &Value) -> Result<RequestBuilder> {
+        Ok(client
+            .post(self.url())
+            .bearer_auth(&self.api_key)
+            .json(body))
+    }
+
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let body = json!({ "model": EMBEDDINGS_MODEL, "input": texts });
+        Ok(client
+            .post(EMBEDDINGS_URL)
+            .bearer_auth(&self.api_key)
+            .json(&body))
+    }
+}
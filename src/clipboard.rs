@@ -0,0 +1,186 @@
+//! Copy to the clipboard, watch it and reply to whatever gets copied
+
+use crate::client::ChatGptClient;
+use crate::config::SharedConfig;
+use crate::print_now;
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::env;
+use std::io::{stdout, Write};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::error;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    /// Kept open, on X11 and Wayland what was copied is only served while it lives
+    static ref SYSTEM_CLIPBOARD: Mutex<Option<arboard::Clipboard>> = Mutex::new(None);
+}
+
+/// Put the text on the system clipboard, or have the terminal do it through OSC 52
+/// over ssh or when the system clipboard cannot be reached
+pub fn copy_text(text: &str) -> Result<()> {
+    let remote = env::var_os("SSH_TTY").is_some() || env::var_os("SSH_CONNECTION").is_some();
+    if !remote {
+        let mut clipboard = SYSTEM_CLIPBOARD.lock();
+        if clipboard.is_none() {
+            *clipboard = arboard::Clipboard::new().ok();
+        }
+        if let Some(clipboard) = clipboard.as_mut() {
+            if clipboard.set_text(text).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+    copy_osc52(text)
+}
+
+/// Copy through the OSC 52 escape sequence, passed through tmux when inside it
+fn copy_osc52(text: &str) -> Result<()> {
+    let data = general_purpose::STANDARD.encode(text);
+    let mut sequence = format!("\x1b]52;c;{data}\x07");
+    if env::var_os("TMUX").is_some() {
+        sequence = format!("\x1bPtmux;\x1b{sequence}\x1b\\");
+    }
+    let mut stdout = stdout();
+    stdout.write_all(sequence.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Commands reading and writing the system clipboard
+#[derive(Clone, Copy)]
+struct Clipboard {
+    paste: &'static [&'static str],
+    copy: &'static [&'static str],
+}
+
+impl Clipboard {
+    /// The first tool of the platform that can read the clipboard
+    fn detect() -> Result<Self> {
+        let candidates: &[Clipboard] = if cfg!(windows) {
+            &[Clipboard {
+                paste: &["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"],
+                copy: &[
+                    "powershell",
+                    "-NoProfile",
+                    "-Command",
+                    "$input | Set-Clipboard",
+                ],
+            }]
+        } else if cfg!(target_os = "macos") {
+            &[Clipboard {
+                paste: &["pbpaste"],
+                copy: &["pbcopy"],
+            }]
+        } else if env::var_os("WAYLAND_DISPLAY").is_some() {
+            &[Clipboard {
+                paste: &["wl-paste", "--no-newline"],
+                copy: &["wl-copy"],
+            }]
+        } else {
+            &[
+                Clipboard {
+                    paste: &["xclip", "-selection", "clipboard", "-o"],
+                    copy: &["xclip", "-selection", "clipboard"],
+                },
+                Clipboard {
+                    paste: &["xsel", "--clipboard", "--output"],
+                    copy: &["xsel", "--clipboard", "--input"],
+                },
+            ]
+        };
+        for candidate in candidates {
+            let found = Command::new(candidate.paste[0])
+                .args(&candidate.paste[1..])
+                .output()
+                .is_ok();
+            if found {
+                return Ok(*candidate);
+            }
+        }
+        let names: Vec<&str> = candidates.iter().map(|v| v.paste[0]).collect();
+        bail!(
+            "Error: No clipboard tool found, install one of {}",
+            names.join(", ")
+        )
+    }
+
+    /// An empty clipboard, or one holding an image, reads as empty text
+    fn paste(&self) -> String {
+        Command::new(self.paste[0])
+            .args(&self.paste[1..])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|v| v.status.success())
+            .map(|v| String::from_utf8_lossy(&v.stdout).to_string())
+            .unwrap_or_default()
+    }
+
+    fn copy(&self, text: &str) -> Result<()> {
+        let mut child = Command::new(self.copy[0])
+            .args(&self.copy[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.copy[0]))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} exited with {status}", self.copy[0]);
+        }
+        Ok(())
+    }
+}
+
+/// Send everything copied from now on, until the process is stopped
+pub fn watch(
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    wrap_input: impl Fn(&str) -> String,
+    write_back: bool,
+) -> Result<()> {
+    let clipboard = Clipboard::detect()?;
+    let mut last = clipboard.paste();
+    let role = config.lock().role.as_ref().map(|v| v.name.clone());
+    match role {
+        Some(name) => print_now!("Watching the clipboard with role '{name}', Ctrl+C to stop\n\n"),
+        None => print_now!("Watching the clipboard, Ctrl+C to stop\n\n"),
+    }
+    loop {
+        sleep(POLL_INTERVAL);
+        let content = clipboard.paste();
+        if content == last {
+            continue;
+        }
+        last = content.clone();
+        if content.trim().is_empty() {
+            continue;
+        }
+        let input = wrap_input(content.trim());
+        let output = match client.send_message(&input) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("{err:#}");
+                continue;
+            }
+        };
+        let output = output.trim();
+        print_now!("{output}\n\n");
+        config.lock().save_message(&input, output)?;
+        if write_back {
+            match clipboard.copy(output) {
+                // Not a new copy, the reply is not sent again
+                Ok(()) => last = clipboard.paste(),
+                Err(err) => error!("{err:#}"),
+            }
+        }
+    }
+}
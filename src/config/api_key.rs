@@ -0,0 +1,57 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Key of the api server, each with its own default model, allowed models and quota
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Name of the key, recorded in the usage log
+    pub name: String,
+    /// Secret sent as `Authorization: Bearer <key>`
+    pub key: String,
+    /// Model of requests that don't name one, as a role, `name`, `name:model` or an alias
+    pub model: Option<String>,
+    /// Models the key may use, every model when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Tokens the key may use per month
+    pub quota: Option<usize>,
+}
+
+impl ApiKey {
+    /// The requested model, or the default one of the key
+    pub fn resolve_model(&self, model: &str) -> Option<String> {
+        if !model.is_empty() {
+            return Some(model.to_string());
+        }
+        self.model.clone().or_else(|| self.models.first().cloned())
+    }
+
+    pub fn allows(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|v| v == model)
+    }
+}
+
+pub fn load_api_keys(path: &Path) -> Result<Vec<ApiKey>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load keys at {}", path.display()))?;
+    let keys: Vec<ApiKey> = serde_yaml::from_str(&content)
+        .with_context(|| format!("Invalid keys at {}", path.display()))?;
+    for (i, key) in keys.iter().enumerate() {
+        if keys[..i]
+            .iter()
+            .any(|v| v.name == key.name || v.key == key.key)
+        {
+            bail!(
+                "Duplicate key '{}' in {}, names and secrets must be unique",
+                key.name,
+                path.display()
+            );
+        }
+    }
+    Ok(keys)
+}
@@ -0,0 +1,269 @@
+//! Files attached with `-f` and `.file`, images go with the message and text files in the input
+
+use super::message::{image_mime, ImageUrl};
+
+use anyhow::{bail, Context, Result};
+use inquire::Select;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use tracing::warn;
+
+/// Bytes above which an attached text file is guarded, unless `max_file_size` is set
+pub const MAX_FILE_SIZE: u64 = 100 * 1024;
+/// Bytes above which an attached image is guarded, the limit of most providers
+const MAX_IMAGE_SIZE: u64 = 20 * 1024 * 1024;
+/// Lines kept of a text file by `head`
+const HEAD_LINES: usize = 200;
+/// Shortest run of printable characters kept by `strings`
+const MIN_STRING_LEN: usize = 4;
+/// Bytes looked at for a NUL byte, which tells binary files from text
+const SNIFF_SIZE: usize = 8000;
+/// Files read at the same time when attaching several
+const LOADING_TASKS: usize = 8;
+
+/// What to do with an attached file that is binary or too large
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileGuard {
+    /// Pick one of the others for each file, on a terminal
+    #[default]
+    Ask,
+    Skip,
+    /// The first lines of a text file
+    Head,
+    /// The printable strings of a binary file
+    Strings,
+    /// The whole file, binary files get their strings instead
+    Send,
+}
+
+impl fmt::Display for FileGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileGuard::Ask => write!(f, "ask"),
+            FileGuard::Skip => write!(f, "skip it"),
+            FileGuard::Head => write!(f, "its first {HEAD_LINES} lines"),
+            FileGuard::Strings => write!(f, "its printable strings"),
+            FileGuard::Send => write!(f, "all of it"),
+        }
+    }
+}
+
+/// Attached files, as image urls and as text to put in the input
+#[derive(Debug, Default)]
+pub struct Attachments {
+    pub images: Vec<String>,
+    pub texts: Vec<String>,
+}
+
+impl Attachments {
+    /// The input followed by the text files
+    pub fn input(&self, input: &str) -> String {
+        let mut parts = vec![input.to_string()];
+        parts.extend(self.texts.iter().cloned());
+        parts.retain(|v| !v.is_empty());
+        parts.join("\n\n")
+    }
+}
+
+/// A file as read, before the guard applies
+enum Loaded {
+    Image(String),
+    Text(String, String),
+    LargeImage(String, Vec<u8>),
+    LargeText(String, String),
+    Binary(String, Vec<u8>),
+}
+
+/// Read the files a few at a time with the progress on a terminal, then guard the binary
+/// and large ones one by one, so that asking about them doesn't mix
+pub fn load_attachments(paths: &[String], guard: FileGuard, max_size: u64) -> Result<Attachments> {
+    let mut attachments = Attachments::default();
+    for loaded in load_all(paths, max_size)? {
+        match loaded {
+            Loaded::Image(url) => attachments.images.push(url),
+            Loaded::Text(path, text) => attachments.texts.push(format_text(&path, &text)),
+            Loaded::LargeImage(path, data) => {
+                let size = data.len() as u64;
+                match choose(guard, &path, "a large image", size, &[FileGuard::Send])? {
+                    FileGuard::Skip => warn!("Skipped {path}"),
+                    _ => attachments.images.push(ImageUrl::encode(&path, &data)?),
+                }
+            }
+            Loaded::LargeText(path, text) => {
+                let size = text.len() as u64;
+                let options = [FileGuard::Head, FileGuard::Send];
+                match choose(guard, &path, "a large file", size, &options)? {
+                    FileGuard::Skip => warn!("Skipped {path}"),
+                    FileGuard::Head => {
+                        let text = head(&text);
+                        attachments.texts.push(format_text(&path, &text));
+                    }
+                    _ => attachments.texts.push(format_text(&path, &text)),
+                }
+            }
+            Loaded::Binary(path, data) => {
+                let size = data.len() as u64;
+                match choose(guard, &path, "a binary file", size, &[FileGuard::Strings])? {
+                    FileGuard::Skip => warn!("Skipped {path}"),
+                    _ => {
+                        let text = strings(&data, max_size as usize);
+                        attachments.texts.push(format_text(&path, &text));
+                    }
+                }
+            }
+        }
+    }
+    Ok(attachments)
+}
+
+fn load_all(paths: &[String], max_size: u64) -> Result<Vec<Loaded>> {
+    if paths.len() < 2 {
+        return paths.iter().map(|v| load(v, max_size)).collect();
+    }
+    let progress = atty::is(atty::Stream::Stderr);
+    let next = AtomicUsize::new(0);
+    let mut files: Vec<Option<Result<Loaded>>> = paths.iter().map(|_| None).collect();
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..LOADING_TASKS.min(paths.len()) {
+            let (tx, next) = (tx.clone(), &next);
+            s.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                match paths.get(index) {
+                    Some(path) if tx.send((index, load(path, max_size))).is_ok() => {}
+                    _ => break,
+                }
+            });
+        }
+        drop(tx);
+        for (done, (index, file)) in rx.iter().enumerate() {
+            if progress {
+                eprint!("\r\x1b[2KLoaded {}/{} files", done + 1, paths.len());
+            }
+            files[index] = Some(file);
+        }
+    });
+    if progress {
+        eprint!("\r\x1b[2K");
+    }
+    files.into_iter().flatten().collect()
+}
+
+fn load(path: &str, max_size: u64) -> Result<Loaded> {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return Ok(Loaded::Image(path.to_string()));
+    }
+    let data = fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+    if image_mime(path).is_some() {
+        if data.len() as u64 > MAX_IMAGE_SIZE {
+            return Ok(Loaded::LargeImage(path.to_string(), data));
+        }
+        return Ok(Loaded::Image(ImageUrl::encode(path, &data)?));
+    }
+    if data[..data.len().min(SNIFF_SIZE)].contains(&0) {
+        return Ok(Loaded::Binary(path.to_string(), data));
+    }
+    let text = match String::from_utf8(data) {
+        Ok(v) => v,
+        Err(err) => return Ok(Loaded::Binary(path.to_string(), err.into_bytes())),
+    };
+    if text.len() as u64 > max_size {
+        return Ok(Loaded::LargeText(path.to_string(), text));
+    }
+    Ok(Loaded::Text(path.to_string(), text))
+}
+
+/// The guard for the file, asked on a terminal, `send` and `head` only where they apply
+fn choose(
+    guard: FileGuard,
+    path: &str,
+    kind: &str,
+    size: u64,
+    options: &[FileGuard],
+) -> Result<FileGuard> {
+    if guard != FileGuard::Ask {
+        return Ok(guard);
+    }
+    let size = format!("{} KB", size / 1024);
+    if !atty::is(atty::Stream::Stdin) {
+        let names: Vec<String> = options
+            .iter()
+            .map(|v| format!("{v:?}").to_lowercase())
+            .collect();
+        bail!(
+            "{path} is {kind} ({size}), set `file_guard` to {} or skip",
+            names.join(", ")
+        );
+    }
+    let mut options = options.to_vec();
+    options.push(FileGuard::Skip);
+    let answer = Select::new(&format!("{path} is {kind} ({size}), attach"), options).prompt()?;
+    Ok(answer)
+}
+
+/// The file in a fenced block under its path, the fence longer than any inside
+fn format_text(path: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{path}:\n{fence}\n{}\n{fence}", text.trim_end())
+}
+
+fn head(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= HEAD_LINES {
+        return text.to_string();
+    }
+    format!(
+        "{}\n… {} more lines",
+        lines[..HEAD_LINES].join("\n"),
+        lines.len() - HEAD_LINES
+    )
+}
+
+/// Runs of printable ascii like the `strings` command, up to `max_size` bytes
+fn strings(data: &[u8], max_size: usize) -> String {
+    let mut output = String::new();
+    for run in data.split(|v| !(v.is_ascii_graphic() || *v == b' ' || *v == b'\t')) {
+        if run.len() < MIN_STRING_LEN {
+            continue;
+        }
+        if output.len() + run.len() > max_size {
+            output.push_str("…\n");
+            break;
+        }
+        output.push_str(&String::from_utf8_lossy(run));
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards() {
+        assert_eq!(
+            strings(b"\x7fELF\x02\x01\0\0main\0\x01ab\0GLIBC_2.34\0", 100),
+            "main\nGLIBC_2.34\n"
+        );
+        assert_eq!(strings(b"first\0second\0third", 10), "first\n…\n");
+        let text: Vec<String> = (1..=250).map(|v| v.to_string()).collect();
+        let output = head(&text.join("\n"));
+        assert!(output.ends_with("\n200\n… 50 more lines"));
+        assert_eq!(
+            format_text("a.md", "```sh\nls\n```\n"),
+            "a.md:\n````\n```sh\nls\n```\n````"
+        );
+    }
+}
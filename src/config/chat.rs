@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Number of chats listed by `.history`
+const RECENT_CHATS: usize = 10;
+
+/// Entry of the chat log, one json object per line
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chat {
+    pub time: String,
+    /// Client and model as name:model
+    pub model: String,
+    pub role: Option<String>,
+    pub input: String,
+    pub output: String,
+}
+
+impl Chat {
+    fn summary(&self, index: usize) -> String {
+        let time = self.time.get(..16).unwrap_or(&self.time).replace('T', " ");
+        let input = self.input.lines().next().unwrap_or_default();
+        let input = match input.char_indices().nth(60) {
+            Some((i, _)) => format!("{}…", &input[..i]),
+            None => input.to_string(),
+        };
+        format!("{index:>4}  {time}  {input}")
+    }
+}
+
+pub fn load_chats(path: &Path) -> Result<Vec<Chat>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load chats at {}", path.display()))?;
+    // Skip lines that fail to parse, e.g. cut short by a crash, rather than the whole log
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+pub fn save_chat(path: &Path, chat: &Chat) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let line = serde_json::to_string(chat)?;
+    writeln!(file, "{line}").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The latest chats, or those whose input or output contains the term, numbered from the first chat
+pub fn list_chats(chats: &[Chat], term: Option<&str>) -> String {
+    let lines: Vec<String> = match term {
+        None => chats
+            .iter()
+            .enumerate()
+            .skip(chats.len().saturating_sub(RECENT_CHATS))
+            .map(|(i, chat)| chat.summary(i + 1))
+            .collect(),
+        Some(term) => {
+            let term = term.to_lowercase();
+            chats
+                .iter()
+                .enumerate()
+                .filter(|(_, chat)| {
+                    chat.input.to_lowercase().contains(&term)
+                        || chat.output.to_lowercase().contains(&term)
+                })
+                .map(|(i, chat)| chat.summary(i + 1))
+                .collect()
+        }
+    };
+    if lines.is_empty() {
+        return "No chats".into();
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_chats() {
+        let chat = |input: &str, output: &str| Chat {
+            time: "2024-03-01T10:20:30+01:00".into(),
+            model: "openai:gpt-4".into(),
+            role: None,
+            input: input.into(),
+            output: output.into(),
+        };
+        let chats = vec![
+            chat("how to post a json in rust", "Use reqwest"),
+            chat("convert toml to json\n[package]", "{}"),
+            chat("hello", "Hi"),
+        ];
+        assert_eq!(
+            list_chats(&chats, Some("JSON")),
+            "   1  2024-03-01 10:20  how to post a json in rust\n   2  2024-03-01 10:20  convert toml to json"
+        );
+        assert_eq!(
+            list_chats(&chats, Some("reqwest")),
+            "   1  2024-03-01 10:20  how to post a json in rust"
+        );
+        assert_eq!(list_chats(&chats, Some("python")), "No chats");
+    }
+}
@@ -1,15 +1,28 @@
-use super::message::{num_tokens_from_messages, Message, MessageRole};
+use super::message::{
+    num_tokens_from_message, num_tokens_from_messages, Message, MessageContent, MessageRole,
+};
 use super::role::Role;
-use super::MAX_TOKENS;
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Messages at the end that compaction leaves as they are, the last exchange
+const KEEP_MESSAGES: usize = 2;
+const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Conversation {
     pub tokens: usize,
     pub role: Option<Role>,
     pub messages: Vec<Message>,
+    /// Parameters set with `.set` during the conversation, over those of the role and config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
 }
 
 impl Conversation {
@@ -18,6 +31,9 @@ impl Conversation {
             tokens: 0,
             role,
             messages: vec![],
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
         };
         value.update_tokens();
         value
@@ -48,22 +64,17 @@ impl Conversation {
         if need_add_msg {
             self.messages.push(Message {
                 role: MessageRole::User,
-                content: input.to_string(),
+                content: self.wrap_input(input).into(),
             });
         }
         self.messages.push(Message {
             role: MessageRole::Assistant,
-            content: output.to_string(),
+            content: output.into(),
         });
         self.tokens = num_tokens_from_messages(&self.messages);
         Ok(())
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        let messages = self.build_emssages(content);
-        serde_yaml::to_string(&messages).unwrap_or("Unable to echo message".into())
-    }
-
     pub fn build_emssages(&self, content: &str) -> Vec<Message> {
         let mut messages = self.messages.to_vec();
         let mut need_add_msg = true;
@@ -76,13 +87,204 @@ impl Conversation {
         if need_add_msg {
             messages.push(Message {
                 role: MessageRole::User,
-                content: content.into(),
+                content: self.wrap_input(content).into(),
             });
         }
         messages
     }
 
-    pub fn reamind_tokens(&self) -> usize {
-        MAX_TOKENS.saturating_sub(self.tokens)
+    /// The input wrapped by the role, as every message of the conversation is
+    fn wrap_input(&self, input: &str) -> String {
+        match self.role.as_ref() {
+            Some(role) => role.wrap_input(input),
+            None => input.to_string(),
+        }
+    }
+
+    /// Last messages of the conversation, for prompts that need its context
+    pub fn recent_history(&self, count: usize) -> String {
+        let start = self.messages.len().saturating_sub(count);
+        self.messages[start..]
+            .iter()
+            .map(|v| format!("{}: {}", v.role.name(), v.content))
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    }
+
+    pub fn tokens_info(&self, context_size: usize) -> String {
+        let mut output = format!("{:<6}{:<12}{:<10}{}\n", "#", "role", "tokens", "total");
+        let mut total = 0;
+        for (i, message) in self.messages.iter().enumerate() {
+            let tokens = num_tokens_from_message(message);
+            total += tokens;
+            output.push_str(&format!(
+                "{:<6}{:<12}{:<10}{}\n",
+                i + 1,
+                message.role.name(),
+                tokens,
+                total
+            ));
+        }
+        output.push_str(&format!(
+            "\n{} tokens used, {} tokens remain\n",
+            self.tokens,
+            self.reamind_tokens(context_size)
+        ));
+        output
+    }
+
+    pub fn reamind_tokens(&self, context_size: usize) -> usize {
+        context_size.saturating_sub(self.tokens)
+    }
+
+    /// Drop the last exchange, the role applies again once no exchange is left
+    pub fn undo(&mut self) -> Result<()> {
+        if !matches!(
+            self.messages.last().map(|v| &v.role),
+            Some(MessageRole::Assistant)
+        ) {
+            bail!("Error: Nothing to undo");
+        }
+        self.messages.pop();
+        while let Some(message) = self.messages.pop() {
+            if matches!(message.role, MessageRole::User) {
+                break;
+            }
+        }
+        let has_user = self
+            .messages
+            .iter()
+            .any(|v| matches!(v.role, MessageRole::User));
+        if !has_user && self.summary().is_none() {
+            self.messages.clear();
+            self.update_tokens();
+        } else {
+            self.tokens = num_tokens_from_messages(&self.messages);
+        }
+        Ok(())
+    }
+
+    /// The messages compaction would replace, with the summary of an earlier compaction
+    pub fn compactable_text(&self) -> Option<String> {
+        let range = self.compact_range()?;
+        let mut parts: Vec<String> = self.summary().map(|v| v.to_string()).into_iter().collect();
+        parts.extend(
+            self.messages[range]
+                .iter()
+                .map(|v| format!("{}: {}", v.role.name(), v.content)),
+        );
+        Some(parts.join("\n\n"))
+    }
+
+    /// Drop the messages before the last exchange, keeping the summary of them if given
+    pub fn compact(&mut self, summary: Option<&str>) -> Result<()> {
+        let Some(range) = self.compact_range() else {
+            bail!("Error: Nothing to compact, the conversation is only the last exchange");
+        };
+        self.messages.drain(range);
+        if let Some(summary) = summary {
+            let summary = format!("{SUMMARY_HEADER}\n{}", summary.trim());
+            match self.messages.first_mut() {
+                Some(message) if matches!(message.role, MessageRole::System) => {
+                    let content = message.content.to_string();
+                    let prompt = match content.split_once(SUMMARY_HEADER) {
+                        Some((prompt, _)) => prompt.trim_end().to_string(),
+                        None => content,
+                    };
+                    message.content = if prompt.is_empty() {
+                        summary.into()
+                    } else {
+                        format!("{prompt}\n\n{summary}").into()
+                    };
+                }
+                _ => self.messages.insert(
+                    0,
+                    Message {
+                        role: MessageRole::System,
+                        content: summary.into(),
+                    },
+                ),
+            }
+        }
+        self.tokens = num_tokens_from_messages(&self.messages);
+        Ok(())
+    }
+
+    /// Messages between the leading system message and the last exchange
+    fn compact_range(&self) -> Option<Range<usize>> {
+        let start = self
+            .messages
+            .iter()
+            .take_while(|v| matches!(v.role, MessageRole::System))
+            .count();
+        let end = self.messages.len().saturating_sub(KEEP_MESSAGES);
+        (start < end).then_some(start..end)
+    }
+
+    fn summary(&self) -> Option<&str> {
+        let message = self.messages.first()?;
+        if !matches!(message.role, MessageRole::System) {
+            return None;
+        }
+        match &message.content {
+            MessageContent::Text(text) => text.split_once(SUMMARY_HEADER).map(|(_, v)| v.trim()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact() {
+        let mut conversation = Conversation::new(Some(Role::new("Be terse.", None)));
+        conversation.add_message("a", "1").unwrap();
+        conversation.add_message("b", "2").unwrap();
+        conversation.add_message("c", "3").unwrap();
+        assert_eq!(
+            conversation.compactable_text().unwrap(),
+            "user: a\n\nassistant: 1\n\nuser: b\n\nassistant: 2"
+        );
+        conversation.compact(Some("Asked a and b.")).unwrap();
+        let contents: Vec<String> = conversation
+            .messages
+            .iter()
+            .map(|v| v.content.to_string())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Be terse.\n\nSummary of the earlier conversation:\nAsked a and b.",
+                "c",
+                "3"
+            ]
+        );
+        conversation.add_message("d", "4").unwrap();
+        assert_eq!(
+            conversation.compactable_text().unwrap(),
+            "Asked a and b.\n\nuser: c\n\nassistant: 3"
+        );
+        conversation.compact(None).unwrap();
+        assert_eq!(conversation.messages.len(), 3);
+        assert!(conversation.compact(None).is_err());
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut conversation = Conversation::new(Some(Role::new("Be terse.", None)));
+        assert!(conversation.undo().is_err());
+        conversation.add_message("a", "1").unwrap();
+        conversation.add_message("b", "2").unwrap();
+        conversation.undo().unwrap();
+        assert_eq!(conversation.messages.len(), 3);
+        assert_eq!(conversation.messages[2].content.to_string(), "1");
+        conversation.undo().unwrap();
+        assert!(conversation.messages.is_empty());
+        assert_eq!(
+            conversation.tokens,
+            Conversation::new(conversation.role.clone()).tokens
+        );
     }
 }
@@ -0,0 +1,253 @@
+use super::role::{is_reserved_role_name, Role, BUILTIN_VARIABLES, ROLE_FIELDS};
+use super::{Config, CONTEXT_SIZE};
+
+use crate::utils::count_tokens;
+
+use serde::Serialize;
+use serde_yaml::Value;
+
+/// Share of the context of the model a prompt may take before it is flagged
+const PROMPT_SHARE_LIMIT: f64 = 0.5;
+/// Instructions pulling the reply in opposite directions
+const CONFLICTS: [(&str, &str); 5] = [
+    ("concise", "in detail"),
+    ("brief", "elaborate"),
+    ("only code", "explain"),
+    ("without explanation", "explain"),
+    ("plain text", "markdown"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    /// Position of the role in the file, from 1
+    pub index: usize,
+    pub role: Option<String>,
+    pub level: Level,
+    pub message: String,
+}
+
+/// Check the roles file content, the roles that parse are resolved against the config
+pub fn lint_roles(config: &mut Config, content: &str) -> Vec<Issue> {
+    let entries = match serde_yaml::from_str::<Value>(content) {
+        Ok(Value::Sequence(v)) => v,
+        Ok(Value::Null) => vec![],
+        Ok(_) => return vec![issue(0, None, Level::Error, "Roles must be a list")],
+        Err(err) => {
+            return vec![issue(
+                0,
+                None,
+                Level::Error,
+                &format!("Invalid yaml, {err}"),
+            )]
+        }
+    };
+    let mut issues = vec![];
+    let mut roles: Vec<(usize, Role)> = vec![];
+    for (i, entry) in entries.into_iter().enumerate() {
+        let index = i + 1;
+        let name = entry["name"].as_str().map(|v| v.to_string());
+        let mut add =
+            |level, message: &str| issues.push(issue(index, name.clone(), level, message));
+        let Some(map) = entry.as_mapping() else {
+            add(Level::Error, "Not a role, expected a `name` and a `prompt`");
+            continue;
+        };
+        for key in map.keys() {
+            let key = key.as_str().unwrap_or_default();
+            if !ROLE_FIELDS.contains(&key) {
+                add(
+                    Level::Warning,
+                    &format!("Unknown field `{key}`, it is ignored"),
+                );
+            }
+        }
+        if name.as_deref().unwrap_or_default().trim().is_empty() {
+            add(Level::Error, "Missing `name`");
+            continue;
+        }
+        if !entry["prompt"].is_string() {
+            add(Level::Error, "Missing `prompt`");
+            continue;
+        }
+        if is_reserved_role_name(name.as_deref().unwrap_or_default()) {
+            add(
+                Level::Error,
+                "Reserved name, `.role` reads it as a command and can't select the role",
+            );
+        }
+        match serde_yaml::from_value::<Role>(entry) {
+            Ok(role) if roles.iter().any(|(_, v)| v.name == role.name) => {
+                add(Level::Error, "Duplicate name, only the first role is used")
+            }
+            Ok(role) => roles.push((index, role)),
+            Err(err) => add(Level::Error, &format!("Invalid role, {err}")),
+        }
+    }
+    config.roles = roles.iter().map(|(_, v)| v.clone()).collect();
+    for (index, role) in &roles {
+        let mut add = |level, message: &str| {
+            issues.push(issue(*index, Some(role.name.clone()), level, message))
+        };
+        for message in lint_role(config, role) {
+            add(message.0, &message.1);
+        }
+    }
+    issues.sort_by_key(|v| v.index);
+    issues
+}
+
+/// Issues as text, or json for scripts
+pub fn format_issues(issues: &[Issue], total: usize, json: bool) -> String {
+    if json {
+        return serde_json::to_string_pretty(issues).unwrap_or_default();
+    }
+    let mut lines: Vec<String> = issues
+        .iter()
+        .map(|v| {
+            let level = match v.level {
+                Level::Error => "error",
+                Level::Warning => "warning",
+            };
+            match (&v.role, v.index) {
+                (Some(name), _) => format!("{level}: role '{name}': {}", v.message),
+                (None, 0) => format!("{level}: {}", v.message),
+                (None, index) => format!("{level}: role #{index}: {}", v.message),
+            }
+        })
+        .collect();
+    let errors = issues.iter().filter(|v| v.level == Level::Error).count();
+    let warnings = issues.len() - errors;
+    if issues.is_empty() {
+        lines.push(format!("No issues in {total} roles"));
+    } else {
+        lines.push(format!("{errors} errors, {warnings} warnings"));
+    }
+    lines.join("\n")
+}
+
+fn lint_role(config: &Config, role: &Role) -> Vec<(Level, String)> {
+    let mut output = vec![];
+    if let Some(v) = role.temperature.filter(|v| !(0.0..=2.0).contains(v)) {
+        output.push((
+            Level::Error,
+            format!("Temperature {v} is not between 0 and 2"),
+        ));
+    }
+    if let Some(v) = role.top_p.filter(|v| !(0.0..=1.0).contains(v)) {
+        output.push((Level::Error, format!("Top_p {v} is not between 0 and 1")));
+    }
+    let client_config = match &role.model {
+        Some(model) => match config.find_client(model) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                output.push((Level::Error, format!("Unknown model `{model}`")));
+                None
+            }
+        },
+        None => config.clients.first().cloned(),
+    };
+    let resolved = match config.resolve_role(&role.name) {
+        Ok(v) => v,
+        Err(err) => {
+            output.push((Level::Error, format!("{err}")));
+            return output;
+        }
+    };
+    if role.prompt.matches("{{").count() > role.prompt.matches("}}").count() {
+        output.push((Level::Warning, "Unclosed `{{` in the prompt".into()));
+    }
+    for name in resolved.variables() {
+        if !BUILTIN_VARIABLES.contains(&name.as_str()) && !config.variables.contains_key(&name) {
+            output.push((
+                Level::Warning,
+                format!("No value for {{{{{name}}}}}, it is asked for unless given with `--var {name}=<value>`"),
+            ));
+        }
+    }
+    let context = client_config
+        .and_then(|v| v.as_client().context_size())
+        .unwrap_or(CONTEXT_SIZE);
+    let tokens = count_tokens(&resolved.prompt);
+    if tokens >= context {
+        output.push((
+            Level::Error,
+            format!("The prompt takes {tokens} tokens, the model only has {context}"),
+        ));
+    } else if tokens as f64 > context as f64 * PROMPT_SHARE_LIMIT {
+        output.push((
+            Level::Warning,
+            format!("The prompt takes {tokens} of the {context} tokens of the model"),
+        ));
+    }
+    let prompt = resolved.prompt.to_lowercase();
+    for (a, b) in CONFLICTS {
+        if prompt.contains(a) && prompt.contains(b) {
+            output.push((
+                Level::Warning,
+                format!("The prompt may conflict, it asks for '{a}' and '{b}'"),
+            ));
+        }
+    }
+    output
+}
+
+fn issue(index: usize, role: Option<String>, level: Level, message: &str) -> Issue {
+    Issue {
+        index,
+        role,
+        level,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_roles() {
+        let mut config: Config = serde_yaml::from_str("clients: []").unwrap();
+        let content = r#"
+- name: concise
+  prompt: Be concise.
+- name: writer
+  extends: concise
+  prompt: Explain {{topic}} in detail.
+  temperature: 3
+- name: concise
+  prompt: Duplicate
+- prompt: No name
+- name: broken
+  extends: missing
+  prompt: "{{lang"
+  tone: friendly
+- name: info
+  prompt: Shadowed by `.role info`
+"#;
+        let issues = lint_roles(&mut config, content);
+        let messages: Vec<String> = issues
+            .iter()
+            .map(|v| format!("{}:{:?}:{}", v.index, v.level, v.message))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2:Error:Temperature 3 is not between 0 and 2",
+                "2:Warning:No value for {{topic}}, it is asked for unless given with `--var topic=<value>`",
+                "2:Warning:The prompt may conflict, it asks for 'concise' and 'in detail'",
+                "3:Error:Duplicate name, only the first role is used",
+                "4:Error:Missing `name`",
+                "5:Warning:Unknown field `tone`, it is ignored",
+                "5:Error:Unknown role 'missing'",
+                "6:Error:Reserved name, `.role` reads it as a command and can't select the role",
+            ]
+        );
+    }
+}
@@ -1,22 +1,151 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
 
 use crate::utils::count_tokens;
 
+/// Rough cost of an image, as charged at high detail for a 1024x1024 picture
+pub const IMAGE_TOKENS: usize = 765;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
     pub role: MessageRole,
-    pub content: String,
+    pub content: MessageContent,
 }
 
 impl Message {
     pub fn new(content: &str) -> Self {
         Self {
             role: MessageRole::User,
-            content: content.to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Plain text, or text and images for vision models
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Array(Vec<MessageContentPart>),
+}
+
+impl MessageContent {
+    /// Text followed by the given images
+    pub fn with_images(text: &str, images: &[String]) -> Self {
+        if images.is_empty() {
+            return text.into();
+        }
+        let mut parts = vec![MessageContentPart::Text { text: text.into() }];
+        parts.extend(images.iter().map(|url| MessageContentPart::ImageUrl {
+            image_url: ImageUrl { url: url.clone() },
+        }));
+        MessageContent::Array(parts)
+    }
+
+    pub fn images(&self) -> usize {
+        match self {
+            MessageContent::Text(_) => 0,
+            MessageContent::Array(parts) => parts
+                .iter()
+                .filter(|v| matches!(v, MessageContentPart::ImageUrl { .. }))
+                .count(),
         }
     }
 }
 
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::Text(text.into())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+/// The text of the content, images left out
+impl fmt::Display for MessageContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageContent::Text(text) => write!(f, "{text}"),
+            MessageContent::Array(parts) => {
+                let texts: Vec<&str> = parts
+                    .iter()
+                    .filter_map(|v| match v {
+                        MessageContentPart::Text { text } => Some(text.as_str()),
+                        MessageContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect();
+                write!(f, "{}", texts.join("\n\n"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImageUrl {
+    /// Web address, or data url holding the base64 encoded image
+    pub url: String,
+}
+
+impl ImageUrl {
+    /// Data url of an image file, its type told by its first bytes
+    pub fn encode(path: &str, data: &[u8]) -> Result<String> {
+        let mime = match sniff_image(data) {
+            Some(v) => v,
+            None => bail!("{path} is not a png, jpeg, webp or gif image"),
+        };
+        let data = general_purpose::STANDARD.encode(data);
+        Ok(format!("data:{mime};base64,{data}"))
+    }
+}
+
+/// Short description of an image url, data urls are summarized by type and size
+pub fn describe_image(url: &str) -> String {
+    match url
+        .strip_prefix("data:")
+        .and_then(|v| v.split_once(";base64,"))
+    {
+        Some((mime, data)) => format!("{mime}, {} KB", data.len() * 3 / 4 / 1024),
+        None => url.to_string(),
+    }
+}
+
+/// Mime type of an image, judged by the signature its data starts with
+fn sniff_image(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Mime type of an image file, judged by its extension
+pub fn image_mime(path: &str) -> Option<&'static str> {
+    let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "gif" => Some("image/gif"),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageRole {
@@ -25,17 +154,33 @@ pub enum MessageRole {
     User,
 }
 
+impl MessageRole {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageRole::System => "system",
+            MessageRole::Assistant => "assistant",
+            MessageRole::User => "user",
+        }
+    }
+}
+
 pub fn num_tokens_from_messages(messages: &[Message]) -> usize {
     let mut num_tokens = 0;
     for message in messages.iter() {
-        num_tokens += 4;
-        num_tokens += count_tokens(&message.content);
-        num_tokens += 1; // role always take 1 token
+        num_tokens += num_tokens_from_message(message);
     }
     num_tokens += 2;
     num_tokens
 }
 
+pub fn num_tokens_from_message(message: &Message) -> usize {
+    let mut num_tokens = 4;
+    num_tokens += count_tokens(&message.content.to_string());
+    num_tokens += message.content.images() * IMAGE_TOKENS;
+    num_tokens += 1; // role always take 1 token
+    num_tokens
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,6 +190,21 @@ mod tests {
         assert_eq!(
             serde_json::to_string(&Message::new("Hello World")).unwrap(),
             "{\"role\":\"user\",\"content\":\"Hello World\"}"
-        )
+        );
+        let message = Message {
+            role: MessageRole::User,
+            content: MessageContent::with_images(
+                "What is it?",
+                &["data:image/png;base64,AA==".into()],
+            ),
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            json,
+            "{\"role\":\"user\",\"content\":[{\"type\":\"text\",\"text\":\"What is it?\"},\
+{\"type\":\"image_url\",\"image_url\":{\"url\":\"data:image/png;base64,AA==\"}}]}"
+        );
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.content, message.content);
     }
 }
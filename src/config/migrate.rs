@@ -0,0 +1,81 @@
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Version of the config file written by this release
+pub const CONFIG_VERSION: u64 = 2;
+
+/// Migrations indexed by the version they upgrade from
+const MIGRATIONS: [fn(&mut Mapping) -> Vec<String>; 2] = [migrate_v0, migrate_v1];
+
+/// Upgrade the config in place, returning a warning for every deprecated key found
+pub fn migrate(value: &mut Value) -> Result<Vec<String>> {
+    let map = value
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("Config must be a mapping"))?;
+    let version = match map.get("version") {
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| anyhow!("Invalid config version {v:?}"))?,
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        bail!("Config version {version} is newer than {CONFIG_VERSION}, please upgrade aichat");
+    }
+    let mut warnings = vec![];
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        warnings.extend(migration(map));
+    }
+    map.insert("version".into(), CONFIG_VERSION.into());
+    Ok(warnings)
+}
+
+/// Unversioned configs only need to be stamped with a version
+fn migrate_v0(_map: &mut Mapping) -> Vec<String> {
+    vec![]
+}
+
+/// `api_key` moved into an openai entry of `clients`
+fn migrate_v1(map: &mut Mapping) -> Vec<String> {
+    let api_key = match map.remove("api_key") {
+        Some(v) => v,
+        None => return vec![],
+    };
+    if !map.contains_key("clients") {
+        let mut client = Mapping::new();
+        client.insert("type".into(), "openai".into());
+        client.insert("api_key".into(), api_key);
+        map.insert("clients".into(), Value::Sequence(vec![client.into()]));
+    }
+    vec!["`api_key` is deprecated, use `clients` instead".into()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_unversioned() {
+        let mut value: Value = serde_yaml::from_str("api_key: sk-xxx").unwrap();
+        let warnings = migrate(&mut value).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(value["version"].as_u64(), Some(CONFIG_VERSION));
+        assert!(value.get("api_key").is_none());
+        assert_eq!(value["clients"][0]["type"].as_str(), Some("openai"));
+        assert_eq!(value["clients"][0]["api_key"].as_str(), Some("sk-xxx"));
+    }
+
+    #[test]
+    fn test_migrate_current() {
+        let content = "version: 2\nclients:\n- type: openai\n  api_key: sk-xxx\n";
+        let mut value: Value = serde_yaml::from_str(content).unwrap();
+        let original = value.clone();
+        assert!(migrate(&mut value).unwrap().is_empty());
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn test_migrate_newer() {
+        let mut value: Value = serde_yaml::from_str("version: 999\napi_key: sk-xxx").unwrap();
+        assert!(migrate(&mut value).is_err());
+    }
+}
@@ -1,63 +1,219 @@
+mod api_key;
+mod attachment;
+mod chat;
 mod conversation;
+mod lint;
 mod message;
+mod migrate;
+mod pipeline;
 mod role;
+mod session;
+mod usage;
 
-use self::message::Message;
-use self::role::Role;
+use self::api_key::load_api_keys;
+pub use self::api_key::ApiKey;
+use self::attachment::{load_attachments, Attachments, FileGuard, MAX_FILE_SIZE};
+use self::chat::{list_chats, load_chats, save_chat, Chat};
+use self::lint::{format_issues, lint_roles, Level};
+pub use self::message::image_mime;
+use self::message::{
+    describe_image, num_tokens_from_message, Message, MessageContent, MessageRole, IMAGE_TOKENS,
+};
+use self::migrate::{migrate, CONFIG_VERSION};
+pub use self::pipeline::Pipeline;
+use self::role::{is_reserved_role_name, merge_prompt_content, Role, BUILTIN_VARIABLES};
+pub use self::session::session_documents;
+use self::session::{last_session, list_sessions, Session};
+use self::usage::{
+    load_usage, parse_report_range, range_months, save_usage, summarize_usage, usage_report,
+    ModelPrice, Usage,
+};
 use self::{conversation::Conversation, message::num_tokens_from_messages};
 
-use crate::utils::now;
+use crate::client::ClientConfig;
+use crate::rag::{list_rags, CrawlOptions, Rag, WeightedRag};
+use crate::render::RenderOptions;
+use crate::shell::{os_version, resolve_shell};
+use crate::utils::{
+    count_tokens, detect_code_language, edit_file, glob_match, mask_marker, mask_text, now,
+    StartupProfile,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use inquire::{Confirm, Text};
 use parking_lot::Mutex;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
 use std::{
+    collections::BTreeMap,
     env,
     fs::{create_dir_all, read_to_string, File, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
     process::exit,
     sync::Arc,
+    time::Instant,
 };
+use tracing::{debug, info, warn};
 
-const MAX_TOKENS: usize = 4096;
+const CONTEXT_SIZE: usize = 4096;
+/// Price of gpt-3.5-turbo in dollars per 1k tokens
+const PRICE_PER_1K_TOKENS: f64 = 0.002;
+const COMPACT_THRESHOLD: f64 = 0.9;
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_FILE_NAME: &str = "roles.yaml";
+const KEYS_FILE_NAME: &str = "keys.yaml";
 const HISTORY_FILE_NAME: &str = "history.txt";
+const COMMANDS_FILE_NAME: &str = "commands.jsonl";
+const CHATS_FILE_NAME: &str = "chats.jsonl";
 const MESSAGE_FILE_NAME: &str = "messages.md";
-const SET_COMPLETIONS: [&str; 9] = [
+const SESSIONS_DIR_NAME: &str = "sessions";
+const RAGS_DIR_NAME: &str = "rags";
+const USAGE_DIR_NAME: &str = "usage";
+const PROTECT_PROMPT: &str =
+    "Keep the markers like ⟦0⟧ exactly as they are, they stand for code and links.";
+const SET_COMPLETIONS: [&str; 30] = [
     ".set api_key",
     ".set temperature",
+    ".set top_p",
+    ".set max_tokens",
     ".set save true",
     ".set save false",
     ".set highlight true",
     ".set highlight false",
     ".set proxy",
+    ".set reply_language",
+    ".set shell",
+    ".set step true",
+    ".set step false",
+    ".set smart_paste true",
+    ".set smart_paste false",
+    ".set rag_grounding_check true",
+    ".set rag_grounding_check false",
+    ".set rag_max_tokens",
+    ".set code_line_numbers true",
+    ".set code_line_numbers false",
+    ".set max_speed",
+    ".set confirm_cost_above",
+    ".set max_retries",
+    ".set compact summarize",
+    ".set compact truncate",
+    ".set compact_threshold",
     ".set dry_run true",
     ".set dry_run false",
+    ".set terminal_title true",
+    ".set terminal_title false",
 ];
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    /// Openai api key
-    pub api_key: String,
+    /// Api providers
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    /// Client to use as `name` or `name:model`, defaults to the first client
+    pub model: Option<String>,
+    /// Model generating shell commands with `-e` and `.sh`, as `name`, `name:model` or an alias
+    pub shell_model: Option<String>,
+    /// Model summarizing the conversation when it is compacted
+    pub summary_model: Option<String>,
+    /// Client whose embeddings build the rag indexes
+    pub embedding_model: Option<String>,
+    /// Short names of models given as `name:model`, accepted wherever a model is
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Roles applied to `-f` files matching a glob, e.g. `"*.rs": rust-expert`, unless a role is given
+    #[serde(default)]
+    pub file_roles: BTreeMap<String, String>,
     /// What sampling temperature to use, between 0 and 2
     pub temperature: Option<f64>,
+    /// Nucleus sampling, the probability mass of the tokens to consider
+    pub top_p: Option<f64>,
     /// Whether to persistently save chat messages
     #[serde(default)]
     pub save: bool,
     /// Whether to disable highlight
     #[serde(default = "highlight_value")]
     pub highlight: bool,
-    /// Set proxy
+    /// Proxy of all clients, e.g. http://127.0.0.1:8080 or socks5://127.0.0.1:1080, `none` to
+    /// ignore the `HTTPS_PROXY` and `ALL_PROXY` env vars
     pub proxy: Option<String>,
-    /// Used only for debugging
+    /// Language the replies should always be written in
+    pub reply_language: Option<String>,
+    /// Whether to pause at every paragraph and wait for feedback in a conversation
+    #[serde(default)]
+    pub step: bool,
+    /// Whether to offer wrapping raw code input in a fenced block
+    #[serde(default)]
+    pub smart_paste: bool,
+    /// Whether to check replies against the retrieved chunks and flag unsupported claims
+    #[serde(default)]
+    pub rag_grounding_check: bool,
+    /// Tokens of the chunks retrieved from all the rags in use, 4 chunks when unset
+    pub rag_max_tokens: Option<usize>,
+    /// Limits of the crawls of `.rag add`
+    #[serde(default)]
+    pub rag_crawl: CrawlOptions,
+    /// Minutes between rebuilds of each named rag while serving with `--serve`
+    #[serde(default)]
+    pub rag_refresh: BTreeMap<String, u64>,
+    /// Whether to number the lines inside code blocks
+    #[serde(default)]
+    pub code_line_numbers: bool,
+    /// Maximum characters per second to render streaming output in repl
+    pub max_speed: Option<u32>,
+    /// Ask for confirmation when the estimated cost of a request exceeds this many dollars
+    pub confirm_cost_above: Option<f64>,
+    /// Dollars per 1K prompt and completion tokens by model, for the models not priced at the default
+    #[serde(default)]
+    pub pricing: Vec<ModelPrice>,
+    /// Whether to show progress in the terminal title
+    #[serde(default)]
+    pub terminal_title: bool,
+    /// How to shorten a conversation nearing the context size, summarize or truncate
+    #[serde(default)]
+    pub compact: CompactMode,
+    /// Share of the context, from 0 to 1, after which a conversation is compacted, 0.9 by default
+    pub compact_threshold: Option<f64>,
+    /// Whether to show the model, role and last session upon repl
+    #[serde(default = "banner_value")]
+    pub banner: bool,
+    /// Whether to establish a connection to the api server upon repl
+    #[serde(default)]
+    pub prewarm: bool,
+    /// Times to retry a request that is rate limited, fails on the server or cannot connect
+    pub max_retries: Option<usize>,
+    /// Seconds to keep idle connections alive in the pool
+    pub pool_idle_timeout: Option<u64>,
+    /// Maximum idle connections kept per host
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Edit mode of the repl, emacs or vi
+    #[serde(default)]
+    pub edit_mode: EditMode,
+    /// Keys of the repl bound to a repl command or an editor action, e.g. `ctrl-o: .edit`
+    #[serde(default)]
+    pub keybindings: BTreeMap<String, String>,
+    /// Whether to keep ANSI escapes and control characters of piped input
+    #[serde(default)]
+    pub raw_stdin: bool,
+    /// What to do with an attached file that is binary or too large: ask, skip, head, strings or send
+    #[serde(default)]
+    pub file_guard: FileGuard,
+    /// Bytes above which an attached text file is too large, 100 KB by default
+    pub max_file_size: Option<u64>,
+    /// Shell the generated commands are for, as a name or path, detected when unset
+    pub shell: Option<String>,
+    /// Print the request body in place of the reply instead of sending it
     #[serde(default)]
     pub dry_run: bool,
+    /// Only use local clients and only send requests to local hosts, e.g. in air-gapped networks
+    #[serde(default)]
+    pub offline: bool,
     /// If set ture, start a conversation immediately upon repl
     #[serde(default)]
     pub conversation_first: bool,
+    /// Predefined pipelines
+    #[serde(default)]
+    pub pipelines: Vec<Pipeline>,
     /// Predefined roles
     #[serde(skip)]
     pub roles: Vec<Role>,
@@ -67,32 +223,323 @@ pub struct Config {
     /// Current conversation
     #[serde(skip)]
     pub conversation: Option<Conversation>,
+    /// Values of the `{{name}}` placeholders of role prompts, set with `--var`
+    #[serde(skip)]
+    pub variables: BTreeMap<String, String>,
+    /// Temperature of the current message, set by an inline directive
+    #[serde(skip)]
+    pub message_temperature: Option<f64>,
+    /// Model of the current message, set by an inline directive
+    #[serde(skip)]
+    pub message_model: Option<String>,
+    /// Rag indexes in use, with the weights of their chunks
+    #[serde(skip)]
+    pub rags: Vec<WeightedRag>,
+    /// Chunks retrieved for the current message
+    #[serde(skip)]
+    pub rag_context: Option<String>,
+    /// Images attached to the current message, as urls
+    #[serde(skip)]
+    pub attachments: Vec<String>,
+    /// Requests sent since the start
+    #[serde(skip)]
+    pub usage: Vec<Usage>,
 }
 
 pub type SharedConfig = Arc<Mutex<Config>>;
 
 impl Config {
-    pub fn init(is_interactive: bool) -> Result<Config> {
+    pub fn init(is_interactive: bool, profile: &mut StartupProfile) -> Result<Config> {
         let config_path = Config::config_file()?;
         if is_interactive && !config_path.exists() {
             create_config_file(&config_path)?;
         }
-        let content = read_to_string(&config_path)
-            .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
-        let mut config: Config = serde_yaml::from_str(&content)
-            .with_context(|| format!("Invalid config at {}", config_path.display()))?;
+        let mut config = Self::load(&config_path, profile)?;
         config.load_roles()?;
+        profile.phase("roles");
+        debug!(
+            "Loaded {} clients and {} roles from {}",
+            config.clients.len(),
+            config.roles.len(),
+            config_path.display()
+        );
         if config.conversation_first {
             config.start_conversation()?;
+            profile.phase("conversation");
         }
 
         Ok(config)
     }
 
+    /// Check the roles file, return the report and whether it found no errors
+    pub fn lint_roles(variables: BTreeMap<String, String>, json: bool) -> Result<(String, bool)> {
+        let mut config = Self::load(&Config::config_file()?, &mut StartupProfile::default())?;
+        config.variables = variables;
+        let path = Self::roles_file()?;
+        let content = if path.exists() {
+            read_to_string(&path)
+                .with_context(|| format!("Failed to load roles at {}", path.display()))?
+        } else {
+            String::new()
+        };
+        let issues = lint_roles(&mut config, &content);
+        let output = format_issues(&issues, config.roles.len(), json);
+        Ok((output, issues.iter().all(|v| v.level != Level::Error)))
+    }
+
+    /// The config file, migrated, without the roles
+    fn load(config_path: &Path, profile: &mut StartupProfile) -> Result<Config> {
+        let mut value = Self::load_config_value(config_path)?;
+        profile.phase("config file");
+        for warning in migrate(&mut value)? {
+            warn!("{warning}, run `aichat --migrate-config` to update the config");
+        }
+        profile.phase("migration check");
+        let invalid = || {
+            format!(
+                "Invalid config at {}, fix it with `aichat --edit-config`",
+                config_path.display()
+            )
+        };
+        // The largest sections are read on their own to time them apart
+        let clients = take_config_key(&mut value, "clients").with_context(invalid)?;
+        profile.phase("clients");
+        let aliases = take_config_key(&mut value, "aliases").with_context(invalid)?;
+        profile.phase("aliases");
+        let pipelines = take_config_key(&mut value, "pipelines").with_context(invalid)?;
+        profile.phase("pipelines");
+        let mut config: Config = serde_yaml::from_value(value).with_context(invalid)?;
+        config.clients = clients;
+        config.aliases = aliases;
+        config.pipelines = pipelines;
+        profile.phase("settings");
+        Ok(config)
+    }
+
+    /// Value of a key in the config file as written there, None when it's unset
+    pub fn get_config_key(key: &str) -> Result<Option<String>> {
+        check_config_key(key)?;
+        let mut value = Self::load_config_value(&Config::config_file()?)?;
+        migrate(&mut value)?;
+        let output = match value.get(key) {
+            None | Some(serde_yaml::Value::Null) => return Ok(None),
+            Some(serde_yaml::Value::String(v)) => v.clone(),
+            Some(v) => serde_yaml::to_string(v)
+                .with_context(|| "Failed to serde value")?
+                .trim_end()
+                .to_string(),
+        };
+        Ok(Some(output))
+    }
+
+    /// Write a key to the config file, `null` removes it, if the config still loads with it
+    pub fn save_config_key(key: &str, value: &str) -> Result<()> {
+        if key == "api_key" {
+            bail!("The api key lives in `clients`, please use --edit-config");
+        }
+        check_config_key(key)?;
+        let config_path = Config::config_file()?;
+        let mut content = Self::load_config_value(&config_path)?;
+        let map = content
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("Invalid config at {}", config_path.display()))?;
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(value).with_context(|| "Invalid value")?;
+        if value.is_null() {
+            map.remove(key);
+        } else {
+            map.insert(key.into(), value);
+        }
+        let mut migrated = content.clone();
+        migrate(&mut migrated)?;
+        let config = serde_yaml::from_value::<Config>(migrated)
+            .with_context(|| format!("Invalid value for `{key}`"))?;
+        for model in [
+            &config.model,
+            &config.shell_model,
+            &config.summary_model,
+            &config.embedding_model,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            config
+                .find_client(model)
+                .with_context(|| format!("Invalid value for `{key}`"))?;
+        }
+        let content = serde_yaml::to_string(&content).with_context(|| "Failed to serde config")?;
+        std::fs::write(&config_path, content)
+            .with_context(|| format!("Failed to write config to {}", config_path.display()))
+    }
+
+    /// Open the config file in the editor
+    pub fn edit_config_file() -> Result<()> {
+        let config_path = Config::config_file()?;
+        edit_file(&config_path)
+    }
+
+    /// Rewrite the config file in the current version, keeping a backup of the old one
+    pub fn migrate_config_file() -> Result<String> {
+        let config_path = Config::config_file()?;
+        let mut value = Self::load_config_value(&config_path)?;
+        let original = value.clone();
+        let warnings = migrate(&mut value)?;
+        if value == original {
+            return Ok(format!("{} is up to date", config_path.display()));
+        }
+        let backup_path = config_path.with_extension("yaml.bak");
+        std::fs::copy(&config_path, &backup_path)
+            .with_context(|| format!("Failed to backup config to {}", backup_path.display()))?;
+        let content = serde_yaml::to_string(&value).with_context(|| "Failed to serde config")?;
+        std::fs::write(&config_path, content)
+            .with_context(|| format!("Failed to write config to {}", config_path.display()))?;
+        let mut output = warnings.join("\n");
+        output.push_str(&format!(
+            "\nMigrated {} to version {CONFIG_VERSION}, the old config is saved at {}",
+            config_path.display(),
+            backup_path.display()
+        ));
+        Ok(output.trim_start().to_string())
+    }
+
+    /// Client serving the requests, picked by the role, the `model` key or the order of `clients`
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        match &self.message_model {
+            Some(model) => self.find_client(model),
+            None => self.role_client_config(self.role.as_ref()),
+        }
+    }
+
+    fn role_client_config(&self, role: Option<&Role>) -> Result<ClientConfig> {
+        let model = role.and_then(|v| v.model.as_ref()).or(self.model.as_ref());
+        match model {
+            Some(model) => self.find_client(model),
+            None if self.offline => self
+                .clients
+                .iter()
+                .find(|v| v.is_local())
+                .cloned()
+                .ok_or_else(|| {
+                    anyhow!("No local client in config, add one to `clients` to work offline")
+                }),
+            None => self
+                .clients
+                .first()
+                .cloned()
+                .ok_or_else(|| anyhow!("No client in config, please add one to `clients`")),
+        }
+    }
+
+    /// Client building a rag index, the `embedding_model` key or the current one
+    pub fn embedding_client_config(&self) -> Result<ClientConfig> {
+        match &self.embedding_model {
+            Some(model) => self.find_client(model),
+            None => self.client_config(),
+        }
+    }
+
+    /// Proxy of the client, its own `proxy` or the global one
+    pub fn client_proxy(&self, client_config: Option<&ClientConfig>) -> Option<String> {
+        client_config
+            .and_then(|v| v.as_client().proxy().map(|v| v.to_string()))
+            .or_else(|| self.proxy.clone())
+    }
+
+    /// Context size of the current model
+    pub fn context_size(&self) -> usize {
+        self.client_config()
+            .ok()
+            .and_then(|v| v.as_client().context_size())
+            .unwrap_or(CONTEXT_SIZE)
+    }
+
+    /// Find a client by `name`, `name:model` or an alias of one
+    pub fn find_client(&self, model: &str) -> Result<ClientConfig> {
+        let model = self.aliases.get(model).map_or(model, |v| v.as_str());
+        let (name, model) = match model.split_once(':') {
+            Some((name, model)) => (name, Some(model)),
+            None => (model, None),
+        };
+        let mut client = self
+            .clients
+            .iter()
+            .find(|v| v.as_client().name() == name)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<String> = self.clients.iter().map(|v| v.as_client().name()).collect();
+                anyhow!(
+                    "Unknown client '{name}', the clients are {}, add others to `clients` with `aichat --edit-config`",
+                    names.join(", ")
+                )
+            })?;
+        if self.offline && !client.is_local() {
+            bail!("Client '{name}' is not local, offline only allows `local` clients");
+        }
+        if let Some(model) = model {
+            client.as_client_mut().set_model(model);
+        }
+        Ok(client)
+    }
+
+    /// Select the client explicitly, this overrides the model of the current role
+    pub fn set_model(&mut self, model: &str) -> Result<()> {
+        self.find_client(model)?;
+        self.model = Some(model.to_string());
+        if let Some(role) = self.role.as_mut() {
+            role.model = None;
+        }
+        Ok(())
+    }
+
+    pub fn list_models(&self) -> String {
+        let current = self
+            .client_config()
+            .map(|v| v.as_client().name())
+            .unwrap_or_default();
+        self.clients
+            .iter()
+            .map(|v| {
+                let mark = if v.as_client().name() == current {
+                    "*"
+                } else {
+                    " "
+                };
+                format!("{mark} {}", v.id())
+            })
+            .chain(
+                self.aliases
+                    .iter()
+                    .map(|(alias, model)| format!("  {alias} ({model})")),
+            )
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
     pub fn find_role(&self, name: &str) -> Option<Role> {
         self.roles.iter().find(|v| v.name == name).cloned()
     }
 
+    /// Role of the first file matching a glob of `file_roles`, the longest glob if several do
+    pub fn file_role(&self, paths: &[String]) -> Option<String> {
+        paths.iter().find_map(|path| {
+            self.file_roles
+                .iter()
+                .filter(|(pattern, _)| match pattern.contains('/') {
+                    true => glob_match(pattern, path),
+                    false => Path::new(path)
+                        .file_name()
+                        .map(|v| glob_match(pattern, &v.to_string_lossy()))
+                        .unwrap_or_default(),
+                })
+                .max_by_key(|(pattern, _)| pattern.len())
+                .map(|(_, role)| role.clone())
+        })
+    }
+
+    pub fn find_pipeline(&self, name: &str) -> Option<Pipeline> {
+        self.pipelines.iter().find(|v| v.name == name).cloned()
+    }
+
     pub fn config_dir() -> Result<PathBuf> {
         let env_name = format!(
             "{}_CONFIG_DIR",
@@ -129,7 +576,7 @@ impl Config {
             return Ok(());
         }
         let timestamp = now();
-        let output = match self.role.as_ref() {
+        let entry = match self.role.as_ref() {
             None => {
                 format!("# CHAT:[{timestamp}]\n{input}\n--------\n{output}\n--------\n\n",)
             }
@@ -147,8 +594,35 @@ impl Config {
                 }
             }
         };
-        file.write_all(output.as_bytes())
-            .with_context(|| "Failed to save message")
+        file.write_all(entry.as_bytes())
+            .with_context(|| "Failed to save message")?;
+        let chat = Chat {
+            time: timestamp,
+            model: self.client_config().map(|v| v.id()).unwrap_or_default(),
+            role: self
+                .role
+                .as_ref()
+                .filter(|v| !v.is_temp())
+                .map(|v| v.name.clone()),
+            input: input.to_string(),
+            output: output.to_string(),
+        };
+        save_chat(&Self::chats_file()?, &chat)
+    }
+
+    pub fn last_reply() -> Result<Option<String>> {
+        let path = Config::messages_file()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load messages at {}", path.display()))?;
+        let reply = content
+            .trim_end()
+            .strip_suffix("--------")
+            .and_then(|v| v.rsplit_once("\n--------\n"))
+            .map(|(_, output)| output.trim_end().to_string());
+        Ok(reply)
     }
 
     pub fn config_file() -> Result<PathBuf> {
@@ -159,27 +633,122 @@ impl Config {
         Self::local_file(ROLES_FILE_NAME)
     }
 
+    pub fn keys_file() -> Result<PathBuf> {
+        Self::local_file(KEYS_FILE_NAME)
+    }
+
+    /// Keys of the api server, none means it is open to everyone who can reach it
+    pub fn api_keys() -> Result<Vec<ApiKey>> {
+        load_api_keys(&Self::keys_file()?)
+    }
+
     pub fn history_file() -> Result<PathBuf> {
         Self::local_file(HISTORY_FILE_NAME)
     }
 
+    pub fn chats_file() -> Result<PathBuf> {
+        Self::local_file(CHATS_FILE_NAME)
+    }
+
+    /// The latest saved chats, or those containing the term
+    pub fn history_info(&self, term: Option<&str>) -> Result<String> {
+        let chats = load_chats(&Self::chats_file()?)?;
+        if chats.is_empty() && !self.save {
+            bail!("Error: No saved chats, make sure `save` is enabled");
+        }
+        Ok(list_chats(&chats, term))
+    }
+
+    /// Saved chat #index, as printed and its reply
+    pub fn get_chat(&self, index: usize) -> Result<(String, String)> {
+        let chats = load_chats(&Self::chats_file()?)?;
+        let chat = index
+            .checked_sub(1)
+            .and_then(|i| chats.get(i))
+            .ok_or_else(|| anyhow!("Error: No chat #{index}, run `.history` to list chats"))?;
+        let role = chat
+            .role
+            .as_ref()
+            .map(|v| format!(", {v}"))
+            .unwrap_or_default();
+        let text = format!(
+            "# {} ({}{role})\n{}\n--------\n{}",
+            chat.time, chat.model, chat.input, chat.output
+        );
+        Ok((text, chat.output.clone()))
+    }
+
+    /// Log of the shell commands run with `-e` and `.sh`
+    pub fn commands_file() -> Result<PathBuf> {
+        Self::local_file(COMMANDS_FILE_NAME)
+    }
+
     pub fn messages_file() -> Result<PathBuf> {
         Self::local_file(MESSAGE_FILE_NAME)
     }
 
+    pub fn sessions_dir() -> Result<PathBuf> {
+        Self::local_file(SESSIONS_DIR_NAME)
+    }
+
+    pub fn rags_dir() -> Result<PathBuf> {
+        Self::local_file(RAGS_DIR_NAME)
+    }
+
+    pub fn usage_dir() -> Result<PathBuf> {
+        Self::local_file(USAGE_DIR_NAME)
+    }
+
+    /// Find a role and compose it with the roles it extends and mixes in
+    pub fn resolve_role(&self, name: &str) -> Result<Role> {
+        self.resolve_role_inner(name, &mut vec![])
+    }
+
     pub fn change_role(&mut self, name: &str) -> Result<String> {
-        match self.find_role(name) {
-            Some(role) => {
-                if let Some(conversation) = self.conversation.as_mut() {
-                    conversation.update_role(&role)?;
+        let mut role = self.resolve_role(name)?;
+        self.fill_role_variables(&mut role)?;
+        if let Some(conversation) = self.conversation.as_mut() {
+            conversation.update_role(&role)?;
+        }
+        let output = serde_yaml::to_string(&role).unwrap_or("Unable to echo role details".into());
+        self.role = Some(role);
+        Ok(output)
+    }
+
+    /// Fill the placeholders of the role from `--var`, the environment, or by asking
+    pub fn fill_role_variables(&self, role: &mut Role) -> Result<()> {
+        let mut values = BTreeMap::new();
+        for name in role.variables() {
+            let value = match self.variables.get(&name) {
+                Some(v) => v.clone(),
+                None if BUILTIN_VARIABLES.contains(&name.as_str()) => {
+                    self.builtin_variable(&name)?
                 }
-                let output =
-                    serde_yaml::to_string(&role).unwrap_or("Unable to echo role details".into());
-                self.role = Some(role);
-                Ok(output)
-            }
-            None => bail!("Error: Unknown role"),
+                None if atty::is(atty::Stream::Stdin) => {
+                    Text::new(&format!("{name}:")).prompt()?
+                }
+                None => bail!(
+                    "Role '{}' needs a value for {{{{{name}}}}}, set it with `--var {name}=<value>`",
+                    role.name
+                ),
+            };
+            values.insert(name, value);
         }
+        role.fill_variables(&values);
+        Ok(())
+    }
+
+    /// Value of one of `BUILTIN_VARIABLES`
+    fn builtin_variable(&self, name: &str) -> Result<String> {
+        let value = match name {
+            "os" => os_version(),
+            "arch" => env::consts::ARCH.into(),
+            "shell" => resolve_shell(self.shell.as_deref()).name,
+            "cwd" => env::current_dir()?.display().to_string(),
+            "date" => now()[..10].to_string(),
+            _ => bail!("Unknown builtin variable {name}"),
+        };
+        Ok(value)
     }
 
     pub fn create_temp_role(&mut self, prompt: &str) -> Result<()> {
@@ -192,24 +761,60 @@ impl Config {
     }
 
     pub fn get_temperature(&self) -> Option<f64> {
-        self.role
-            .as_ref()
-            .and_then(|v| v.temperature)
+        self.message_temperature
+            .or_else(|| self.conversation.as_ref().and_then(|v| v.temperature))
+            .or_else(|| self.role.as_ref().and_then(|v| v.temperature))
             .or(self.temperature)
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        if let Some(conversation) = self.conversation.as_ref() {
-            conversation.echo_messages(content)
-        } else if let Some(role) = self.role.as_ref() {
-            role.echo_messages(content)
-        } else {
-            content.to_string()
+    pub fn get_top_p(&self) -> Option<f64> {
+        self.conversation
+            .as_ref()
+            .and_then(|v| v.top_p)
+            .or_else(|| self.role.as_ref().and_then(|v| v.top_p))
+            .or(self.top_p)
+    }
+
+    /// Maximum number of tokens of the reply, left to the api unless set
+    pub fn get_max_reply_tokens(&self) -> Option<usize> {
+        self.conversation
+            .as_ref()
+            .and_then(|v| v.max_tokens)
+            .or_else(|| self.role.as_ref().and_then(|v| v.max_tokens))
+    }
+
+    /// Parameters differing from the config, set by the conversation or the role
+    pub fn params_info(&self) -> String {
+        let mut params = vec![];
+        let temperature = self.get_temperature();
+        if let Some(v) = temperature.filter(|_| temperature != self.temperature) {
+            params.push(format!("temperature={v}"));
+        }
+        let top_p = self.get_top_p();
+        if let Some(v) = top_p.filter(|_| top_p != self.top_p) {
+            params.push(format!("top_p={v}"));
+        }
+        if let Some(v) = self.get_max_reply_tokens() {
+            params.push(format!("max_tokens={v}"));
         }
+        params.join(" ")
     }
 
     pub fn build_messages(&self, content: &str) -> Result<Vec<Message>> {
-        let messages = if let Some(conversation) = self.conversation.as_ref() {
+        if self.offline && self.attachments.iter().any(|v| v.starts_with("http")) {
+            bail!("Image urls are not loaded offline, attach local files instead");
+        }
+        let messages = self.assemble_messages(content);
+        let tokens = num_tokens_from_messages(&messages);
+        if tokens >= self.context_size() {
+            bail!("Exceed max tokens limit")
+        }
+
+        Ok(messages)
+    }
+
+    fn assemble_messages(&self, content: &str) -> Vec<Message> {
+        let mut messages = if let Some(conversation) = self.conversation.as_ref() {
             conversation.build_emssages(content)
         } else if let Some(role) = self.role.as_ref() {
             role.build_emssages(content)
@@ -217,12 +822,326 @@ impl Config {
             let message = Message::new(content);
             vec![message]
         };
-        let tokens = num_tokens_from_messages(&messages);
-        if tokens >= MAX_TOKENS {
-            bail!("Exceed max tokens limit")
+        if let (Some(context), Some(message)) = (&self.rag_context, messages.last_mut()) {
+            message.content = format!(
+                "Answer using the context below when it is relevant.\n\n<context>\n{context}\n</context>\n\n{}",
+                message.content
+            )
+            .into();
+        }
+        if let (Some(language), Some(message)) = (&self.reply_language, messages.last_mut()) {
+            message.content = format!("{}\n\nAlways reply in {language}.", message.content).into();
         }
+        if let (true, Some(message)) = (self.protects(content), messages.last_mut()) {
+            message.content = format!("{}\n\n{PROTECT_PROMPT}", message.content).into();
+        }
+        if let Some(message) = messages.last_mut() {
+            message.content =
+                MessageContent::with_images(&message.content.to_string(), &self.attachments);
+        }
+        messages
+    }
 
-        Ok(messages)
+    /// The input with code and urls masked when the role protects them, and what was masked
+    pub fn mask_input(&self, content: &str) -> (String, Vec<String>) {
+        match &self.role {
+            Some(role) if role.protect => mask_text(content),
+            _ => (content.to_string(), vec![]),
+        }
+    }
+
+    fn protects(&self, content: &str) -> bool {
+        self.role.as_ref().is_some_and(|v| v.protect) && content.contains(&mask_marker(0))
+    }
+
+    /// What the next turn would send, section by section with token counts
+    pub fn preview(&mut self, content: &str) -> String {
+        let total = num_tokens_from_messages(&self.assemble_messages(content));
+        let (context, attachments) = (
+            self.rag_context.take(),
+            std::mem::take(&mut self.attachments),
+        );
+        let messages = self.assemble_messages(content);
+        (self.rag_context, self.attachments) = (context, attachments);
+
+        let mut sections = vec![];
+        let (system, messages): (Vec<&Message>, Vec<&Message>) = messages
+            .iter()
+            .partition(|v| matches!(v.role, MessageRole::System));
+        if !system.is_empty() {
+            let tokens = system.iter().map(|v| num_tokens_from_message(v)).sum();
+            let text = system.iter().map(|v| v.content.to_string()).collect();
+            sections.push(("system".to_string(), tokens, text));
+        }
+        if let Some((input, history)) = messages.split_last() {
+            if !history.is_empty() {
+                let tokens = history.iter().map(|v| num_tokens_from_message(v)).sum();
+                let text = history
+                    .iter()
+                    .map(|v| format!("{}: {}", v.role.name(), v.content))
+                    .collect();
+                sections.push((format!("history, {} messages", history.len()), tokens, text));
+            }
+            if let Some(context) = &self.rag_context {
+                sections.push((
+                    "rag context".into(),
+                    count_tokens(context),
+                    vec![context.clone()],
+                ));
+            }
+            let text = vec![input.content.to_string()];
+            sections.push(("input".into(), num_tokens_from_message(input), text));
+        }
+        if !self.attachments.is_empty() {
+            let text = self.attachments.iter().map(|v| describe_image(v)).collect();
+            let tokens = self.attachments.len() * IMAGE_TOKENS;
+            sections.push(("attachments".into(), tokens, text));
+        }
+
+        let mut output = String::new();
+        for (name, tokens, text) in sections {
+            output.push_str(&format!("# {name} ({tokens} tokens)\n"));
+            output.push_str(&format!("{}\n\n", text.join("\n\n").trim_end()));
+        }
+        // Retrieving the rag context already sent requests, the input itself was not sent
+        let sent = match (&self.rag_context, &self.conversation) {
+            (None, _) => "nothing was sent",
+            (Some(_), Some(conversation)) if !conversation.messages.is_empty() => {
+                "only the query rewrite and the embedding of the rag retrieval were sent"
+            }
+            (Some(_), _) => "only the embedding of the rag retrieval was sent",
+        };
+        output.push_str(&format!(
+            "{total} tokens of {}, {sent}",
+            self.context_size()
+        ));
+        output
+    }
+
+    /// Whether the model is a role, a client, `name:model` or an alias served by `--serve`
+    pub fn has_model(&self, model: &str) -> bool {
+        self.find_role(model).is_some() || self.find_client(model).is_ok()
+    }
+
+    /// Client and body serving a request to the api server. The requested `model` may name
+    /// a role or a client, without one the current role and client serve it
+    pub fn build_server_body(&self, request: &Value) -> Result<(ClientConfig, Value)> {
+        let mut messages: Vec<Message> = serde_json::from_value(request["messages"].clone())
+            .with_context(|| "Invalid messages")?;
+        let model = request["model"].as_str().unwrap_or_default();
+        let (role, client_config) = match self.find_role(model) {
+            Some(_) => {
+                let role = self.resolve_role(model)?;
+                let client_config = self.role_client_config(Some(&role))?;
+                (Some(role), client_config)
+            }
+            None if model.is_empty() => (self.role.clone(), self.client_config()?),
+            None => (self.role.clone(), self.find_client(model)?),
+        };
+        if let Some(role) = role.as_ref() {
+            match messages.last_mut() {
+                Some(Message {
+                    role: MessageRole::User,
+                    content: MessageContent::Text(text),
+                }) if role.embeded() => *text = merge_prompt_content(&role.prompt, text),
+                _ => messages.insert(
+                    0,
+                    Message {
+                        role: MessageRole::System,
+                        content: role.prompt.as_str().into(),
+                    },
+                ),
+            }
+        }
+        let mut body = json!({
+            "model": client_config.as_client().model(),
+            "messages": messages,
+        });
+        let temperature = request["temperature"]
+            .as_f64()
+            .or_else(|| role.as_ref().and_then(|v| v.temperature))
+            .or(self.temperature);
+        if let Some(v) = temperature {
+            body["temperature"] = v.into();
+        }
+        let top_p = request["top_p"]
+            .as_f64()
+            .or_else(|| role.as_ref().and_then(|v| v.top_p))
+            .or(self.top_p);
+        if let Some(v) = top_p {
+            body["top_p"] = v.into();
+        }
+        let max_tokens = request["max_tokens"]
+            .as_u64()
+            .or_else(|| role.as_ref().and_then(|v| v.max_tokens).map(|v| v as u64));
+        if let Some(v) = max_tokens {
+            body["max_tokens"] = v.into();
+        }
+        if request["stream"].as_bool() == Some(true) {
+            body["stream"] = true.into();
+        }
+        Ok((client_config, body))
+    }
+
+    /// Offer to wrap raw code in a fenced block tagged with the detected language
+    pub fn smart_paste(&self, input: String) -> Result<String> {
+        if !self.smart_paste {
+            return Ok(input);
+        }
+        let lang = match detect_code_language(&input) {
+            Some(v) => v,
+            None => return Ok(input),
+        };
+        let ans = Confirm::new(&format!("Looks like {lang} code, wrap it in a code block?"))
+            .with_default(true)
+            .prompt()?;
+        if ans {
+            Ok(format!("```{lang}\n{}\n```", input.trim_end()))
+        } else {
+            Ok(input)
+        }
+    }
+
+    pub fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            highlight: self.highlight,
+            code_line_numbers: self.code_line_numbers,
+            max_speed: self.max_speed,
+            step: self.step && self.conversation.is_some(),
+        }
+    }
+
+    pub fn count_prompt_tokens(&self, content: &str) -> Result<usize> {
+        Ok(num_tokens_from_messages(&self.build_messages(content)?))
+    }
+
+    /// Estimated cost in dollars of the tokens with the model, given as name:model
+    pub fn estimate_cost(
+        &self,
+        model: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    ) -> f64 {
+        let model_name = model.split_once(':').map(|(_, v)| v).unwrap_or(model);
+        let (prompt, completion) = self
+            .pricing
+            .iter()
+            .find(|v| v.model == model)
+            .or_else(|| self.pricing.iter().find(|v| v.model == model_name))
+            .map(|v| (v.prompt, v.completion))
+            .unwrap_or((PRICE_PER_1K_TOKENS, PRICE_PER_1K_TOKENS));
+        (prompt_tokens as f64 * prompt + completion_tokens as f64 * completion) / 1000.0
+    }
+
+    /// Tokens of the messages of a request in the chat completions format
+    pub fn count_body_tokens(body: &Value) -> usize {
+        match serde_json::from_value::<Vec<Message>>(body["messages"].clone()) {
+            Ok(messages) => num_tokens_from_messages(&messages),
+            Err(_) => count_tokens(&body["messages"].to_string()),
+        }
+    }
+
+    /// Log the tokens of a request in the chat completions format and of its reply,
+    /// with the time since it was sent
+    pub fn record_usage(
+        &mut self,
+        model: &str,
+        key: Option<&str>,
+        body: &Value,
+        reply: &str,
+        started: Instant,
+    ) -> Result<()> {
+        let prompt_tokens = Self::count_body_tokens(body);
+        let completion_tokens = count_tokens(reply);
+        let usage = Usage {
+            time: now(),
+            model: model.to_string(),
+            key: key.map(|v| v.to_string()),
+            role: self.role.as_ref().map(|v| v.name.clone()),
+            prompt_tokens,
+            completion_tokens,
+            cost: self.estimate_cost(model, prompt_tokens, completion_tokens),
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+        };
+        info!(
+            "{model}: {prompt_tokens} prompt tokens, {completion_tokens} completion tokens, about ${:.4}",
+            usage.cost
+        );
+        save_usage(&Self::usage_dir()?, &usage)?;
+        self.usage.push(usage);
+        Ok(())
+    }
+
+    /// Usage since the start and of the month, the current one by default
+    pub fn usage_info(&self, month: Option<&str>) -> Result<String> {
+        let month = month
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| now()[..7].to_string());
+        let usages = load_usage(&Self::usage_dir()?, &month)?;
+        let mut sections = vec![];
+        if !self.usage.is_empty() {
+            sections.push(format!("# session\n{}", summarize_usage(&self.usage)));
+        }
+        if usages.is_empty() {
+            sections.push(format!("# {month}\nNo requests"));
+        } else {
+            sections.push(format!("# {month}\n{}", summarize_usage(&usages)));
+        }
+        Ok(sections.join("\n\n"))
+    }
+
+    /// Markdown report of the usage log over a range, the current month by default
+    pub fn usage_report(range: Option<&str>) -> Result<String> {
+        let (from, to) = parse_report_range(range)?;
+        let dir = Self::usage_dir()?;
+        let mut usages = vec![];
+        for month in range_months(&from, &to) {
+            usages.extend(load_usage(&dir, &month)?);
+        }
+        Ok(usage_report(&usages, &from, &to))
+    }
+
+    /// Tokens used this month with the key of the api server
+    pub fn key_tokens(&self, key: &str) -> Result<usize> {
+        let usages = load_usage(&Self::usage_dir()?, &now()[..7])?;
+        Ok(usages
+            .iter()
+            .filter(|v| v.key.as_deref() == Some(key))
+            .map(|v| v.prompt_tokens + v.completion_tokens)
+            .sum())
+    }
+
+    /// Load the attached files, guarded by `file_guard`
+    pub fn load_attachments(&self, paths: &[String]) -> Result<Attachments> {
+        let max_size = self.max_file_size.unwrap_or(MAX_FILE_SIZE);
+        load_attachments(paths, self.file_guard, max_size)
+    }
+
+    /// Estimated cost in dollars of the requests sent since the start
+    pub fn usage_cost(&self) -> f64 {
+        self.usage.iter().map(|v| v.cost).sum()
+    }
+
+    /// Ask for confirmation if the estimated cost of the request exceeds `confirm_cost_above`
+    pub fn confirm_cost(&self, content: &str) -> Result<()> {
+        let threshold = match self.confirm_cost_above {
+            Some(v) if !self.dry_run => v,
+            _ => return Ok(()),
+        };
+        let tokens = self.count_prompt_tokens(content)?;
+        let cost = self.estimate_cost(&self.client_config()?.id(), tokens, 0);
+        if cost <= threshold {
+            return Ok(());
+        }
+        let ans = Confirm::new(&format!(
+            "The request has {tokens} tokens and costs about ${cost:.4}, send it?"
+        ))
+        .with_default(false)
+        .prompt()?;
+        if !ans {
+            bail!("Request cancelled");
+        }
+        Ok(())
     }
 
     pub fn info(&self) -> Result<String> {
@@ -230,25 +1149,84 @@ impl Config {
             let state = if path.exists() { "" } else { " ⚠️" };
             format!("{}{state}", path.display())
         };
+        let task_model = |model: &Option<String>| model.clone().unwrap_or("-".into());
         let proxy = self
             .proxy
             .as_ref()
             .map(|v| v.to_string())
             .unwrap_or("-".into());
         let temperature = self
-            .temperature
+            .get_temperature()
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let top_p = self
+            .get_top_p()
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let max_tokens = self
+            .get_max_reply_tokens()
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let (model, api_key) = match self.client_config() {
+            Ok(v) => (v.id(), v.as_client().api_key().to_string()),
+            Err(_) => ("-".into(), "-".into()),
+        };
+        let reply_language = self
+            .reply_language
+            .as_ref()
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let max_speed = self.max_speed.map(|v| v.to_string()).unwrap_or("-".into());
+        let rag_max_tokens = self
+            .rag_max_tokens
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let confirm_cost_above = self
+            .confirm_cost_above
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let compact_threshold = self
+            .compact_threshold
+            .map(|v| v.to_string())
+            .unwrap_or("-".into());
+        let max_retries = self
+            .max_retries
             .map(|v| v.to_string())
             .unwrap_or("-".into());
         let items = vec![
             ("config_file", file_info(&Config::config_file()?)),
             ("roles_file", file_info(&Config::roles_file()?)),
             ("messages_file", file_info(&Config::messages_file()?)),
-            ("api_key", self.api_key.clone()),
+            ("model", model),
+            ("shell_model", task_model(&self.shell_model)),
+            ("summary_model", task_model(&self.summary_model)),
+            ("embedding_model", task_model(&self.embedding_model)),
+            ("rag", self.rag_names().unwrap_or("-".into())),
+            ("api_key", api_key),
             ("temperature", temperature),
+            ("top_p", top_p),
+            ("max_tokens", max_tokens),
             ("save", self.save.to_string()),
             ("highlight", self.highlight.to_string()),
+            ("edit_mode", self.edit_mode.stringify().into()),
             ("proxy", proxy),
+            ("reply_language", reply_language),
+            ("shell", self.shell.clone().unwrap_or("-".into())),
+            ("step", self.step.to_string()),
+            ("smart_paste", self.smart_paste.to_string()),
+            ("rag_grounding_check", self.rag_grounding_check.to_string()),
+            ("rag_max_tokens", rag_max_tokens),
+            ("code_line_numbers", self.code_line_numbers.to_string()),
+            ("max_speed", max_speed),
+            ("confirm_cost_above", confirm_cost_above),
+            ("terminal_title", self.terminal_title.to_string()),
             ("conversation_first", self.conversation_first.to_string()),
+            ("compact", self.compact.stringify().into()),
+            ("compact_threshold", compact_threshold),
+            ("banner", self.banner.to_string()),
+            ("prewarm", self.prewarm.to_string()),
+            ("max_retries", max_retries),
+            ("raw_stdin", self.raw_stdin.to_string()),
             ("dry_run", self.dry_run.to_string()),
         ];
         let mut output = String::new();
@@ -258,12 +1236,53 @@ impl Config {
         Ok(output)
     }
 
+    pub fn tokens_info(&self) -> Result<String> {
+        match self.conversation.as_ref() {
+            Some(conversation) => Ok(conversation.tokens_info(self.context_size())),
+            None => bail!("Error: Not in a conversation"),
+        }
+    }
+
+    pub fn get_message(&self, index: usize) -> Result<String> {
+        match self.conversation.as_ref() {
+            Some(conversation) => match index
+                .checked_sub(1)
+                .and_then(|i| conversation.messages.get(i))
+            {
+                Some(message) => Ok(message.content.to_string()),
+                None => bail!("Error: No message #{index}, run `.tokens` to list messages"),
+            },
+            None => bail!("Error: Not in a conversation"),
+        }
+    }
+
     pub fn repl_completions(&self) -> Vec<String> {
         let mut completion: Vec<String> = self
             .roles
             .iter()
             .map(|v| format!(".role {}", v.name))
             .collect();
+        completion.extend(
+            self.clients
+                .iter()
+                .map(|v| format!(".model {}", v.as_client().name())),
+        );
+        completion.extend(self.aliases.keys().map(|v| format!(".model {v}")));
+        if let Ok(dir) = Self::rags_dir() {
+            for name in list_rags(&dir) {
+                completion.push(format!(".rag use {name}"));
+                completion.push(format!(".rag attach {name}"));
+            }
+        }
+        if let Ok(dir) = Self::sessions_dir() {
+            completion.extend(
+                list_sessions(&dir)
+                    .into_iter()
+                    .map(|v| format!(".session load {v}")),
+            );
+            completion.push(".session last".into());
+            completion.push(".rag session".into());
+        }
 
         completion.extend(SET_COMPLETIONS.map(|v| v.to_string()));
         completion
@@ -282,15 +1301,44 @@ impl Config {
                 if unset {
                     bail!("Error: Not allowed");
                 } else {
-                    self.api_key = value.to_string();
+                    let name = self.client_config()?.as_client().name();
+                    if let Some(client) = self
+                        .clients
+                        .iter_mut()
+                        .find(|v| v.as_client().name() == name)
+                    {
+                        client.as_client_mut().set_api_key(value);
+                    }
                 }
             }
             "temperature" => {
-                if unset {
-                    self.temperature = None;
-                } else {
-                    let value = value.parse().with_context(|| "Invalid value")?;
-                    self.temperature = Some(value);
+                let value = match unset {
+                    true => None,
+                    false => Some(value.parse().with_context(|| "Invalid value")?),
+                };
+                match self.conversation.as_mut() {
+                    Some(conversation) => conversation.temperature = value,
+                    None => self.temperature = value,
+                }
+            }
+            "top_p" => {
+                let value = match unset {
+                    true => None,
+                    false => Some(value.parse().with_context(|| "Invalid value")?),
+                };
+                match self.conversation.as_mut() {
+                    Some(conversation) => conversation.top_p = value,
+                    None => self.top_p = value,
+                }
+            }
+            "max_tokens" => {
+                let value = match unset {
+                    true => None,
+                    false => Some(value.parse().with_context(|| "Invalid value")?),
+                };
+                match self.conversation.as_mut() {
+                    Some(conversation) => conversation.max_tokens = value,
+                    None => bail!("Error: max_tokens is set per conversation or role"),
                 }
             }
             "save" => {
@@ -312,14 +1360,232 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.dry_run = value;
             }
+            "reply_language" => {
+                if unset {
+                    self.reply_language = None;
+                } else {
+                    self.reply_language = Some(value.to_string());
+                }
+            }
+            "shell" => {
+                if unset {
+                    self.shell = None;
+                } else {
+                    self.shell = Some(value.to_string());
+                }
+            }
+            "step" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.step = value;
+            }
+            "smart_paste" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.smart_paste = value;
+            }
+            "rag_grounding_check" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.rag_grounding_check = value;
+            }
+            "rag_max_tokens" => {
+                if unset {
+                    self.rag_max_tokens = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.rag_max_tokens = Some(value);
+                }
+            }
+            "code_line_numbers" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.code_line_numbers = value;
+            }
+            "max_speed" => {
+                if unset {
+                    self.max_speed = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.max_speed = Some(value);
+                }
+            }
+            "compact" => {
+                self.compact = match value {
+                    "summarize" => CompactMode::Summarize,
+                    "truncate" => CompactMode::Truncate,
+                    _ => bail!("Error: Invalid value, expected summarize or truncate"),
+                };
+            }
+            "compact_threshold" => {
+                if unset {
+                    self.compact_threshold = None;
+                } else {
+                    let value = value
+                        .parse()
+                        .ok()
+                        .filter(|v| (0.0..=1.0).contains(v))
+                        .ok_or_else(|| anyhow!("Error: Invalid value, must be 0 to 1"))?;
+                    self.compact_threshold = Some(value);
+                }
+            }
+            "max_retries" => {
+                if unset {
+                    self.max_retries = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.max_retries = Some(value);
+                }
+            }
+            "confirm_cost_above" => {
+                if unset {
+                    self.confirm_cost_above = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.confirm_cost_above = Some(value);
+                }
+            }
+            "terminal_title" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.terminal_title = value;
+            }
             _ => bail!("Error: Unknown key `{key}`"),
         }
         Ok(())
     }
 
+    pub fn install_roles(&mut self, content: &str) -> Result<()> {
+        let roles: Vec<Role> = match serde_yaml::from_str(content) {
+            Ok(roles) => roles,
+            Err(_) => {
+                let role: Role =
+                    serde_yaml::from_str(content).with_context(|| "Invalid role definition")?;
+                vec![role]
+            }
+        };
+        if roles.is_empty() {
+            bail!("No role found");
+        }
+        for role in roles.iter() {
+            if role.name.trim().is_empty() || role.prompt.trim().is_empty() {
+                bail!("Invalid role definition, `name` and `prompt` are required");
+            }
+            if is_reserved_role_name(&role.name) {
+                bail!(
+                    "Invalid role name '{}', `.role` would read it as a command",
+                    role.name
+                );
+            }
+        }
+        println!("{}", serde_yaml::to_string(&roles)?.trim_end());
+        let existing: Vec<&str> = roles
+            .iter()
+            .filter(|v| self.find_role(&v.name).is_some())
+            .map(|v| v.name.as_str())
+            .collect();
+        if !existing.is_empty() {
+            println!(
+                "\n⚠️ Will overwrite existing roles: {}",
+                existing.join(", ")
+            );
+        }
+        let ans = Confirm::new("Install the roles above?")
+            .with_default(true)
+            .prompt()?;
+        if !ans {
+            return Ok(());
+        }
+        for role in roles {
+            match self.roles.iter_mut().find(|v| v.name == role.name) {
+                Some(v) => *v = role,
+                None => self.roles.push(role),
+            }
+        }
+        self.save_roles()
+    }
+
+    /// Definition of a new role, to be completed in the editor
+    pub fn new_role_template(&self, name: &str) -> Result<String> {
+        if self.find_role(name).is_some() {
+            bail!("Error: Role '{name}' already exists, use `.role edit {name}`");
+        }
+        Ok(format!(
+            "name: {name}\n\
+# __INPUT__ in the prompt marks where the input goes, without it the prompt is a system prompt\n\
+prompt: |\n  \n\
+temperature: null\n"
+        ))
+    }
+
+    /// Definition of a role as written in the roles file
+    pub fn role_definition(&self, name: &str) -> Result<String> {
+        match self.find_role(name) {
+            Some(role) => serde_yaml::to_string(&role).with_context(|| "Failed to serde role"),
+            None => bail!("Error: Unknown role '{name}'"),
+        }
+    }
+
+    /// Add a role from its definition, or replace the role named `old_name`
+    pub fn save_role(&mut self, old_name: Option<&str>, content: &str) -> Result<String> {
+        let role: Role =
+            serde_yaml::from_str(content).with_context(|| "Invalid role definition")?;
+        if role.name.trim().is_empty() || role.prompt.trim().is_empty() {
+            bail!("Invalid role definition, `name` and `prompt` are required");
+        }
+        if is_reserved_role_name(&role.name) {
+            bail!(
+                "Invalid role name '{}', `.role` would read it as a command",
+                role.name
+            );
+        }
+        self.load_roles()?;
+        if old_name != Some(role.name.as_str()) {
+            if self.find_role(&role.name).is_some() {
+                bail!("Role '{}' already exists", role.name);
+            }
+            if let Some(old_name) = old_name {
+                self.check_role_unused(old_name)?;
+            }
+        }
+        let roles = self.roles.clone();
+        match old_name.and_then(|name| self.roles.iter().position(|v| v.name == name)) {
+            Some(index) => self.roles[index] = role.clone(),
+            None => self.roles.push(role.clone()),
+        }
+        if let Err(err) = self
+            .resolve_role(&role.name)
+            .and_then(|_| self.save_roles())
+        {
+            self.roles = roles;
+            return Err(err);
+        }
+        Ok(role.name)
+    }
+
+    pub fn delete_role(&mut self, name: &str) -> Result<()> {
+        self.load_roles()?;
+        if self.find_role(name).is_none() {
+            bail!("Error: Unknown role '{name}'");
+        }
+        self.check_role_unused(name)?;
+        let roles = self.roles.clone();
+        self.roles.retain(|v| v.name != name);
+        if let Err(err) = self.save_roles() {
+            self.roles = roles;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// The role composed with the roles it extends and mixes in, the current role by default
+    pub fn role_info(&self, name: Option<&str>) -> Result<String> {
+        let role = match (name, self.role.as_ref()) {
+            (Some(name), _) => self.resolve_role(name)?,
+            (None, Some(role)) => role.clone(),
+            (None, None) => bail!("Error: No role selected"),
+        };
+        serde_yaml::to_string(&role).with_context(|| "Failed to serde role")
+    }
+
     pub fn start_conversation(&mut self) -> Result<()> {
         if let Some(conversation) = self.conversation.as_ref() {
-            if conversation.reamind_tokens() > 0 {
+            if conversation.reamind_tokens(self.context_size()) > 0 {
                 let ans = Confirm::new("Already in a conversation, start a new one?")
                     .with_default(true)
                     .prompt()?;
@@ -336,6 +1602,27 @@ impl Config {
         self.conversation = None;
     }
 
+    /// Whether the conversation with the input would fill the context past the threshold
+    pub fn needs_compact(&self, input: &str) -> bool {
+        let Some(conversation) = self.conversation.as_ref() else {
+            return false;
+        };
+        let threshold = self.compact_threshold.unwrap_or(COMPACT_THRESHOLD);
+        let tokens = num_tokens_from_messages(&self.assemble_messages(input));
+        tokens as f64 >= self.context_size() as f64 * threshold
+            && conversation.compactable_text().is_some()
+    }
+
+    /// Compact the conversation, return its tokens before and after
+    pub fn compact_conversation(&mut self, summary: Option<&str>) -> Result<(usize, usize)> {
+        let Some(conversation) = self.conversation.as_mut() else {
+            bail!("Error: Not in a conversation");
+        };
+        let before = conversation.tokens;
+        conversation.compact(summary)?;
+        Ok((before, conversation.tokens))
+    }
+
     pub fn save_conversation(&mut self, input: &str, output: &str) -> Result<()> {
         if let Some(conversation) = self.conversation.as_mut() {
             conversation.add_message(input, output)?;
@@ -343,6 +1630,173 @@ impl Config {
         Ok(())
     }
 
+    pub fn save_session(&self, name: &str) -> Result<String> {
+        let conversation = match self.conversation.as_ref() {
+            Some(conversation) => conversation.clone(),
+            None => bail!("Error: Not in a conversation"),
+        };
+        let session = Session {
+            model: self.client_config().ok().map(|v| v.id()),
+            conversation,
+            rags: self
+                .rags
+                .iter()
+                .map(|v| (v.rag.name.clone(), v.weight))
+                .collect(),
+        };
+        let path = session.save(&Self::sessions_dir()?, name)?;
+        Ok(format!("Saved session to {}", path.display()))
+    }
+
+    /// Save the conversation as a new session to come back to, the conversation goes on as it is
+    pub fn fork_session(&self, name: &str) -> Result<String> {
+        if self.conversation.is_none() {
+            bail!("Error: Not in a conversation");
+        }
+        if Session::exists(&Self::sessions_dir()?, name)? {
+            bail!("Error: Session '{name}' already exists");
+        }
+        self.save_session(name)?;
+        Ok(format!(
+            "Forked the conversation to session '{name}', `.session load {name}` returns to this point"
+        ))
+    }
+
+    /// Drop the last exchange of the conversation
+    pub fn undo_conversation(&mut self) -> Result<()> {
+        match self.conversation.as_mut() {
+            Some(conversation) => conversation.undo(),
+            None => bail!("Error: Not in a conversation"),
+        }
+    }
+
+    /// Resume a saved conversation together with its role and model
+    pub fn load_session(&mut self, name: &str) -> Result<()> {
+        let session = Session::load(&Self::sessions_dir()?, name)?;
+        if let Some(conversation) = self.conversation.as_ref() {
+            if !conversation.messages.is_empty() {
+                let ans = Confirm::new("Already in a conversation, replace it?")
+                    .with_default(true)
+                    .prompt()?;
+                if !ans {
+                    return Ok(());
+                }
+            }
+        }
+        self.role = session.conversation.role.clone();
+        if let Some(model) = session.model {
+            if self.find_client(&model).is_ok() {
+                self.set_model(&model)?;
+            }
+        }
+        self.conversation = Some(session.conversation);
+        if !session.rags.is_empty() {
+            self.rags.clear();
+            for (rag, weight) in session.rags {
+                if let Err(err) = self.attach_rag(&rag, weight) {
+                    warn!("{err:#}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list_sessions(&self) -> Result<String> {
+        let names = list_sessions(&Self::sessions_dir()?);
+        if names.is_empty() {
+            return Ok("No saved sessions".into());
+        }
+        Ok(names.join("\n"))
+    }
+
+    /// Name of the session saved last, for `.session last`
+    pub fn last_session() -> Result<String> {
+        last_session(&Self::sessions_dir()?).ok_or_else(|| anyhow!("Error: No saved sessions"))
+    }
+
+    /// Model, role and last session, shown upon repl
+    pub fn repl_banner(&self) -> Option<String> {
+        if !self.banner {
+            return None;
+        }
+        let mut output = match self.client_config() {
+            Ok(v) => format!("Model {}", v.id()),
+            Err(_) => "No model".into(),
+        };
+        if let Some(role) = self.role.as_ref() {
+            output.push_str(&format!(", role {}", role.name));
+        }
+        if let Ok(name) = Self::last_session() {
+            output.push_str(&format!(
+                "\nLast session '{name}', press Ctrl+G or type `.session last` to resume"
+            ));
+        }
+        Some(output)
+    }
+
+    /// Use the rag alone, in place of those in use
+    pub fn use_rag(&mut self, name: &str) -> Result<()> {
+        let rag = Rag::load(&Self::rags_dir()?, name)?;
+        self.find_client(&rag.client)?;
+        self.rags = vec![WeightedRag { rag, weight: 1.0 }];
+        Ok(())
+    }
+
+    /// Use the rag along with those in use, or change its weight
+    pub fn attach_rag(&mut self, name: &str, weight: f32) -> Result<()> {
+        if weight <= 0.0 {
+            bail!("Error: The weight of a rag must be above 0");
+        }
+        if let Some(v) = self.rags.iter_mut().find(|v| v.rag.name == name) {
+            v.weight = weight;
+            return Ok(());
+        }
+        let rag = Rag::load(&Self::rags_dir()?, name)?;
+        self.find_client(&rag.client)?;
+        self.rags.push(WeightedRag { rag, weight });
+        Ok(())
+    }
+
+    pub fn detach_rag(&mut self, name: &str) -> Result<()> {
+        let count = self.rags.len();
+        self.rags.retain(|v| v.rag.name != name);
+        if self.rags.len() == count {
+            bail!("Error: Rag '{name}' is not in use");
+        }
+        Ok(())
+    }
+
+    /// Put the rebuilt rag in place of the one in use with the same name
+    pub fn replace_rag(&mut self, rag: Rag) {
+        if let Some(v) = self.rags.iter_mut().find(|v| v.rag.name == rag.name) {
+            v.rag = rag;
+        }
+    }
+
+    /// Names of the rags in use, each followed by its weight unless it is 1
+    fn rag_names(&self) -> Option<String> {
+        if self.rags.is_empty() {
+            return None;
+        }
+        let names: Vec<String> = self
+            .rags
+            .iter()
+            .map(|v| match v.weight == 1.0 {
+                true => v.rag.name.clone(),
+                false => format!("{} ({})", v.rag.name, v.weight),
+            })
+            .collect();
+        Some(names.join(", "))
+    }
+
+    pub fn list_rags(&self) -> Result<String> {
+        let names = list_rags(&Self::rags_dir()?);
+        if names.is_empty() {
+            return Ok("No rags, build one with .rag build <name> <path>".into());
+        }
+        Ok(names.join("\n"))
+    }
+
     fn open_message_file(&self) -> Result<File> {
         let path = Config::messages_file()?;
         OpenOptions::new()
@@ -352,20 +1806,130 @@ impl Config {
             .with_context(|| format!("Failed to create/append {}", path.display()))
     }
 
+    fn resolve_role_inner(&self, name: &str, parents: &mut Vec<String>) -> Result<Role> {
+        if parents.iter().any(|v| v == name) {
+            bail!("Role '{name}' inherits from itself");
+        }
+        let mut role = self
+            .find_role(name)
+            .ok_or_else(|| anyhow!("Unknown role '{name}'"))?;
+        parents.push(name.to_string());
+        let mut prompts = vec![];
+        if let Some(base) = role.extends.take() {
+            let base = self.resolve_role_inner(&base, parents)?;
+            prompts.push(base.prompt);
+            role.temperature = role.temperature.or(base.temperature);
+            role.top_p = role.top_p.or(base.top_p);
+            role.max_tokens = role.max_tokens.or(base.max_tokens);
+            role.model = role.model.or(base.model);
+            role.protect = role.protect || base.protect;
+            role.prepend = role.prepend.or(base.prepend);
+            role.append = role.append.or(base.append);
+        }
+        prompts.push(role.prompt.clone());
+        for mixin in std::mem::take(&mut role.mixins) {
+            let mixin = self.resolve_role_inner(&mixin, parents)?;
+            prompts.push(mixin.prompt);
+        }
+        parents.pop();
+        role.prompt = prompts
+            .into_iter()
+            .filter(|v| !v.trim().is_empty())
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Ok(role)
+    }
+
+    /// Write through a temporary file, so an interrupted save leaves the roles file intact
+    fn save_roles(&self) -> Result<()> {
+        let path = Self::roles_file()?;
+        let content =
+            serde_yaml::to_string(&self.roles).with_context(|| "Failed to serde roles")?;
+        let temp_path = path.with_extension("yaml.tmp");
+        std::fs::write(&temp_path, content)
+            .and_then(|_| std::fs::rename(&temp_path, &path))
+            .with_context(|| format!("Failed to write roles to {}", path.display()))
+    }
+
+    /// Refuse to remove or rename a role that other roles extend or mix in
+    fn check_role_unused(&self, name: &str) -> Result<()> {
+        let users: Vec<&str> = self
+            .roles
+            .iter()
+            .filter(|v| v.extends.as_deref() == Some(name) || v.mixins.iter().any(|v| v == name))
+            .map(|v| v.name.as_str())
+            .collect();
+        if !users.is_empty() {
+            bail!("Error: Role '{name}' is used by {}", users.join(", "));
+        }
+        Ok(())
+    }
+
+    fn load_config_value(config_path: &Path) -> Result<serde_yaml::Value> {
+        let content = read_to_string(config_path)
+            .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid config at {}", config_path.display()))
+    }
+
     fn load_roles(&mut self) -> Result<()> {
         let path = Self::roles_file()?;
         if !path.exists() {
             return Ok(());
         }
-        let content = read_to_string(&path)
-            .with_context(|| format!("Failed to load roles at {}", path.display()))?;
-        let roles: Vec<Role> =
-            serde_yaml::from_str(&content).with_context(|| "Invalid roles config")?;
+        let content = read_to_string(&path).with_context(|| {
+            format!(
+                "Failed to load roles at {}, check that it is a readable file",
+                path.display()
+            )
+        })?;
+        let roles: Vec<Role> = serde_yaml::from_str(&content).with_context(|| {
+            format!(
+                "Invalid roles at {}, it must be a list of roles with a `name` and a `prompt`",
+                path.display()
+            )
+        })?;
         self.roles = roles;
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactMode {
+    /// Replace the older messages by a summary the model writes
+    #[default]
+    Summarize,
+    /// Drop the older messages
+    Truncate,
+}
+
+impl CompactMode {
+    pub fn stringify(&self) -> &str {
+        match self {
+            CompactMode::Summarize => "summarize",
+            CompactMode::Truncate => "truncate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+impl EditMode {
+    pub fn stringify(&self) -> &str {
+        match self {
+            EditMode::Emacs => "emacs",
+            EditMode::Vi => "vi",
+        }
+    }
+}
+
 fn create_config_file(config_path: &Path) -> Result<()> {
     let confirm_map_err = |_| anyhow!("Not finish questionnaire, try again later.");
     let text_map_err = |_| anyhow!("An error happened when asking for your key, try again later.");
@@ -379,7 +1943,8 @@ fn create_config_file(config_path: &Path) -> Result<()> {
     let api_key = Text::new("Openai API Key:")
         .prompt()
         .map_err(text_map_err)?;
-    let mut raw_config = format!("api_key: {api_key}\n");
+    let mut raw_config =
+        format!("version: {CONFIG_VERSION}\nclients:\n  - type: openai\n    api_key: {api_key}\n");
 
     let ans = Confirm::new("Use proxy?")
         .with_default(false)
@@ -405,3 +1970,61 @@ fn create_config_file(config_path: &Path) -> Result<()> {
 fn highlight_value() -> bool {
     true
 }
+
+fn banner_value() -> bool {
+    true
+}
+
+/// Fail unless the key is one `Config` reads from the config file
+fn check_config_key(key: &str) -> Result<()> {
+    if !config_keys().contains(&key) {
+        bail!("Unknown key `{key}`");
+    }
+    Ok(())
+}
+
+/// Keys of the config file, as the fields `Config` asks its deserializer for
+/// Remove a key from the config and read it, the default when it's unset
+fn take_config_key<T: DeserializeOwned + Default>(
+    value: &mut serde_yaml::Value,
+    key: &str,
+) -> Result<T> {
+    match value.as_mapping_mut().and_then(|v| v.remove(key)) {
+        Some(v) => serde_yaml::from_value(v).with_context(|| format!("Invalid `{key}`")),
+        None => Ok(T::default()),
+    }
+}
+
+fn config_keys() -> &'static [&'static str] {
+    use serde::de::{self, Visitor};
+
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields collected"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Config::deserialize(FieldNames(&mut fields));
+    fields
+}
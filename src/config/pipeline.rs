@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Pipeline {
+    /// Pipeline name
+    pub name: String,
+    /// Role to use
+    pub role: Option<String>,
+    /// Client to use as `name`, `name:model` or an alias
+    pub model: Option<String>,
+    /// Text prepended to the input
+    pub prepend: Option<String>,
+    /// Text appended to the input
+    pub append: Option<String>,
+    /// Whether to highlight the output
+    pub highlight: Option<bool>,
+    /// Whether to stream the output
+    pub stream: Option<bool>,
+    /// Shell command which receives the reply from stdin
+    pub hook: Option<String>,
+}
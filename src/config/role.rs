@@ -1,9 +1,30 @@
 use super::message::{Message, MessageRole};
 
+use crate::utils::wrap_input;
+
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 const TEMP_NAME: &str = "Ｐ";
 const INPUT_PLACEHOLDER: &str = "__INPUT__";
+/// Placeholders filled from the environment
+pub const BUILTIN_VARIABLES: [&str; 5] = ["os", "arch", "shell", "cwd", "date"];
+/// Subcommands of `.role`, which takes them before a role of that name
+pub const RESERVED_ROLE_NAMES: [&str; 5] = ["create", "edit", "delete", "info", "test"];
+/// Fields of a role as they are written in roles.yaml
+pub const ROLE_FIELDS: [&str; 11] = [
+    "name",
+    "prompt",
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "model",
+    "extends",
+    "mixins",
+    "protect",
+    "prepend",
+    "append",
+];
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Role {
@@ -16,6 +37,30 @@ pub struct Role {
     pub prompt: String,
     /// What sampling temperature to use, between 0 and 2
     pub temperature: Option<f64>,
+    /// Nucleus sampling, the probability mass of the tokens to consider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// Maximum number of tokens of the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// Client to use as `name` or `name:model`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Role whose prompt is placed before this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Roles whose prompts are placed after this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mixins: Vec<String>,
+    /// Whether to hide code and urls of the input from the model, e.g. for translating
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protect: bool,
+    /// Text put before every input, like `--prepend`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prepend: Option<String>,
+    /// Text put after every input, like `--append`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub append: Option<String>,
 }
 
 impl Role {
@@ -24,6 +69,14 @@ impl Role {
             name: TEMP_NAME.into(),
             prompt: prompt.into(),
             temperature,
+            top_p: None,
+            max_tokens: None,
+            model: None,
+            extends: None,
+            mixins: vec![],
+            protect: false,
+            prepend: None,
+            append: None,
         }
     }
 
@@ -31,40 +84,142 @@ impl Role {
         self.name == TEMP_NAME
     }
 
-    pub fn embeded(&self) -> bool {
-        self.prompt.contains(INPUT_PLACEHOLDER)
+    /// Names of the `{{name}}` placeholders of the prompt, in order of appearance
+    pub fn variables(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        replace_variables(&self.prompt, |name| {
+            if !names.iter().any(|v| v == name) {
+                names.push(name.to_string());
+            }
+            None
+        });
+        names
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        if self.embeded() {
-            merge_prompt_content(&self.prompt, content)
-        } else {
-            format!("{}{content}", self.prompt)
+    /// Replace the placeholders with the given values, others are left as they are
+    pub fn fill_variables(&mut self, values: &BTreeMap<String, String>) {
+        self.prompt = replace_variables(&self.prompt, |name| values.get(name).cloned());
+    }
+
+    /// The input between the `prepend` and `append` texts
+    pub fn wrap_input(&self, input: &str) -> String {
+        if input.is_empty() {
+            return input.to_string();
         }
+        wrap_input(self.prepend.as_deref(), input, self.append.as_deref())
+    }
+
+    pub fn embeded(&self) -> bool {
+        self.prompt.contains(INPUT_PLACEHOLDER)
     }
 
     pub fn build_emssages(&self, content: &str) -> Vec<Message> {
+        let content = self.wrap_input(content);
         if self.embeded() {
-            let content = merge_prompt_content(&self.prompt, content);
+            let content = merge_prompt_content(&self.prompt, &content);
             vec![Message {
                 role: MessageRole::User,
-                content,
+                content: content.into(),
             }]
         } else {
             vec![
                 Message {
                     role: MessageRole::System,
-                    content: self.prompt.clone(),
+                    content: self.prompt.as_str().into(),
                 },
                 Message {
                     role: MessageRole::User,
-                    content: content.to_string(),
+                    content: content.into(),
                 },
             ]
         }
     }
 }
 
+/// Whether `.role <name>` runs a subcommand instead of selecting the role
+pub fn is_reserved_role_name(name: &str) -> bool {
+    name.split(' ')
+        .next()
+        .is_some_and(|v| RESERVED_ROLE_NAMES.contains(&v))
+}
+
 pub fn merge_prompt_content(prompt: &str, content: &str) -> String {
     prompt.replace(INPUT_PLACEHOLDER, content)
 }
+
+/// Replace each `{{name}}` placeholder by the value of the function, if any
+fn replace_variables(text: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some((head, tail)) = rest.split_once("{{") {
+        output.push_str(head);
+        let replaced = tail.split_once("}}").and_then(|(name, tail)| {
+            let name = name.trim();
+            let valid =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return None;
+            }
+            value(name).map(|v| (v, tail))
+        });
+        match replaced {
+            Some((v, tail)) => {
+                output.push_str(&v);
+                rest = tail;
+            }
+            None => {
+                output.push_str("{{");
+                rest = tail;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_variables() {
+        let mut role = Role::new(
+            "Translate from {{from}} to {{ to }}, keep {{from}} names. Format: {{ not a var }}",
+            None,
+        );
+        assert_eq!(role.variables(), vec!["from", "to"]);
+        let values = BTreeMap::from([
+            ("from".to_string(), "English".to_string()),
+            ("to".to_string(), "French".to_string()),
+        ]);
+        role.fill_variables(&values);
+        assert_eq!(
+            role.prompt,
+            "Translate from English to French, keep English names. Format: {{ not a var }}"
+        );
+        assert!(role.variables().is_empty());
+    }
+
+    #[test]
+    fn test_role_fields() {
+        let role = Role {
+            top_p: Some(1.0),
+            max_tokens: Some(1),
+            model: Some("openai".into()),
+            extends: Some("base".into()),
+            mixins: vec!["mixin".into()],
+            protect: true,
+            prepend: Some("prepend".into()),
+            append: Some("append".into()),
+            ..Role::new("prompt", None)
+        };
+        let value = serde_yaml::to_value(role).unwrap();
+        let fields: Vec<&str> = value
+            .as_mapping()
+            .unwrap()
+            .keys()
+            .filter_map(|v| v.as_str())
+            .collect();
+        assert_eq!(fields, ROLE_FIELDS);
+    }
+}
@@ -0,0 +1,119 @@
+use super::conversation::Conversation;
+use super::message::MessageRole;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    /// Client and model as name:model
+    pub model: Option<String>,
+    /// Role and message history
+    pub conversation: Conversation,
+    /// Rags in use and their weights
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rags: BTreeMap<String, f32>,
+}
+
+impl Session {
+    pub fn load(dir: &Path, name: &str) -> Result<Self> {
+        let path = session_path(dir, name)?;
+        if !path.exists() {
+            bail!("Error: Unknown session '{name}'");
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load session at {}", path.display()))?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid session at {}", path.display()))
+    }
+
+    pub fn exists(dir: &Path, name: &str) -> Result<bool> {
+        Ok(session_path(dir, name)?.exists())
+    }
+
+    pub fn save(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let path = session_path(dir, name)?;
+        create_dir_all(dir)
+            .with_context(|| format!("Failed to create sessions dir at {}", dir.display()))?;
+        let content = serde_yaml::to_string(self).with_context(|| "Failed to serialize session")?;
+        write(&path, content)
+            .with_context(|| format!("Failed to save session at {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Names of the saved sessions
+pub fn list_sessions(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|v| {
+                let path = v.path();
+                if path.extension()? != "yaml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_string_lossy().to_string())
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    names.sort();
+    names
+}
+
+/// Exchanges of the named sessions, of every saved one if none, as (source, text) documents
+/// for `.rag session`
+pub fn session_documents(dir: &Path, names: &[String]) -> Result<Vec<(String, String)>> {
+    let names = match names.is_empty() {
+        true => list_sessions(dir),
+        false => names.to_vec(),
+    };
+    let mut documents = vec![];
+    for name in names {
+        let session = Session::load(dir, &name)?;
+        let mut input = None;
+        let mut exchange = 0;
+        for message in &session.conversation.messages {
+            match message.role {
+                MessageRole::System => {}
+                MessageRole::User => input = Some(message.content.to_string()),
+                MessageRole::Assistant => {
+                    exchange += 1;
+                    let text = match input.take() {
+                        Some(input) => format!("User: {input}\n\nAssistant: {}", message.content),
+                        None => format!("Assistant: {}", message.content),
+                    };
+                    documents.push((format!("session {name} #{exchange}"), text));
+                }
+            }
+        }
+    }
+    Ok(documents)
+}
+
+/// Name of the session saved last
+pub fn last_session(dir: &Path) -> Option<String> {
+    read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter_map(|v| {
+            let path = v.path();
+            if path.extension()? != "yaml" {
+                return None;
+            }
+            let modified = v.metadata().and_then(|v| v.modified()).ok()?;
+            Some((modified, path.file_stem()?.to_string_lossy().to_string()))
+        })
+        .max()
+        .map(|(_, name)| name)
+}
+
+fn session_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("Error: Invalid session name '{name}'");
+    }
+    Ok(dir.join(format!("{name}.yaml")))
+}
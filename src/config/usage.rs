@@ -0,0 +1,359 @@
+use anyhow::{bail, Context, Result};
+use chrono::{Duration, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Dollars per 1K tokens of a model
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelPrice {
+    /// Client and model as name:model, or the model alone
+    pub model: String,
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Entry of the usage log, one json object per line and one file per month
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Usage {
+    pub time: String,
+    /// Client and model as name:model
+    pub model: String,
+    /// Name of the api server key that sent the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Role the request was sent with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Estimated in dollars
+    pub cost: f64,
+    /// Milliseconds from sending the request to the end of the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+impl Usage {
+    /// As `YYYY-MM`, the name of the log file
+    pub fn month(&self) -> &str {
+        self.time.get(..7).unwrap_or_default()
+    }
+
+    /// As `YYYY-MM-DD`
+    pub fn day(&self) -> &str {
+        self.time.get(..10).unwrap_or_default()
+    }
+}
+
+/// Totals of the requests sharing a model or a role
+struct UsageGroup<'a> {
+    name: &'a str,
+    requests: usize,
+    tokens: usize,
+    cost: f64,
+    latency_ms: u64,
+    /// Requests that recorded their latency, older entries of the log don't
+    timed: usize,
+}
+
+impl UsageGroup<'_> {
+    fn latency(&self) -> String {
+        match self.latency_ms.checked_div(self.timed as u64) {
+            None => "-".into(),
+            Some(ms) if ms < 1000 => format!("{ms}ms"),
+            Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+        }
+    }
+}
+
+pub fn load_usage(dir: &Path, month: &str) -> Result<Vec<Usage>> {
+    let path = usage_path(dir, month)?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = read_to_string(&path)
+        .with_context(|| format!("Failed to load usage at {}", path.display()))?;
+    // Skip lines that fail to parse, e.g. cut short by a crash, rather than the whole log
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+pub fn save_usage(dir: &Path, usage: &Usage) -> Result<()> {
+    let path = usage_path(dir, usage.month())?;
+    create_dir_all(dir)
+        .with_context(|| format!("Failed to create usage dir at {}", dir.display()))?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let line = serde_json::to_string(usage)?;
+    writeln!(file, "{line}").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Requests, tokens and cost by model, the most expensive first, and their total
+pub fn summarize_usage(usages: &[Usage]) -> String {
+    let mut models: Vec<(&str, usize, usize, usize, f64)> = vec![];
+    for usage in usages {
+        let index = match models.iter().position(|v| v.0 == usage.model) {
+            Some(i) => i,
+            None => {
+                models.push((&usage.model, 0, 0, 0, 0.0));
+                models.len() - 1
+            }
+        };
+        let model = &mut models[index];
+        model.1 += 1;
+        model.2 += usage.prompt_tokens;
+        model.3 += usage.completion_tokens;
+        model.4 += usage.cost;
+    }
+    models.sort_by(|a, b| b.4.total_cmp(&a.4));
+    let total = models.iter().fold(("total", 0, 0, 0, 0.0), |acc, v| {
+        (acc.0, acc.1 + v.1, acc.2 + v.2, acc.3 + v.3, acc.4 + v.4)
+    });
+    let width = models.iter().map(|v| v.0.len()).max().unwrap_or(0).max(5);
+    let mut lines = vec![format!(
+        "{:<width$} {:>8} {:>10} {:>10} {:>9}",
+        "model", "requests", "prompt", "completion", "cost"
+    )];
+    for (model, requests, prompt, completion, cost) in models.into_iter().chain([total]) {
+        lines.push(format!(
+            "{model:<width$} {requests:>8} {prompt:>10} {completion:>10} {:>9}",
+            format!("${cost:.4}")
+        ));
+    }
+    lines.join("\n")
+}
+
+/// First and last day of a range given as `YYYY-MM`, `YYYY-MM-DD`, `<n>d` for the last n days,
+/// or `<from>..<to>` of months or days, the current month by default
+pub fn parse_report_range(range: Option<&str>) -> Result<(String, String)> {
+    let today = Local::now().date_naive();
+    let range = match range {
+        Some(v) => v.trim(),
+        None => return Ok((today.format("%Y-%m-01").to_string(), today.to_string())),
+    };
+    if let Some(days) = range.strip_suffix('d').and_then(|v| v.parse::<i64>().ok()) {
+        if days < 1 {
+            bail!("Invalid range '{range}', expected at least 1d");
+        }
+        let from = today - Duration::days(days - 1);
+        return Ok((from.to_string(), today.to_string()));
+    }
+    let (from, to) = range.split_once("..").unwrap_or((range, range));
+    let (from, to) = (range_bound(from, false)?, range_bound(to, true)?);
+    if from > to {
+        bail!("Invalid range '{range}', it ends before it starts");
+    }
+    Ok((from, to))
+}
+
+/// Months of the log files covering the days of the range
+pub fn range_months(from: &str, to: &str) -> Vec<String> {
+    let index = |v: &str| -> Option<i32> {
+        Some(v.get(..4)?.parse::<i32>().ok()? * 12 + v.get(5..7)?.parse::<i32>().ok()? - 1)
+    };
+    match (index(from), index(to)) {
+        (Some(from), Some(to)) => (from..=to)
+            .map(|v| format!("{:04}-{:02}", v / 12, v % 12 + 1))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Markdown report of the usage between two days: totals, then models and roles by requests
+pub fn usage_report(usages: &[Usage], from: &str, to: &str) -> String {
+    let usages: Vec<&Usage> = usages
+        .iter()
+        .filter(|v| v.day() >= from && v.day() <= to)
+        .collect();
+    let mut output = format!("# Usage from {from} to {to}\n\n");
+    if usages.is_empty() {
+        output.push_str("No requests\n");
+        return output;
+    }
+    let total = &group_usage(&usages, |_| Some("total"))[0];
+    let (prompt, completion) = usages.iter().fold((0, 0), |acc, v| {
+        (acc.0 + v.prompt_tokens, acc.1 + v.completion_tokens)
+    });
+    output.push_str(&format!(
+        "- Requests: {}\n- Tokens: {} ({prompt} prompt, {completion} completion)\n\
+- Cost: ${:.4}\n- Average latency: {}\n",
+        total.requests,
+        prompt + completion,
+        total.cost,
+        total.latency()
+    ));
+    output.push_str("\n## Models\n\n| model | requests | tokens | cost | latency |\n|---|---:|---:|---:|---:|\n");
+    for group in group_usage(&usages, |v| Some(&v.model)) {
+        output.push_str(&format!(
+            "| {} | {} | {} | ${:.4} | {} |\n",
+            group.name,
+            group.requests,
+            group.tokens,
+            group.cost,
+            group.latency()
+        ));
+    }
+    let roles = group_usage(&usages, |v| v.role.as_deref());
+    output.push_str("\n## Roles\n\n");
+    if roles.is_empty() {
+        output.push_str("No requests with a role\n");
+    } else {
+        output.push_str("| role | requests | tokens | cost |\n|---|---:|---:|---:|\n");
+        for group in roles {
+            output.push_str(&format!(
+                "| {} | {} | {} | ${:.4} |\n",
+                group.name, group.requests, group.tokens, group.cost
+            ));
+        }
+    }
+    output
+}
+
+/// Groups of the usages by name, the most used first, usages without a name are left out
+fn group_usage<'a>(
+    usages: &[&'a Usage],
+    name: impl Fn(&'a Usage) -> Option<&'a str>,
+) -> Vec<UsageGroup<'a>> {
+    let mut groups: Vec<UsageGroup> = vec![];
+    for usage in usages {
+        let name = match name(usage) {
+            Some(v) => v,
+            None => continue,
+        };
+        let index = match groups.iter().position(|v| v.name == name) {
+            Some(i) => i,
+            None => {
+                groups.push(UsageGroup {
+                    name,
+                    requests: 0,
+                    tokens: 0,
+                    cost: 0.0,
+                    latency_ms: 0,
+                    timed: 0,
+                });
+                groups.len() - 1
+            }
+        };
+        let group = &mut groups[index];
+        group.requests += 1;
+        group.tokens += usage.prompt_tokens + usage.completion_tokens;
+        group.cost += usage.cost;
+        if let Some(ms) = usage.latency_ms {
+            group.latency_ms += ms;
+            group.timed += 1;
+        }
+    }
+    groups.sort_by_key(|v| Reverse(v.requests));
+    groups
+}
+
+fn range_bound(value: &str, end: bool) -> Result<String> {
+    let value = value.trim();
+    let (date, month) = match is_month(value) {
+        true => (format!("{value}-01"), true),
+        false => (value.to_string(), false),
+    };
+    match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+        Ok(_) if month && end => Ok(format!("{value}-31")),
+        Ok(date) => Ok(date.to_string()),
+        Err(_) => bail!("Invalid date '{value}', expected YYYY-MM or YYYY-MM-DD"),
+    }
+}
+
+fn is_month(value: &str) -> bool {
+    value.len() == 7
+        && value
+            .chars()
+            .enumerate()
+            .all(|(i, c)| if i == 4 { c == '-' } else { c.is_ascii_digit() })
+}
+
+fn usage_path(dir: &Path, month: &str) -> Result<PathBuf> {
+    if !is_month(month) {
+        bail!("Error: Invalid month '{month}', expected YYYY-MM");
+    }
+    Ok(dir.join(format!("{month}.jsonl")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_usage() {
+        let usage = |model: &str, cost| Usage {
+            time: "2024-03-01T10:00:00+01:00".into(),
+            model: model.into(),
+            key: None,
+            role: None,
+            prompt_tokens: 100,
+            completion_tokens: 10,
+            cost,
+            latency_ms: None,
+        };
+        let output = summarize_usage(&[
+            usage("local:llama3", 0.0),
+            usage("openai:gpt-4", 0.5),
+            usage("openai:gpt-4", 0.25),
+        ]);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[1],
+            "openai:gpt-4        2        200         20   $0.7500"
+        );
+        assert!(lines[2].starts_with("local:llama3        1"));
+        assert!(lines[3].starts_with("total               3        300         30"));
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let usage = |time: &str, model: &str, role: Option<&str>, latency_ms| Usage {
+            time: time.into(),
+            model: model.into(),
+            key: None,
+            role: role.map(|v| v.into()),
+            prompt_tokens: 100,
+            completion_tokens: 10,
+            cost: 0.5,
+            latency_ms,
+        };
+        let usages = [
+            usage("2024-02-29T23:00:00+01:00", "openai:gpt-4", None, None),
+            usage(
+                "2024-03-01T10:00:00+01:00",
+                "local:llama3",
+                Some("shell"),
+                Some(1000),
+            ),
+            usage("2024-03-02T10:00:00+01:00", "openai:gpt-4", None, None),
+            usage(
+                "2024-03-03T10:00:00+01:00",
+                "local:llama3",
+                Some("shell"),
+                Some(2000),
+            ),
+        ];
+        let (from, to) = parse_report_range(Some("2024-03")).unwrap();
+        assert_eq!((from.as_str(), to.as_str()), ("2024-03-01", "2024-03-31"));
+        let output = usage_report(&usages, &from, &to);
+        assert!(output.contains("- Requests: 3\n- Tokens: 330 (300 prompt, 30 completion)"));
+        assert!(output.contains("- Average latency: 1.5s"));
+        assert!(output.contains(
+            "| local:llama3 | 2 | 220 | $1.0000 | 1.5s |\n| openai:gpt-4 | 1 | 110 | $0.5000 | - |"
+        ));
+        assert!(output.contains("| shell | 2 | 220 | $1.0000 |"));
+        assert_eq!(
+            range_months("2023-11-05", "2024-02-01"),
+            ["2023-11", "2023-12", "2024-01", "2024-02"]
+        );
+        assert!(parse_report_range(Some("2024-03..2024-02")).is_err());
+    }
+}
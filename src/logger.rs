@@ -0,0 +1,25 @@
+use is_terminal::IsTerminal;
+use std::env;
+use std::io::stderr;
+use tracing_subscriber::EnvFilter;
+
+/// Log to stderr, errors only with `-q`, request summaries with `-v` and everything with `-vv`.
+/// Without either flag, `RUST_LOG` is used when set, otherwise warnings are shown
+pub fn init_logger(quiet: bool, verbose: u8) {
+    let directives = match (quiet, verbose) {
+        (true, _) => "error".to_string(),
+        (false, 0) => env::var("RUST_LOG").unwrap_or_else(|_| "warn".into()),
+        (false, 1) => "warn,aichat=info".into(),
+        (false, _) => "debug".into(),
+    };
+    let detailed = verbose > 1 || (!quiet && env::var_os("RUST_LOG").is_some());
+    let builder = tracing_subscriber::fmt()
+        .with_writer(stderr)
+        .with_ansi(stderr().is_terminal())
+        .with_env_filter(EnvFilter::new(directives));
+    if detailed {
+        builder.init();
+    } else {
+        builder.without_time().with_target(false).init();
+    }
+}
@@ -1,32 +1,93 @@
+mod cassette;
 mod cli;
 mod client;
+mod clipboard;
 mod config;
+mod logger;
+mod rag;
 mod render;
 mod repl;
+mod serve;
+mod shell;
 mod term;
+#[cfg(feature = "self-update")]
+mod update;
 #[macro_use]
 mod utils;
 
-use crate::cli::Cli;
+use crate::cassette::Cassette;
+use crate::cli::{Cli, OutputFormat};
 use crate::client::ChatGptClient;
 use crate::config::{Config, SharedConfig};
+use crate::logger::init_logger;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
 use crossbeam::sync::WaitGroup;
 use is_terminal::IsTerminal;
 use parking_lot::Mutex;
 use render::{render_stream, MarkdownRender};
 use repl::{AbortSignal, Repl};
-use std::io::{stdin, Read};
+use std::fs::File;
+use std::io::{stdin, Read, Write};
+use std::process::{Command, Stdio};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{io::stdout, process::exit};
-use utils::cl100k_base_singleton;
+use utils::{cl100k_base_singleton, parse_directives, strip_ansi, StartupProfile};
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let start = Instant::now();
+    let mut cli = Cli::parse();
+    init_logger(cli.quiet, cli.verbose);
+    let mut profile = StartupProfile::new(cli.profile_startup, start);
+    profile.phase("arguments");
+    #[cfg(feature = "self-update")]
+    if cli.update {
+        return update::self_update();
+    }
+    if cli.edit_config {
+        Config::edit_config_file()?;
+        Config::init(false, &mut StartupProfile::default())?;
+        exit(0);
+    }
+    if cli.migrate_config {
+        println!("{}", Config::migrate_config_file()?);
+        exit(0);
+    }
+    if let Some(key) = &cli.get_config {
+        match Config::get_config_key(key)? {
+            Some(value) => println!("{value}"),
+            None => exit(1),
+        }
+        exit(0);
+    }
+    if let Some(args) = &cli.set_config {
+        Config::save_config_key(&args[0], &args[1])?;
+        exit(0);
+    }
+    if cli.lint_roles {
+        let (output, ok) = Config::lint_roles(cli.vars()?, cli.output == OutputFormat::Json)?;
+        println!("{output}");
+        exit(if ok { 0 } else { 1 });
+    }
+    if let Some(range) = &cli.report {
+        let range = Some(range.as_str()).filter(|v| !v.is_empty());
+        print!("{}", Config::usage_report(range)?);
+        exit(0);
+    }
     let text = cli.text();
-    let config = Arc::new(Mutex::new(Config::init(text.is_none())?));
+    let config = Arc::new(Mutex::new(Config::init(text.is_none(), &mut profile)?));
+    if cli.offline {
+        config.lock().offline = true;
+    }
+    if cli.last {
+        match Config::last_reply()? {
+            Some(reply) => println!("{reply}"),
+            None => bail!("No saved reply, make sure `save` is enabled"),
+        }
+        exit(0);
+    }
     if cli.list_roles {
         config
             .lock()
@@ -35,32 +96,153 @@ fn main() -> Result<()> {
             .for_each(|v| println!("{}", v.name));
         exit(0);
     }
-    let role = match &cli.role {
+    if cli.list_pipelines {
+        config
+            .lock()
+            .pipelines
+            .iter()
+            .for_each(|v| println!("{}", v.name));
+        exit(0);
+    }
+    if let Some(url) = &cli.install_role {
+        let client = ChatGptClient::init(config.clone())?;
+        let content = client.fetch_text(&gist_raw_url(url))?;
+        config.lock().install_roles(&content)?;
+        exit(0);
+    }
+    let pipeline = match &cli.pipeline {
         Some(name) => Some(
             config
                 .lock()
-                .find_role(name)
-                .ok_or_else(|| anyhow!("Unknown role '{name}'"))?,
+                .find_pipeline(name)
+                .ok_or_else(|| anyhow!("Unknown pipeline '{name}'"))?,
         ),
         None => None,
     };
+    let mut hook = None;
+    if let Some(pipeline) = pipeline {
+        cli.role = cli.role.or(pipeline.role);
+        cli.model = cli.model.or(pipeline.model);
+        cli.prepend = cli.prepend.or(pipeline.prepend);
+        cli.append = cli.append.or(pipeline.append);
+        if pipeline.highlight == Some(false) {
+            cli.no_highlight = true;
+        }
+        if pipeline.stream == Some(false) {
+            cli.no_stream = true;
+        }
+        hook = pipeline.hook;
+    }
+    if cli.role.is_none() {
+        cli.role = config.lock().file_role(&cli.file);
+    }
+    config.lock().variables = cli.vars()?;
+    let role = match &cli.role {
+        Some(name) => {
+            let config = config.lock();
+            let mut role = config.resolve_role(name)?;
+            config.fill_role_variables(&mut role)?;
+            Some(role)
+        }
+        None => None,
+    };
     config.lock().role = role;
+    profile.phase("role");
+    if let Some(model) = &cli.model {
+        let mut config = config.lock();
+        config.set_model(model)?;
+        // An explicit model also generates the shell commands
+        config.shell_model = None;
+    }
     if cli.no_highlight {
         config.lock().highlight = false;
     }
-    let no_stream = cli.no_stream;
+    let (mut text, directives) = match text {
+        Some(text) => {
+            let (text, directives) = parse_directives(&text)?;
+            (Some(text), directives)
+        }
+        None => (None, Default::default()),
+    };
+    if let Some(model) = &directives.model {
+        config.lock().find_client(model)?;
+    }
+    config.lock().message_temperature = directives.temperature;
+    config.lock().message_model = directives.model;
+    if !cli.file.is_empty() {
+        let attachments = config.lock().load_attachments(&cli.file)?;
+        if !attachments.texts.is_empty() {
+            text = Some(attachments.input(&text.unwrap_or_default()));
+        }
+        config.lock().attachments = attachments.images;
+        profile.phase("attachments");
+    }
+    if cli.dry_run {
+        config.lock().dry_run = true;
+    }
+    let (no_stream, output) = (cli.no_stream, cli.output);
     let client = ChatGptClient::init(config.clone())?;
+    if let Some(path) = &cli.record {
+        client.set_cassette(Cassette::record(path));
+    } else if let Some(path) = &cli.replay {
+        client.set_cassette(Cassette::replay(path)?);
+    }
+    if let Some(path) = &cli.stream_to {
+        let file =
+            File::create(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        client.set_stream_to(file);
+    }
+    profile.phase("client");
+    if let Some(addr) = &cli.serve {
+        profile.report();
+        return serve::run(client, config, addr);
+    }
+    if cli.watch_clipboard {
+        let wrap_input = |content: &str| match &text {
+            Some(text) => cli.wrap_input(&format!("{text}\n{content}")),
+            None => cli.wrap_input(content),
+        };
+        profile.report();
+        return clipboard::watch(&client, &config, wrap_input, cli.write_back);
+    }
+    if cli.execute {
+        profile.report();
+        if cli.history {
+            return shell::history(&client, &config);
+        }
+        let text = text.ok_or_else(|| anyhow!("No task to generate a command for"))?;
+        return shell::execute(&client, &config, &text);
+    }
     if atty::isnt(atty::Stream::Stdin) {
-        let mut input = String::new();
-        stdin().read_to_string(&mut input)?;
+        profile.report();
+        let mut bytes = vec![];
+        stdin().read_to_end(&mut bytes)?;
+        // Invalid UTF-8 is replaced rather than failing the whole input
+        let mut input = String::from_utf8_lossy(&bytes).to_string();
+        if !config.lock().raw_stdin {
+            input = strip_ansi(&input);
+        }
         if let Some(text) = text {
             input = format!("{text}\n{input}");
         }
-        start_directive(client, config, &input, no_stream)
+        let input = cli.wrap_input(&input);
+        start_directive(client, config, &input, no_stream, output, hook)
     } else {
         match text {
-            Some(text) => start_directive(client, config, &text, no_stream),
-            None => start_interactive(client, config),
+            Some(text) => {
+                profile.report();
+                let input = cli.wrap_input(&text);
+                start_directive(client, config, &input, no_stream, output, hook)
+            }
+            None => {
+                if cli.pipeline.is_some() {
+                    bail!("No input for pipeline");
+                }
+                if !cli.file.is_empty() {
+                    bail!("No input for the attached files");
+                }
+                start_interactive(client, config, profile)
+            }
         }
     }
 }
@@ -70,12 +252,22 @@ fn start_directive(
     config: SharedConfig,
     input: &str,
     no_stream: bool,
+    format: OutputFormat,
+    hook: Option<String>,
 ) -> Result<()> {
-    let highlight = config.lock().highlight && stdout().is_terminal();
-    let output = if no_stream {
+    config.lock().confirm_cost(input)?;
+    let mut options = config.lock().render_options();
+    options.highlight = options.highlight && stdout().is_terminal();
+    options.step = false;
+    let output = if format == OutputFormat::Json {
+        let (data, output) = client.send_message_json(input)?;
+        println!("{}", serde_json::to_string_pretty(&data)?);
+        output
+    } else if no_stream {
         let output = client.send_message(input)?;
-        if highlight {
-            let mut markdown_render = MarkdownRender::new();
+        if options.highlight {
+            let mut markdown_render =
+                MarkdownRender::new().with_line_numbers(options.code_line_numbers);
             println!("{}", markdown_render.render(&output).trim());
         } else {
             println!("{}", output.trim());
@@ -89,15 +281,62 @@ fn start_directive(
             abort_clone.set_ctrlc();
         })
         .expect("Error setting Ctrl-C handler");
-        let output = render_stream(input, &client, highlight, false, abort, wg.clone())?;
+        let (output, _, truncated) =
+            render_stream(input, &client, options, false, abort, wg.clone())?;
         wg.wait();
+        if truncated {
+            bail!("The reply was cut off, the connection dropped");
+        }
         output
     };
-    config.lock().save_message(input, &output)
+    config.lock().save_message(input, &output)?;
+    if let Some(hook) = hook {
+        run_hook(&hook, &output)?;
+    }
+    Ok(())
+}
+
+fn gist_raw_url(url: &str) -> String {
+    if url.starts_with("https://gist.github.com/") && !url.contains("/raw") {
+        format!("{}/raw", url.trim_end_matches('/'))
+    } else {
+        url.to_string()
+    }
+}
+
+fn run_hook(hook: &str, output: &str) -> Result<()> {
+    let (shell, arg) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(arg)
+        .arg(hook)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run hook '{hook}'"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(output.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("Hook '{hook}' exited with {status}");
+    }
+    Ok(())
 }
 
-fn start_interactive(client: ChatGptClient, config: SharedConfig) -> Result<()> {
+fn start_interactive(
+    client: ChatGptClient,
+    config: SharedConfig,
+    mut profile: StartupProfile,
+) -> Result<()> {
+    client.prewarm()?;
+    profile.phase("prewarm");
     cl100k_base_singleton();
+    profile.phase("tokenizer");
     let mut repl = Repl::init(config.clone())?;
+    profile.phase("repl");
+    profile.report();
     repl.run(client, config)
 }
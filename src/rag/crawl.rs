@@ -0,0 +1,264 @@
+//! Bounded crawl of a documentation site from its first page, staying on the same host and
+//! following robots.txt
+
+use super::web::{html_links, html_to_text};
+
+use crate::client::ChatGptClient;
+use crate::utils::glob_match;
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// Name looked for in the `User-agent` lines of robots.txt, before `*`
+const USER_AGENT: &str = "aichat";
+/// Extensions of links that are never pages
+const SKIPPED_EXTENSIONS: [&str; 20] = [
+    "png", "jpg", "jpeg", "gif", "svg", "webp", "ico", "css", "js", "pdf", "zip", "gz", "tar",
+    "mp3", "mp4", "woff", "woff2", "ttf", "xml", "json",
+];
+
+/// Limits of a crawl, `rag_crawl` in the config or options of `.rag add`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CrawlOptions {
+    /// Links followed away from the first page, 0 only indexes it
+    pub depth: usize,
+    pub max_pages: usize,
+    /// Globs the url paths must match, every path when empty
+    pub include: Vec<String>,
+    /// Globs of url paths left out
+    pub exclude: Vec<String>,
+    /// Milliseconds between requests, raised to the `Crawl-delay` of robots.txt
+    pub delay_ms: u64,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            depth: 3,
+            max_pages: 200,
+            include: vec![],
+            exclude: vec![],
+            delay_ms: 1000,
+        }
+    }
+}
+
+impl CrawlOptions {
+    /// Set an option given as `key=value`, `include` and `exclude` add a glob
+    pub fn set(&mut self, option: &str) -> Result<()> {
+        let (key, value) = match option.split_once('=') {
+            Some(v) => v,
+            None => bail!("Error: Invalid crawl option '{option}', expected key=value"),
+        };
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Error: Invalid value of {key}"))
+        };
+        match key {
+            "depth" => self.depth = number()? as usize,
+            "max_pages" => self.max_pages = number()? as usize,
+            "delay_ms" => self.delay_ms = number()?,
+            "include" => self.include.push(value.to_string()),
+            "exclude" => self.exclude.push(value.to_string()),
+            _ => bail!("Error: Unknown crawl option '{key}'"),
+        }
+        Ok(())
+    }
+
+    fn allows(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|v| glob_match(v, path)))
+            && !self.exclude.iter().any(|v| glob_match(v, path))
+    }
+}
+
+/// Rules of robots.txt for this crawler
+#[derive(Debug, Default)]
+struct Robots {
+    /// Path patterns, allowed or not
+    rules: Vec<(bool, String)>,
+    delay: Option<f64>,
+}
+
+impl Robots {
+    /// Rules of the group naming this crawler, or else of the `*` group
+    fn parse(text: &str) -> Self {
+        // Each group is its user agents, then its rules
+        let mut groups: Vec<(Vec<String>, Robots)> = vec![];
+        let mut in_rules = true;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+            if key == "user-agent" {
+                if in_rules {
+                    groups.push((vec![], Robots::default()));
+                    in_rules = false;
+                }
+                if let Some((agents, _)) = groups.last_mut() {
+                    agents.push(value.to_ascii_lowercase());
+                }
+                continue;
+            }
+            let robots = match groups.last_mut() {
+                Some((_, robots)) => robots,
+                None => continue,
+            };
+            in_rules = true;
+            match key.as_str() {
+                "allow" if !value.is_empty() => robots.rules.push((true, value.to_string())),
+                "disallow" if !value.is_empty() => robots.rules.push((false, value.to_string())),
+                "crawl-delay" => robots.delay = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let group = |name: &str| groups.iter().position(|(v, _)| v.iter().any(|v| v == name));
+        match group(USER_AGENT).or_else(|| group("*")) {
+            Some(index) => groups.swap_remove(index).1,
+            None => Robots::default(),
+        }
+    }
+
+    /// Whether the path is allowed, by the longest matching rule, `allow` winning ties
+    fn allows(&self, path: &str) -> bool {
+        let mut best: Option<(usize, bool)> = None;
+        for (allow, pattern) in &self.rules {
+            let matched = match pattern.strip_suffix('$') {
+                Some(pattern) => glob_match(pattern, path),
+                None => glob_match(&format!("{pattern}*"), path),
+            };
+            let longer = match best {
+                Some((len, allowed)) => {
+                    pattern.len() > len || (pattern.len() == len && *allow && !allowed)
+                }
+                None => true,
+            };
+            if matched && longer {
+                best = Some((pattern.len(), *allow));
+            }
+        }
+        best.map(|(_, allow)| allow).unwrap_or(true)
+    }
+}
+
+/// Pages of the site from the url, as (url, text) documents, within the limits of the options
+pub fn crawl(
+    client: &ChatGptClient,
+    start: &str,
+    options: &CrawlOptions,
+) -> Result<Vec<(String, String)>> {
+    let start = Url::parse(start).map_err(|_| anyhow!("Error: Invalid url {start}"))?;
+    let host = start.host_str().unwrap_or_default().to_string();
+    let robots = match start.join("/robots.txt") {
+        Ok(url) => client
+            .fetch_text(url.as_str())
+            .map(|v| Robots::parse(&v))
+            .unwrap_or_default(),
+        Err(_) => Robots::default(),
+    };
+    if !robots.allows(&url_path(&start)) {
+        bail!("Error: robots.txt of {host} doesn't allow crawling {start}");
+    }
+    let delay_ms = robots
+        .delay
+        .map(|v| (v * 1000.0) as u64)
+        .unwrap_or_default()
+        .max(options.delay_ms);
+    let progress = atty::is(atty::Stream::Stderr);
+    let mut queue = VecDeque::from([(start.clone(), 0)]);
+    let mut seen = HashSet::from([start.to_string()]);
+    let mut pages = vec![];
+    while let Some((url, depth)) = queue.pop_front() {
+        if pages.len() >= options.max_pages {
+            break;
+        }
+        // Also after the request of robots.txt
+        thread::sleep(Duration::from_millis(delay_ms));
+        if progress {
+            eprint!("\r\x1b[2KCrawled {} pages, fetching {url}", pages.len());
+        }
+        let (final_url, html) = match client.fetch_html(url.as_str()) {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("{err:#}");
+                continue;
+            }
+        };
+        // Redirected away from the site
+        let url = match Url::parse(&final_url) {
+            Ok(v) if v.host_str() == Some(host.as_str()) => v,
+            _ => continue,
+        };
+        if depth < options.depth {
+            for link in html_links(&html) {
+                let mut link = match url.join(&link) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                link.set_fragment(None);
+                let path = url_path(&link);
+                let extension = link.path().rsplit_once('.').map(|(_, v)| v.to_lowercase());
+                if !matches!(link.scheme(), "http" | "https")
+                    || link.host_str() != Some(host.as_str())
+                    || extension.is_some_and(|v| SKIPPED_EXTENSIONS.contains(&v.as_str()))
+                    || !robots.allows(&path)
+                    || !options.allows(&path)
+                    || !seen.insert(link.to_string())
+                {
+                    continue;
+                }
+                queue.push_back((link, depth + 1));
+            }
+        }
+        if url != start && !options.allows(&url_path(&url)) {
+            continue;
+        }
+        let text = html_to_text(&html);
+        if !text.is_empty() {
+            pages.push((url.to_string(), text));
+        }
+    }
+    if progress {
+        eprint!("\r\x1b[2K");
+    }
+    Ok(pages)
+}
+
+/// Path and query of the url, what robots.txt rules and the globs match
+fn url_path(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_robots() {
+        let robots = Robots::parse(
+            "User-agent: googlebot\nDisallow: /\n\n\
+            User-agent: *\nUser-agent: other\nDisallow: /private\nAllow: /private/docs\n\
+            Disallow: /*.pdf$\nCrawl-delay: 2 # seconds\n",
+        );
+        assert!(robots.allows("/guide/intro"));
+        assert!(!robots.allows("/private/keys"));
+        assert!(robots.allows("/private/docs/a"));
+        assert!(!robots.allows("/files/a.pdf"));
+        assert!(robots.allows("/files/a.pdf?download"));
+        assert_eq!(robots.delay, Some(2.0));
+        let robots = Robots::parse("User-agent: aichat\nDisallow: /\nUser-agent: *\nAllow: /\n");
+        assert!(!robots.allows("/"));
+    }
+}
@@ -0,0 +1,289 @@
+//! Embeddings of a rag in a flat file, memory-mapped so that large indexes are queried without
+//! loading them
+//!
+//! The file holds a header, the centroids of the clusters, where each cluster starts, the chunk
+//! of each vector, then the normalized vectors grouped by cluster, all as little-endian 32 bits.
+//! Small indexes have a single cluster and are searched exhaustively, larger ones only search the
+//! clusters closest to the query.
+
+use anyhow::{bail, Context, Result};
+use memmap2::Mmap;
+use std::fs::{rename, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread;
+
+const MAGIC: &[u8; 8] = b"AICHATV1";
+/// Magic, then the dimension, the number of vectors and the number of clusters
+const HEADER_SIZE: usize = 20;
+/// Vectors below which the index is a single cluster
+const MIN_CLUSTERED: usize = 20_000;
+/// Clusters searched for a query
+const PROBES: usize = 16;
+/// Vectors per cluster the centroids are trained on
+const TRAINING_SAMPLE: usize = 32;
+const TRAINING_ROUNDS: usize = 8;
+
+#[derive(Debug)]
+pub struct VectorIndex {
+    mmap: Mmap,
+    dim: usize,
+    count: usize,
+    clusters: usize,
+}
+
+impl VectorIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open vector index at {}", path.display()))?;
+        // The file is only ever replaced by a rename, never written in place
+        let mmap = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to map vector index at {}", path.display()))?;
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            bail!("Invalid vector index at {}", path.display());
+        }
+        let dim = read_u32(&mmap, 8);
+        let count = read_u32(&mmap, 12);
+        let clusters = read_u32(&mmap, 16);
+        let size = HEADER_SIZE + 4 * (clusters * dim + clusters + 1 + count + count * dim);
+        if mmap.len() != size {
+            bail!("Invalid vector index at {}", path.display());
+        }
+        Ok(Self {
+            mmap,
+            dim,
+            count,
+            clusters,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Chunks of the `top_k` vectors closest to the query, with their cosine similarity
+    pub fn search(&self, query: &[f32], top_k: usize) -> Vec<(f32, usize)> {
+        if query.len() != self.dim {
+            return vec![];
+        }
+        let query = normalize(query.to_vec());
+        let mut clusters: Vec<(f32, usize)> = (0..self.clusters)
+            .map(|v| (dot(&query, self.centroid(v)), v))
+            .collect();
+        clusters.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut hits = vec![];
+        for (_, cluster) in clusters.into_iter().take(PROBES) {
+            for position in self.offset(cluster)..self.offset(cluster + 1) {
+                let score = dot(&query, self.vector(position));
+                hits.push((score, self.chunk(position)));
+            }
+        }
+        hits.sort_by(|a, b| b.0.total_cmp(&a.0));
+        hits.truncate(top_k);
+        hits
+    }
+
+    /// Every vector in the order of the chunks, to update the index
+    pub fn vectors(&self) -> Vec<Vec<f32>> {
+        let mut vectors = vec![vec![]; self.count];
+        for position in 0..self.count {
+            vectors[self.chunk(position)] = read_floats(self.vector(position));
+        }
+        vectors
+    }
+
+    fn centroid(&self, cluster: usize) -> &[u8] {
+        let start = HEADER_SIZE + 4 * cluster * self.dim;
+        &self.mmap[start..start + 4 * self.dim]
+    }
+
+    fn offset(&self, cluster: usize) -> usize {
+        read_u32(
+            &self.mmap,
+            HEADER_SIZE + 4 * (self.clusters * self.dim + cluster),
+        )
+    }
+
+    fn chunk(&self, position: usize) -> usize {
+        let start = HEADER_SIZE + 4 * (self.clusters * self.dim + self.clusters + 1);
+        read_u32(&self.mmap, start + 4 * position)
+    }
+
+    fn vector(&self, position: usize) -> &[u8] {
+        let start = HEADER_SIZE
+            + 4 * (self.clusters * self.dim + self.clusters + 1 + self.count + position * self.dim);
+        &self.mmap[start..start + 4 * self.dim]
+    }
+}
+
+/// Write the vectors, in the order of the chunks, to a new index replacing the one at the path
+pub fn write_index(path: &Path, vectors: &[Vec<f32>]) -> Result<()> {
+    let dim = vectors.first().map(|v| v.len()).unwrap_or_default();
+    if vectors.iter().any(|v| v.len() != dim) {
+        bail!("Embeddings of different sizes, the client must not change between chunks");
+    }
+    let vectors: Vec<Vec<f32>> = vectors.iter().map(|v| normalize(v.clone())).collect();
+    let (centroids, clusters) = if vectors.len() < MIN_CLUSTERED {
+        (vec![vec![0.0; dim]], vec![0; vectors.len()])
+    } else {
+        let centroids = train_centroids(&vectors, (vectors.len() as f64).sqrt() as usize);
+        let clusters = nearest_centroids(&vectors, &centroids);
+        (centroids, clusters)
+    };
+    let mut order: Vec<usize> = (0..vectors.len()).collect();
+    order.sort_by_key(|v| clusters[*v]);
+    let mut offsets = vec![0; centroids.len() + 1];
+    for cluster in &clusters {
+        offsets[cluster + 1] += 1;
+    }
+    for i in 1..offsets.len() {
+        offsets[i] += offsets[i - 1];
+    }
+
+    let temp = path.with_extension("tmp");
+    let file = File::create(&temp)
+        .with_context(|| format!("Failed to create vector index at {}", temp.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    for value in [dim, vectors.len(), centroids.len()] {
+        writer.write_all(&(value as u32).to_le_bytes())?;
+    }
+    for value in centroids.iter().flatten() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    for value in offsets.iter().chain(&order) {
+        writer.write_all(&(*value as u32).to_le_bytes())?;
+    }
+    for chunk in &order {
+        for value in &vectors[*chunk] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    rename(&temp, path)
+        .with_context(|| format!("Failed to save vector index at {}", path.display()))
+}
+
+/// Spherical k-means on a sample of the vectors, seeded with evenly spaced ones
+fn train_centroids(vectors: &[Vec<f32>], count: usize) -> Vec<Vec<f32>> {
+    let step = (vectors.len() / (count * TRAINING_SAMPLE)).max(1);
+    let sample: Vec<Vec<f32>> = vectors.iter().step_by(step).cloned().collect();
+    let mut centroids: Vec<Vec<f32>> = (0..count)
+        .map(|v| sample[v * sample.len() / count].clone())
+        .collect();
+    for _ in 0..TRAINING_ROUNDS {
+        let clusters = nearest_centroids(&sample, &centroids);
+        let mut sums = vec![vec![0.0; centroids[0].len()]; count];
+        for (vector, cluster) in sample.iter().zip(clusters) {
+            for (sum, value) in sums[cluster].iter_mut().zip(vector) {
+                *sum += value;
+            }
+        }
+        for (centroid, sum) in centroids.iter_mut().zip(sums) {
+            // A cluster left empty keeps its centroid
+            if sum.iter().any(|v| *v != 0.0) {
+                *centroid = normalize(sum);
+            }
+        }
+    }
+    centroids
+}
+
+/// Closest centroid of each vector, the vectors split between the cores
+fn nearest_centroids(vectors: &[Vec<f32>], centroids: &[Vec<f32>]) -> Vec<usize> {
+    let tasks = thread::available_parallelism()
+        .map(|v| v.get())
+        .unwrap_or(1);
+    let size = vectors.len().div_ceil(tasks).max(1);
+    thread::scope(|s| {
+        let handles: Vec<_> = vectors
+            .chunks(size)
+            .map(|part| {
+                s.spawn(move || {
+                    part.iter()
+                        .map(|vector| {
+                            let mut best = (f32::MIN, 0);
+                            for (i, centroid) in centroids.iter().enumerate() {
+                                let score: f32 =
+                                    vector.iter().zip(centroid).map(|(a, b)| a * b).sum();
+                                if score > best.0 {
+                                    best = (score, i);
+                                }
+                            }
+                            best.1
+                        })
+                        .collect::<Vec<usize>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|v| v.join().unwrap_or_default())
+            .collect()
+    })
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+fn dot(query: &[f32], bytes: &[u8]) -> f32 {
+    query
+        .iter()
+        .zip(bytes.chunks_exact(4))
+        .map(|(a, b)| a * f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .sum()
+}
+
+fn read_floats(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+        .collect()
+}
+
+fn read_u32(bytes: &[u8], start: usize) -> usize {
+    u32::from_le_bytes([
+        bytes[start],
+        bytes[start + 1],
+        bytes[start + 2],
+        bytes[start + 3],
+    ]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_index() {
+        let path = std::env::temp_dir().join(format!("aichat-test-{}.vectors", std::process::id()));
+        // Two groups of vectors, enough of them to be clustered
+        let vectors: Vec<Vec<f32>> = (0..MIN_CLUSTERED + 10)
+            .map(|v| {
+                let x = (v % 97) as f32 / 97.0;
+                if v % 2 == 0 {
+                    vec![1.0, x, 0.0]
+                } else {
+                    vec![0.0, x, 1.0]
+                }
+            })
+            .collect();
+        write_index(&path, &vectors).unwrap();
+        let index = VectorIndex::open(&path).unwrap();
+        assert_eq!(index.len(), vectors.len());
+        let hits = index.search(&[0.0, 0.0, 2.0], 3);
+        assert_eq!(hits.len(), 3);
+        assert!(hits
+            .iter()
+            .all(|(score, chunk)| *score > 0.99 && chunk % 2 == 1));
+        assert_eq!(index.vectors()[2], normalize(vectors[2].clone()));
+        assert!(index.search(&[1.0, 0.0], 3).is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
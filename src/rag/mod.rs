@@ -0,0 +1,551 @@
+//! Retrieval of local documents, indexed with the embeddings of a client
+
+mod crawl;
+mod index;
+mod web;
+
+pub use self::crawl::CrawlOptions;
+
+use self::crawl::crawl;
+use self::index::{write_index, VectorIndex};
+use self::web::{html_to_text, parse_feed, Entry};
+
+use crate::client::{ChatGptClient, ClientConfig};
+use crate::config::{session_documents, Config};
+use crate::print_now;
+use crate::utils::count_tokens;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Maximum characters in a chunk
+const CHUNK_SIZE: usize = 1000;
+/// Number of chunks retrieved for a query, without `rag_max_tokens`
+const TOP_K: usize = 4;
+/// Chunks each rag offers for a query, merged with the others within `rag_max_tokens`
+const CANDIDATES: usize = 16;
+const EXTENSIONS: [&str; 5] = ["md", "markdown", "txt", "rst", "org"];
+/// Path of the rags indexing saved sessions, followed by their names separated by commas
+pub const SESSIONS_PATH: &str = "session:";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rag {
+    pub name: String,
+    /// Directory, file, sitemap, feed or saved sessions that were indexed
+    pub path: String,
+    /// Client whose embeddings built the index, queries must use the same one
+    pub client: String,
+    pub chunks: Vec<Chunk>,
+    /// Pages listed by the sitemap or feed when it was last fetched
+    #[serde(default)]
+    pub pages: Vec<Entry>,
+    /// Limits of the crawl for a site indexed from its first page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crawl: Option<CrawlOptions>,
+    /// Vectors of the chunks once saved, mapped from the file next to the rag
+    #[serde(skip)]
+    index: Option<Arc<VectorIndex>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chunk {
+    pub file: String,
+    pub text: String,
+    /// Only in memory while building, saved in the vector index, rags saved before it have them
+    #[serde(default, skip_serializing)]
+    pub vector: Vec<f32>,
+}
+
+impl Rag {
+    /// Index the markdown and text files under the path, the pages of a sitemap or feed, the
+    /// pages of a site crawled from its url, or the exchanges of saved sessions
+    pub fn build(
+        name: &str,
+        path: &str,
+        crawl: Option<CrawlOptions>,
+        client: &ChatGptClient,
+        client_config: &ClientConfig,
+    ) -> Result<Self> {
+        let mut rag = Self {
+            name: name.to_string(),
+            path: path.to_string(),
+            client: client_config.as_client().name(),
+            chunks: vec![],
+            pages: vec![],
+            crawl,
+            index: None,
+        };
+        rag.rebuild(client, client_config)?;
+        Ok(rag)
+    }
+
+    /// Index the path again, only pages that are new or updated for sitemaps and feeds
+    pub fn rebuild(
+        &mut self,
+        client: &ChatGptClient,
+        client_config: &ClientConfig,
+    ) -> Result<String> {
+        if let Some(options) = self.crawl.clone() {
+            return self.crawl_site(&options, client, client_config);
+        }
+        if self.path.starts_with("http://") || self.path.starts_with("https://") {
+            return self.sync_pages(client, client_config);
+        }
+        if let Some(names) = self.path.strip_prefix(SESSIONS_PATH) {
+            let names: Vec<String> = names
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect();
+            return self.index_sessions(&names, client, client_config);
+        }
+        let mut files = vec![];
+        collect_files(Path::new(&self.path), &mut files)?;
+        if files.is_empty() {
+            bail!("Error: No markdown or text files in {}", self.path);
+        }
+        let mut documents = vec![];
+        for file in &files {
+            let content = read_to_string(file)
+                .with_context(|| format!("Failed to load {}", file.display()))?;
+            documents.push((file.display().to_string(), content));
+        }
+        self.chunks = embed_documents(documents, &[], client, client_config)?;
+        self.index = None;
+        Ok(format!("Indexed {} files", files.len()))
+    }
+
+    /// Index the exchanges of the sessions, only embedding those not indexed yet
+    fn index_sessions(
+        &mut self,
+        names: &[String],
+        client: &ChatGptClient,
+        client_config: &ClientConfig,
+    ) -> Result<String> {
+        let documents = session_documents(&Config::sessions_dir()?, names)?;
+        if documents.is_empty() {
+            bail!("Error: No exchanges in the saved sessions");
+        }
+        let count = documents.len();
+        self.restore_vectors();
+        self.chunks = embed_documents(documents, &self.chunks, client, client_config)?;
+        Ok(format!("Indexed {count} exchanges"))
+    }
+
+    /// Index the pages of the site again, only embedding the chunks that changed
+    fn crawl_site(
+        &mut self,
+        options: &CrawlOptions,
+        client: &ChatGptClient,
+        client_config: &ClientConfig,
+    ) -> Result<String> {
+        let documents = crawl(client, &self.path, options)?;
+        if documents.is_empty() {
+            bail!("Error: No pages crawled from {}", self.path);
+        }
+        let count = documents.len();
+        self.restore_vectors();
+        self.chunks = embed_documents(documents, &self.chunks, client, client_config)?;
+        Ok(format!("Crawled {count} pages"))
+    }
+
+    /// Move the vectors of the index back to the chunks, to update them
+    fn restore_vectors(&mut self) {
+        if let Some(index) = self.index.take() {
+            for (chunk, vector) in self.chunks.iter_mut().zip(index.vectors()) {
+                chunk.vector = vector;
+            }
+        }
+    }
+
+    fn sync_pages(
+        &mut self,
+        client: &ChatGptClient,
+        client_config: &ClientConfig,
+    ) -> Result<String> {
+        let entries = parse_feed(&client.fetch_text(&self.path)?);
+        if entries.is_empty() {
+            bail!(
+                "Error: No pages listed in {}, crawl the site with `.rag add {}`",
+                self.path,
+                self.path
+            );
+        }
+        // Pages without a date are only fetched once
+        let changed: Vec<&Entry> = entries
+            .iter()
+            .filter(|v| {
+                !self
+                    .pages
+                    .iter()
+                    .any(|p| p.url == v.url && p.updated == v.updated)
+            })
+            .collect();
+        let removed = self
+            .pages
+            .iter()
+            .filter(|p| !entries.iter().any(|v| v.url == p.url))
+            .count();
+        if !changed.is_empty() {
+            print_now!("Fetching {} pages...\n", changed.len());
+        }
+        let mut documents = vec![];
+        for entry in &changed {
+            let html = client.fetch_text(&entry.url)?;
+            documents.push((entry.url.clone(), html_to_text(&html)));
+        }
+        let chunks = embed_documents(documents, &[], client, client_config)?;
+        self.restore_vectors();
+        self.chunks.retain(|chunk| {
+            entries.iter().any(|v| v.url == chunk.file)
+                && !changed.iter().any(|v| v.url == chunk.file)
+        });
+        self.chunks.extend(chunks);
+        let output = format!(
+            "{} pages added or updated, {removed} removed",
+            changed.len()
+        );
+        self.pages = entries;
+        Ok(output)
+    }
+
+    pub fn load(dir: &Path, name: &str) -> Result<Self> {
+        let path = rag_path(dir, name)?;
+        if !path.exists() {
+            bail!("Error: Unknown rag '{name}'");
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load rag at {}", path.display()))?;
+        let mut rag: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid rag at {}", path.display()))?;
+        if rag.chunks.iter().any(|v| v.vector.is_empty()) {
+            let index = VectorIndex::open(&path.with_extension("vectors"))?;
+            if index.len() != rag.chunks.len() {
+                bail!("Error: The vectors of rag '{name}' don't match its chunks, rebuild it");
+            }
+            rag.index = Some(Arc::new(index));
+        }
+        Ok(rag)
+    }
+
+    /// Save the chunks, then the vectors built in memory to the vector index, mapped from then on
+    pub fn save(&mut self, dir: &Path) -> Result<()> {
+        let path = rag_path(dir, &self.name)?;
+        create_dir_all(dir)
+            .with_context(|| format!("Failed to create rags dir at {}", dir.display()))?;
+        if self.index.is_none() {
+            let index_path = path.with_extension("vectors");
+            let vectors: Vec<Vec<f32>> = self
+                .chunks
+                .iter_mut()
+                .map(|v| std::mem::take(&mut v.vector))
+                .collect();
+            write_index(&index_path, &vectors)?;
+            self.index = Some(Arc::new(VectorIndex::open(&index_path)?));
+        }
+        let content = serde_json::to_string(self).with_context(|| "Failed to serialize rag")?;
+        write(&path, content).with_context(|| format!("Failed to save rag at {}", path.display()))
+    }
+
+    /// Chunks closest to the query vector, with their cosine similarity
+    fn search(&self, vector: &[f32], count: usize) -> Vec<(f32, &Chunk)> {
+        let mut scores: Vec<(f32, &Chunk)> = match &self.index {
+            Some(index) => index
+                .search(vector, count)
+                .into_iter()
+                .filter_map(|(score, i)| Some((score, self.chunks.get(i)?)))
+                .collect(),
+            None => self
+                .chunks
+                .iter()
+                .map(|v| (cosine_similarity(vector, &v.vector), v))
+                .collect(),
+        };
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scores.truncate(count);
+        scores
+    }
+}
+
+/// Rag in use, the similarity of its chunks multiplied by the weight when merged with others
+#[derive(Debug, Clone)]
+pub struct WeightedRag {
+    pub rag: Rag,
+    pub weight: f32,
+}
+
+/// Chunks of the rags closest to the query, which is embedded once per client as (client,
+/// vector), formatted as context for the prompt. The best weighted chunks are kept, as many as
+/// fit in `max_tokens` or `TOP_K` of them
+pub fn retrieve(
+    rags: &[WeightedRag],
+    vectors: &[(String, Vec<f32>)],
+    max_tokens: Option<usize>,
+) -> String {
+    let count = match max_tokens {
+        Some(_) => CANDIDATES,
+        None => TOP_K,
+    };
+    let mut scores: Vec<(f32, &Chunk)> = vec![];
+    for WeightedRag { rag, weight } in rags {
+        let vector = match vectors.iter().find(|(client, _)| *client == rag.client) {
+            Some((_, vector)) => vector,
+            None => continue,
+        };
+        let hits = rag.search(vector, count);
+        scores.extend(
+            hits.into_iter()
+                .map(|(score, chunk)| (score * weight, chunk)),
+        );
+    }
+    scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut tokens = 0;
+    let mut sections = vec![];
+    for (_, chunk) in scores {
+        let section = format!("Source: {}\n{}", chunk.file, chunk.text);
+        if sections.contains(&section) {
+            continue;
+        }
+        match max_tokens {
+            Some(max_tokens) => {
+                let size = count_tokens(&section);
+                if tokens + size > max_tokens {
+                    continue;
+                }
+                tokens += size;
+            }
+            None if sections.len() == TOP_K => break,
+            None => {}
+        }
+        sections.push(section);
+    }
+    sections.join("\n\n")
+}
+
+/// Split the documents, given as (source, content), into chunks and embed them, reusing the
+/// vectors of the previous chunks with the same source and text
+fn embed_documents(
+    documents: Vec<(String, String)>,
+    previous: &[Chunk],
+    client: &ChatGptClient,
+    client_config: &ClientConfig,
+) -> Result<Vec<Chunk>> {
+    let previous: HashMap<(&str, &str), &Vec<f32>> = previous
+        .iter()
+        .filter(|v| !v.vector.is_empty())
+        .map(|v| ((v.file.as_str(), v.text.as_str()), &v.vector))
+        .collect();
+    let mut chunks = vec![];
+    let mut texts = vec![];
+    for (file, content) in documents {
+        for text in split_chunks(&content, CHUNK_SIZE) {
+            let vector = previous
+                .get(&(file.as_str(), text.as_str()))
+                .map(|v| v.to_vec());
+            if vector.is_none() {
+                texts.push(text.clone());
+            }
+            chunks.push(Chunk {
+                file: file.clone(),
+                text,
+                vector: vector.unwrap_or_default(),
+            });
+        }
+    }
+    if texts.is_empty() {
+        return Ok(chunks);
+    }
+    print_now!("Embedding {} chunks...\n", texts.len());
+    let mut vectors = client.embed(client_config, &texts)?.into_iter();
+    for chunk in chunks.iter_mut().filter(|v| v.vector.is_empty()) {
+        chunk.vector = vectors.next().unwrap_or_default();
+    }
+    Ok(chunks)
+}
+
+/// Names of the built indexes
+pub fn list_rags(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|v| {
+                let path = v.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_string_lossy().to_string())
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    names.sort();
+    names
+}
+
+fn rag_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("Error: Invalid rag name '{name}'");
+    }
+    Ok(dir.join(format!("{name}.json")))
+}
+
+/// Text files under the path, PDF files fail the build since their text cannot be read yet
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_file() {
+        check_not_pdf(path)?;
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let entries = read_dir(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|v| v.path()).collect();
+    paths.sort();
+    for path in paths {
+        let hidden = path
+            .file_name()
+            .map(|v| v.to_string_lossy().starts_with('.'))
+            .unwrap_or_default();
+        if hidden {
+            continue;
+        }
+        check_not_pdf(&path)?;
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .map(|v| EXTENSIONS.iter().any(|ext| v == *ext))
+            .unwrap_or_default()
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn check_not_pdf(path: &Path) -> Result<()> {
+    if path
+        .extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("pdf"))
+    {
+        bail!(
+            "Error: PDF files are not supported, convert {} to text first, e.g. with `pdftotext`",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Group paragraphs into chunks of at most `size` characters, splitting longer paragraphs
+fn split_chunks(text: &str, size: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for paragraph in text
+        .split("\n\n")
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+    {
+        if !chunk.is_empty() && chunk.chars().count() + paragraph.chars().count() + 2 > size {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        let chars: Vec<char> = paragraph.chars().collect();
+        for part in chars.chunks(size) {
+            if !chunk.is_empty() {
+                chunk.push_str("\n\n");
+            }
+            chunk.extend(part);
+            if part.len() == size {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retrieve() {
+        let rag = |name: &str, client: &str, weight: f32, texts: &[(&str, [f32; 2])]| {
+            let chunks = texts
+                .iter()
+                .map(|(text, vector)| Chunk {
+                    file: name.into(),
+                    text: text.to_string(),
+                    vector: vector.to_vec(),
+                })
+                .collect();
+            let rag = Rag {
+                name: name.into(),
+                path: String::new(),
+                client: client.into(),
+                chunks,
+                pages: vec![],
+                crawl: None,
+                index: None,
+            };
+            WeightedRag { rag, weight }
+        };
+        let rags = [
+            rag(
+                "docs",
+                "a",
+                1.0,
+                &[("install", [1.0, 0.0]), ("usage", [0.8, 0.6])],
+            ),
+            rag(
+                "notes",
+                "b",
+                0.5,
+                &[("todo", [1.0, 0.0]), ("misc", [0.0, 1.0])],
+            ),
+        ];
+        let vectors = [
+            ("a".to_string(), vec![1.0, 0.0]),
+            ("b".into(), vec![1.0, 0.0]),
+        ];
+        assert_eq!(
+            retrieve(&rags, &vectors, None),
+            "Source: docs\ninstall\n\nSource: docs\nusage\n\nSource: notes\ntodo\n\nSource: notes\nmisc"
+        );
+        assert_eq!(
+            retrieve(&rags, &vectors, Some(10)),
+            "Source: docs\ninstall\n\nSource: docs\nusage"
+        );
+        assert_eq!(
+            retrieve(&rags, &vectors[1..], None),
+            "Source: notes\ntodo\n\nSource: notes\nmisc"
+        );
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        assert_eq!(
+            split_chunks("aaa\n\nbbb\n\n\n\ncc", 8),
+            vec!["aaa\n\nbbb".to_string(), "cc".into()]
+        );
+        assert_eq!(
+            split_chunks("abcdefghij\n\nk", 4),
+            vec!["abcd".to_string(), "efgh".into(), "ij".into(), "k".into()]
+        );
+    }
+}
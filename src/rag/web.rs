@@ -0,0 +1,221 @@
+//! Minimal parsing of sitemaps, feeds and html pages, enough to index documentation sites
+
+use serde::{Deserialize, Serialize};
+
+/// Page listed by a sitemap or feed
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    pub url: String,
+    /// `lastmod`, `pubDate` or `updated` as written in the document
+    pub updated: Option<String>,
+}
+
+/// Entries of a sitemap.xml, RSS or Atom document
+pub fn parse_feed(xml: &str) -> Vec<Entry> {
+    if xml.contains("<urlset") {
+        return blocks(xml, "url")
+            .into_iter()
+            .filter_map(|v| {
+                Some(Entry {
+                    url: text(v, "loc")?,
+                    updated: text(v, "lastmod"),
+                })
+            })
+            .collect();
+    }
+    if xml.contains("<rss") {
+        return blocks(xml, "item")
+            .into_iter()
+            .filter_map(|v| {
+                Some(Entry {
+                    url: text(v, "link")?,
+                    updated: text(v, "pubDate"),
+                })
+            })
+            .collect();
+    }
+    blocks(xml, "entry")
+        .into_iter()
+        .filter_map(|v| {
+            Some(Entry {
+                url: attribute(v, "link", "href").or_else(|| text(v, "link"))?,
+                updated: text(v, "updated").or_else(|| text(v, "published")),
+            })
+        })
+        .collect()
+}
+
+/// Readable text of an html page, keeping paragraphs apart
+pub fn html_to_text(html: &str) -> String {
+    let mut output = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('>') {
+            Some(v) => v,
+            None => break,
+        };
+        let closing = rest[1..].starts_with('/');
+        let tag = rest[1..end]
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        rest = &rest[end + 1..];
+        match tag.as_str() {
+            "script" | "style" | "head" | "nav" | "footer" if !closing => {
+                let close = format!("</{tag}");
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(v) => &rest[v..],
+                    None => "",
+                };
+            }
+            "p" | "div" | "br" | "li" | "tr" | "pre" | "section" | "article" | "h1" | "h2"
+            | "h3" | "h4" | "h5" | "h6" => output.push_str("\n\n"),
+            _ => {}
+        }
+    }
+    output.push_str(rest);
+    let output = decode_entities(&output);
+    output
+        .split("\n\n")
+        .map(|v| v.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Targets of the `href` attributes of an html page, as written
+pub fn html_links(html: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + 5..];
+        let quote = match rest.chars().next() {
+            Some(c @ ('"' | '\'')) => c,
+            _ => continue,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(v) => v + 1,
+            None => break,
+        };
+        links.push(decode_entities(rest[1..end].trim()));
+        rest = &rest[end..];
+    }
+    links
+}
+
+fn blocks<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}");
+    let close = format!("</{tag}>");
+    let mut output = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        // Skip longer tags sharing the prefix, e.g. `<urlset` for `<url`
+        if !rest.starts_with(['>', ' ', '\n', '\t', '\r']) {
+            continue;
+        }
+        let end = match rest.find(&close) {
+            Some(v) => v,
+            None => break,
+        };
+        output.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    output
+}
+
+fn text(xml: &str, tag: &str) -> Option<String> {
+    let content = blocks(xml, tag).into_iter().next()?;
+    let content = &content[content.find('>')? + 1..];
+    let content = content
+        .trim()
+        .trim_start_matches("<![CDATA[")
+        .trim_end_matches("]]>")
+        .trim();
+    if content.is_empty() {
+        return None;
+    }
+    Some(decode_entities(content))
+}
+
+fn attribute(xml: &str, tag: &str, name: &str) -> Option<String> {
+    let open = format!("<{tag} ");
+    let start = xml.find(&open)? + open.len();
+    let element = &xml[start..start + xml[start..].find('>')?];
+    let key = format!("{name}=\"");
+    let value_start = element.find(&key)? + key.len();
+    let value = &element[value_start..value_start + element[value_start..].find('"')?];
+    Some(decode_entities(value))
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_feed() {
+        let sitemap = r#"<?xml version="1.0"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://docs.example.com/a</loc><lastmod>2023-05-01</lastmod></url>
+  <url><loc>https://docs.example.com/b?x=1&amp;y=2</loc></url>
+</urlset>"#;
+        assert_eq!(
+            parse_feed(sitemap),
+            vec![
+                Entry {
+                    url: "https://docs.example.com/a".into(),
+                    updated: Some("2023-05-01".into())
+                },
+                Entry {
+                    url: "https://docs.example.com/b?x=1&y=2".into(),
+                    updated: None
+                }
+            ]
+        );
+        let rss = "<rss><channel><link>https://blog.example.com</link>\
+            <item><title>A</title><link><![CDATA[https://blog.example.com/a]]></link>\
+            <pubDate>Mon, 01 May 2023 00:00:00 GMT</pubDate></item></channel></rss>";
+        assert_eq!(
+            parse_feed(rss),
+            vec![Entry {
+                url: "https://blog.example.com/a".into(),
+                updated: Some("Mon, 01 May 2023 00:00:00 GMT".into())
+            }]
+        );
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom"><entry>
+            <link rel="alternate" href="https://blog.example.com/b"/>
+            <updated>2023-05-02T00:00:00Z</updated></entry></feed>"#;
+        assert_eq!(
+            parse_feed(atom),
+            vec![Entry {
+                url: "https://blog.example.com/b".into(),
+                updated: Some("2023-05-02T00:00:00Z".into())
+            }]
+        );
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><title>T</title><style>p { color: red }</style></head>\
+            <body><h1>Title</h1><p>Some   <b>bold</b>\n text &amp; more.</p>\
+            <script>var a = '<p>';</script><p>Last</p></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Title\n\nSome bold text & more.\n\nLast"
+        );
+    }
+}
@@ -1,19 +1,24 @@
-use super::MarkdownRender;
+use super::{MarkdownRender, RenderOptions};
 
 use crate::print_now;
 use crate::repl::{ReplyStreamEvent, SharedAbortSignal};
 
 use anyhow::Result;
 use crossbeam::channel::Receiver;
+use std::time::Duration;
 
-pub fn cmd_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSignal) -> Result<()> {
+pub fn cmd_render_stream(
+    rx: Receiver<ReplyStreamEvent>,
+    abort: SharedAbortSignal,
+    options: RenderOptions,
+) -> Result<()> {
     let mut buffer = String::new();
-    let mut markdown_render = MarkdownRender::new();
+    let mut markdown_render = MarkdownRender::new().with_line_numbers(options.code_line_numbers);
     loop {
         if abort.aborted() {
             return Ok(());
         }
-        if let Ok(evt) = rx.try_recv() {
+        if let Ok(evt) = rx.recv_timeout(Duration::from_millis(100)) {
             match evt {
                 ReplyStreamEvent::Text(text) => {
                     if text.contains('\n') {
@@ -26,6 +26,8 @@ pub struct MarkdownRender {
     md_syntax: SyntaxReference,
     code_syntax: Option<SyntaxReference>,
     prev_line_type: LineType,
+    line_numbers: bool,
+    code_line: usize,
 }
 
 impl MarkdownRender {
@@ -43,9 +45,17 @@ impl MarkdownRender {
             md_syntax,
             code_syntax: None,
             prev_line_type: line_type,
+            line_numbers: false,
+            code_line: 0,
         }
     }
 
+    /// Number the lines inside code blocks
+    pub fn with_line_numbers(mut self, enabled: bool) -> Self {
+        self.line_numbers = enabled;
+        self
+    }
+
     pub fn render(&mut self, src: &str) -> String {
         src.split('\n')
             .map(|line| self.render_line(line).unwrap_or_else(|| line.to_string()))
@@ -55,7 +65,7 @@ impl MarkdownRender {
 
     pub fn render_line_stateless(&self, line: &str) -> String {
         let output = if self.is_code_block() && detect_code_block(line).is_none() {
-            self.render_code_line(line)
+            self.render_code_line(line, self.code_line + 1)
         } else {
             self.render_line_inner(line, &self.md_syntax)
         };
@@ -74,6 +84,7 @@ impl MarkdownRender {
             match self.prev_line_type {
                 LineType::Normal | LineType::CodeEnd => {
                     self.prev_line_type = LineType::CodeBegin;
+                    self.code_line = 0;
                     self.code_syntax = if lang.is_empty() {
                         None
                     } else {
@@ -100,9 +111,13 @@ impl MarkdownRender {
                         }
                     }
                     self.prev_line_type = LineType::CodeInner;
-                    self.render_code_line(line)
+                    self.code_line += 1;
+                    self.render_code_line(line, self.code_line)
+                }
+                LineType::CodeInner => {
+                    self.code_line += 1;
+                    self.render_code_line(line, self.code_line)
                 }
-                LineType::CodeInner => self.render_code_line(line),
             }
         }
     }
@@ -117,11 +132,18 @@ impl MarkdownRender {
         Some(format!("{ws}{}", as_terminal_escaped(&ranges)))
     }
 
-    fn render_code_line(&self, line: &str) -> Option<String> {
-        self.code_syntax
+    fn render_code_line(&self, line: &str, number: usize) -> Option<String> {
+        let output = self
+            .code_syntax
             .as_ref()
             .map(|syntax| self.render_line_inner(line, syntax))
-            .unwrap_or_else(|| Some(format!("{}", line.with(self.code_color))))
+            .unwrap_or_else(|| Some(format!("{}", line.with(self.code_color))))?;
+        if self.line_numbers {
+            let gutter = format!("{number:>3} │").with(Color::DarkGrey);
+            Some(format!("{gutter} {output}"))
+        } else {
+            Some(output)
+        }
     }
 
     fn find_syntax(&self, lang: &str) -> Option<&SyntaxReference> {
@@ -226,4 +248,14 @@ mod tests {
         let render = MarkdownRender::new();
         assert!(render.find_syntax("csharp").is_some());
     }
+
+    #[test]
+    fn test_render_line_numbers() {
+        let mut render = MarkdownRender::new().with_line_numbers(true);
+        let output = render.render("text\n```\nfoo\nbar\n```\ntext");
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert!(lines[2].contains("  1 │") && lines[2].contains("foo"));
+        assert!(lines[3].contains("  2 │"));
+        assert!(!lines[4].contains('│') && !lines[5].contains('│'));
+    }
 }
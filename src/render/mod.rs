@@ -15,22 +15,35 @@ use crossbeam::channel::unbounded;
 use crossbeam::sync::WaitGroup;
 use std::thread::spawn;
 
+/// Options shared by the stream renders
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    pub highlight: bool,
+    /// Number the lines inside code blocks
+    pub code_line_numbers: bool,
+    /// Maximum characters per second, only applied in repl
+    pub max_speed: Option<u32>,
+    /// Pause the reply at the first paragraph boundary
+    pub step: bool,
+}
+
+/// Render the reply as it streams, return it, whether it paused in step mode and whether it was cut off
 pub fn render_stream(
     input: &str,
     client: &ChatGptClient,
-    highlight: bool,
+    options: RenderOptions,
     repl: bool,
     abort: SharedAbortSignal,
     wg: WaitGroup,
-) -> Result<String> {
-    let mut stream_handler = if highlight {
+) -> Result<(String, bool, bool)> {
+    let stream_handler = if options.highlight {
         let (tx, rx) = unbounded();
         let abort_clone = abort.clone();
         spawn(move || {
             let err = if repl {
-                repl_render_stream(rx, abort)
+                repl_render_stream(rx, abort, options)
             } else {
-                cmd_render_stream(rx, abort)
+                cmd_render_stream(rx, abort, options)
             };
             if let Err(err) = err {
                 let err = format!("{err:?}");
@@ -43,7 +56,12 @@ pub fn render_stream(
         drop(wg);
         ReplyStreamHandler::new(None, repl, abort)
     };
+    let mut stream_handler = stream_handler.with_step(options.step);
     client.send_message_streaming(input, &mut stream_handler)?;
     let buffer = stream_handler.get_buffer();
-    Ok(buffer.to_string())
+    Ok((
+        buffer.to_string(),
+        stream_handler.paused(),
+        stream_handler.truncated(),
+    ))
 }
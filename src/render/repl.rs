@@ -1,4 +1,4 @@
-use super::MarkdownRender;
+use super::{MarkdownRender, RenderOptions};
 
 use crate::repl::{ReplyStreamEvent, SharedAbortSignal};
 
@@ -16,11 +16,17 @@ use std::{
 };
 use unicode_width::UnicodeWidthStr;
 
-pub fn repl_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSignal) -> Result<()> {
+const THROTTLE_INTERVAL: Duration = Duration::from_millis(10);
+
+pub fn repl_render_stream(
+    rx: Receiver<ReplyStreamEvent>,
+    abort: SharedAbortSignal,
+    options: RenderOptions,
+) -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
 
-    let ret = repl_render_stream_inner(rx, abort, &mut stdout);
+    let ret = repl_render_stream_inner(rx, abort, options, &mut stdout);
 
     disable_raw_mode()?;
 
@@ -30,63 +36,83 @@ pub fn repl_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSign
 fn repl_render_stream_inner(
     rx: Receiver<ReplyStreamEvent>,
     abort: SharedAbortSignal,
+    options: RenderOptions,
     writer: &mut Stdout,
 ) -> Result<()> {
     let mut last_tick = Instant::now();
     let tick_rate = Duration::from_millis(100);
     let mut buffer = String::new();
-    let mut markdown_render = MarkdownRender::new();
-    let terminal_columns = terminal::size()?.0;
+    let mut markdown_render = MarkdownRender::new().with_line_numbers(options.code_line_numbers);
+    let mut terminal_columns = terminal::size()?.0.max(1);
+    let mut throttle = Throttle::new(options.max_speed);
+    let mut pending = String::new();
+    let mut received_done = false;
     loop {
         if abort.aborted() {
             return Ok(());
         }
 
         if let Ok(evt) = rx.try_recv() {
+            let (text, done) = merge_events(evt, &rx);
+            pending.push_str(&text);
+            received_done = received_done || done;
+        }
+
+        let text = throttle.take(&mut pending);
+        let done = received_done && pending.is_empty();
+        if !text.is_empty() || done {
             recover_cursor(writer, terminal_columns, &buffer)?;
 
-            match evt {
-                ReplyStreamEvent::Text(text) => {
-                    if text.contains('\n') {
-                        let text = format!("{buffer}{text}");
-                        let mut lines: Vec<&str> = text.split('\n').collect();
-                        buffer = lines.pop().unwrap_or_default().to_string();
-                        let output = markdown_render.render(&lines.join("\n"));
-                        for line in output.split('\n') {
-                            queue!(
-                                writer,
-                                style::Print(line),
-                                style::Print("\n"),
-                                cursor::MoveLeft(terminal_columns),
-                            )?;
-                        }
+            if !text.is_empty() {
+                if text.contains('\n') {
+                    let text = format!("{buffer}{text}");
+                    let mut lines: Vec<&str> = text.split('\n').collect();
+                    buffer = lines.pop().unwrap_or_default().to_string();
+                    let output = markdown_render.render(&lines.join("\n"));
+                    for line in output.split('\n') {
+                        queue!(
+                            writer,
+                            style::Print(line),
+                            style::Print("\n"),
+                            cursor::MoveLeft(terminal_columns),
+                        )?;
+                    }
+                    if !done {
                         queue!(writer, style::Print(&buffer),)?;
-                    } else {
-                        buffer = format!("{buffer}{text}");
+                    }
+                } else {
+                    buffer = format!("{buffer}{text}");
+                    if !done {
                         let output = markdown_render.render_line_stateless(&buffer);
                         queue!(writer, style::Print(&output))?;
                     }
-                    writer.flush()?;
                 }
-                ReplyStreamEvent::Done => {
-                    let output = markdown_render.render_line_stateless(&buffer);
-                    queue!(writer, style::Print(output.trim_end()), style::Print("\n"))?;
-                    if cfg!(windows) {
-                        queue!(writer, style::Print("\n"))?;
-                    }
-                    writer.flush()?;
-                    break;
+                writer.flush()?;
+            }
+            if done {
+                let output = markdown_render.render_line_stateless(&buffer);
+                queue!(writer, style::Print(output.trim_end()), style::Print("\n"))?;
+                if cfg!(windows) {
+                    queue!(writer, style::Print("\n"))?;
                 }
+                writer.flush()?;
+                break;
+            }
+            if !throttle.enabled() {
+                continue;
             }
-            continue;
         }
 
-        let timeout = tick_rate
-            .checked_sub(last_tick.elapsed())
-            .unwrap_or_else(|| Duration::from_secs(0));
+        let timeout = if !pending.is_empty() {
+            THROTTLE_INTERVAL
+        } else {
+            tick_rate
+                .checked_sub(last_tick.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0))
+        };
         if crossterm::event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
+            match event::read()? {
+                Event::Key(key) => match key.code {
                     KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => {
                         abort.set_ctrlc();
                         return Ok(());
@@ -95,8 +121,20 @@ fn repl_render_stream_inner(
                         abort.set_ctrld();
                         return Ok(());
                     }
+                    KeyCode::Char('f') if key.modifiers == KeyModifiers::CONTROL => {
+                        throttle.fast_forward();
+                    }
                     _ => {}
+                },
+                Event::Resize(columns, _) => {
+                    // Redraw the unfinished line so it wraps at the new width
+                    terminal_columns = columns.max(1);
+                    recover_cursor(writer, terminal_columns, &buffer)?;
+                    let output = markdown_render.render_line_stateless(&buffer);
+                    queue!(writer, style::Print(&output))?;
+                    writer.flush()?;
                 }
+                _ => {}
             }
         }
 
@@ -107,6 +145,69 @@ fn repl_render_stream_inner(
     Ok(())
 }
 
+/// Limit how many characters are rendered per second
+struct Throttle {
+    max_speed: Option<u32>,
+    last_take: Instant,
+}
+
+impl Throttle {
+    fn new(max_speed: Option<u32>) -> Self {
+        Self {
+            max_speed,
+            last_take: Instant::now(),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_speed.is_some()
+    }
+
+    fn fast_forward(&mut self) {
+        self.max_speed = None;
+    }
+
+    /// Take the characters allowed to render since the last take
+    fn take(&mut self, pending: &mut String) -> String {
+        let max_speed = match self.max_speed {
+            Some(v) => v,
+            None => return std::mem::take(pending),
+        };
+        if pending.is_empty() {
+            self.last_take = Instant::now();
+            return String::new();
+        }
+        let count = (self.last_take.elapsed().as_secs_f64() * max_speed as f64) as usize;
+        if count == 0 {
+            return String::new();
+        }
+        self.last_take = Instant::now();
+        let index = pending
+            .char_indices()
+            .nth(count)
+            .map(|(i, _)| i)
+            .unwrap_or(pending.len());
+        let remain = pending.split_off(index);
+        std::mem::replace(pending, remain)
+    }
+}
+
+/// Merge all pending text events, so a burst of chunks is highlighted only once
+fn merge_events(evt: ReplyStreamEvent, rx: &Receiver<ReplyStreamEvent>) -> (String, bool) {
+    let mut text = String::new();
+    let mut evt = evt;
+    loop {
+        match evt {
+            ReplyStreamEvent::Text(chunk) => text.push_str(&chunk),
+            ReplyStreamEvent::Done => return (text, true),
+        }
+        match rx.try_recv() {
+            Ok(next) => evt = next,
+            Err(_) => return (text, false),
+        }
+    }
+}
+
 fn recover_cursor(writer: &mut Stdout, terminal_columns: u16, buffer: &str) -> Result<()> {
     let buffer_rows = (buffer.width() as u16 + terminal_columns - 1) / terminal_columns;
     let (_, row) = cursor::position()?;
@@ -121,5 +222,7 @@ fn recover_cursor(writer: &mut Stdout, terminal_columns: u16, buffer: &str) -> R
             cursor::MoveTo(0, 0)
         )?;
     }
+    // Erase fragments left behind when the line was wrapped at another width
+    queue!(writer, terminal::Clear(terminal::ClearType::FromCursorDown))?;
     Ok(())
 }
@@ -1,31 +1,88 @@
 use crate::client::ChatGptClient;
-use crate::config::SharedConfig;
+use crate::clipboard::copy_text;
+use crate::config::{CompactMode, Config, SharedConfig};
 use crate::print_now;
+use crate::rag::{retrieve, Rag, WeightedRag};
 use crate::render::render_stream;
+use crate::shell;
+use crate::utils::{
+    count_tokens, edit_text, extract_code_blocks, now, parse_directives, quote_text,
+};
 
 use super::abort::SharedAbortSignal;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use crossbeam::channel::Sender;
 use crossbeam::sync::WaitGroup;
+use inquire::{Confirm, Text};
+use reqwest::Url;
 use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+const STEP_CONTINUE_PROMPT: &str = "Continue from where you stopped.";
+const RESUME_PROMPT: &str = "Your reply to the message above was cut off after the text below. \
+Continue it from exactly where it stopped, without repeating anything.";
+const COMPACT_PROMPT: &str = "Summarize the conversation below in a few sentences. \
+Keep the facts, decisions and open questions a follow-up would need. Reply with the summary only.";
+const REWRITE_PROMPT: &str = "Rewrite the follow-up below into a standalone search query, \
+resolving references to the conversation. Reply with the query only.";
+const REWRITE_HISTORY_MESSAGES: usize = 4;
+const GROUNDING_PROMPT: &str = "Check the answer below against the context. \
+List every claim of the answer that the context does not support, or say that all claims are supported. \
+End with a line `Confidence: high`, `Confidence: medium` or `Confidence: low`.";
 
 pub enum ReplCmd {
     Submit(String),
+    SubmitFiles(Vec<String>, String),
     SetRole(String),
+    TestRole(String, String),
+    CreateRole(String),
+    EditRole(String),
+    DeleteRole(String),
+    RoleInfo(Option<String>),
     UpdateConfig(String),
     Prompt(String),
     ClearRole,
+    SetModel(String),
+    Shell(String),
+    ListModels,
     ViewInfo,
+    ViewTokens,
+    ViewUsage(Option<String>),
+    SearchHistory(Option<String>),
+    Preview(Vec<String>, String),
+    Payload(Vec<String>, String),
     StartConversation,
     EndConversatoin,
+    SaveSession(String),
+    LoadSession(String),
+    ListSessions,
+    BuildRag(String, String),
+    AddRag(String, Vec<String>),
+    UseRag(String),
+    AttachRag(String, f32),
+    DetachRag(String),
+    RebuildRag(Option<String>),
+    ListRags,
+    ClearRag,
+    StartTranscript(String),
+    EndTranscript,
+    Continue,
+    Copy(Option<usize>),
+    Compact,
+    Undo,
+    Fork(String),
 }
 
 pub struct ReplCmdHandler {
     client: ChatGptClient,
     config: SharedConfig,
     reply: RefCell<String>,
+    /// Input of the last reply if the connection dropped before it ended
+    truncated: RefCell<Option<String>>,
     abort: SharedAbortSignal,
+    transcript: RefCell<Option<File>>,
 }
 
 impl ReplCmdHandler {
@@ -39,7 +96,9 @@ impl ReplCmdHandler {
             client,
             config,
             reply,
+            truncated: RefCell::new(None),
             abort,
+            transcript: RefCell::new(None),
         })
     }
 
@@ -50,30 +109,107 @@ impl ReplCmdHandler {
                     self.reply.borrow_mut().clear();
                     return Ok(());
                 }
-                let highlight = self.config.lock().highlight;
-                let wg = WaitGroup::new();
-                let ret = render_stream(
-                    &input,
-                    &self.client,
-                    highlight,
-                    true,
-                    self.abort.clone(),
-                    wg.clone(),
-                );
-                wg.wait();
-                let buffer = ret?;
-                self.config.lock().save_message(&input, &buffer)?;
-                self.config.lock().save_conversation(&input, &buffer)?;
-                *self.reply.borrow_mut() = buffer;
+                let (input, directives) = parse_directives(&input)?;
+                if let Some(model) = &directives.model {
+                    self.config.lock().find_client(model)?;
+                }
+                let context = self.retrieve(&input)?;
+                {
+                    let mut config = self.config.lock();
+                    config.message_temperature = directives.temperature;
+                    config.message_model = directives.model;
+                    config.rag_context = context;
+                }
+                let ret = self.submit(input);
+                {
+                    let mut config = self.config.lock();
+                    config.message_temperature = None;
+                    config.message_model = None;
+                    config.rag_context = None;
+                }
+                ret?;
+            }
+            ReplCmd::SubmitFiles(files, input) => {
+                let attachments = self.config.lock().load_attachments(&files)?;
+                let input = attachments.input(&input);
+                self.config.lock().attachments = attachments.images;
+                let ret = self.handle(ReplCmd::Submit(input));
+                self.config.lock().attachments.clear();
+                ret?;
             }
             ReplCmd::SetRole(name) => {
                 let output = self.config.lock().change_role(&name)?;
                 print_now!("{}\n\n", output.trim_end());
             }
+            ReplCmd::TestRole(name, input) => {
+                let role = {
+                    let config = self.config.lock();
+                    let mut role = config.resolve_role(&name)?;
+                    config.fill_role_variables(&mut role)?;
+                    role
+                };
+                let (role, conversation) = {
+                    let mut config = self.config.lock();
+                    (config.role.replace(role), config.conversation.take())
+                };
+                let tokens = self.config.lock().count_prompt_tokens(&input);
+                let ret = tokens.and_then(|tokens| {
+                    let model = self.config.lock().client_config()?.id();
+                    Ok((model, tokens, self.render(&input)?.0))
+                });
+                {
+                    let mut config = self.config.lock();
+                    config.role = role;
+                    config.conversation = conversation;
+                }
+                let (model, prompt_tokens, reply) = ret?;
+                let reply_tokens = count_tokens(&reply);
+                let cost = self
+                    .config
+                    .lock()
+                    .estimate_cost(&model, prompt_tokens, reply_tokens);
+                print_now!(
+                    "{prompt_tokens} prompt tokens, {reply_tokens} reply tokens, about ${cost:.4}\n\n"
+                );
+            }
+            ReplCmd::CreateRole(name) => {
+                let template = self.config.lock().new_role_template(&name)?;
+                self.edit_role(None, template)?;
+            }
+            ReplCmd::EditRole(name) => {
+                let definition = self.config.lock().role_definition(&name)?;
+                self.edit_role(Some(&name), definition)?;
+            }
+            ReplCmd::DeleteRole(name) => {
+                self.config.lock().role_definition(&name)?;
+                let ans = Confirm::new(&format!("Delete the role '{name}'?"))
+                    .with_default(false)
+                    .prompt()?;
+                if ans {
+                    self.config.lock().delete_role(&name)?;
+                }
+                print_now!("\n");
+            }
+            ReplCmd::RoleInfo(name) => {
+                let output = self.config.lock().role_info(name.as_deref())?;
+                print_now!("{}\n\n", output.trim_end());
+            }
             ReplCmd::ClearRole => {
                 self.config.lock().role = None;
                 print_now!("\n");
             }
+            ReplCmd::Shell(task) => {
+                shell::execute(&self.client, &self.config, &task)?;
+                print_now!("\n");
+            }
+            ReplCmd::SetModel(name) => {
+                self.config.lock().set_model(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::ListModels => {
+                let output = self.config.lock().list_models();
+                print_now!("{}\n\n", output.trim_end());
+            }
             ReplCmd::Prompt(prompt) => {
                 self.config.lock().create_temp_role(&prompt)?;
                 print_now!("\n");
@@ -82,6 +218,30 @@ impl ReplCmdHandler {
                 let output = self.config.lock().info()?;
                 print_now!("{}\n\n", output.trim_end());
             }
+            ReplCmd::ViewTokens => {
+                let output = self.config.lock().tokens_info()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::SearchHistory(term) => {
+                let output = self.config.lock().history_info(term.as_deref())?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::ViewUsage(month) => {
+                let output = self.config.lock().usage_info(month.as_deref())?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::Preview(files, input) => {
+                let output = self.with_message_context(&files, &input, |input| {
+                    Ok(self.config.lock().preview(input))
+                })?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::Payload(files, input) => {
+                let payload = self.with_message_context(&files, &input, |input| {
+                    self.client.build_payload(input, true)
+                })?;
+                print_now!("{}\n\n", serde_json::to_string_pretty(&payload)?);
+            }
             ReplCmd::UpdateConfig(input) => {
                 self.config.lock().update(&input)?;
                 print_now!("\n");
@@ -94,6 +254,416 @@ impl ReplCmdHandler {
                 self.config.lock().end_conversation();
                 print_now!("\n");
             }
+            ReplCmd::SaveSession(name) => {
+                let output = self.config.lock().save_session(&name)?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::LoadSession(name) => {
+                self.config.lock().load_session(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::ListSessions => {
+                let output = self.config.lock().list_sessions()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::BuildRag(name, path) => {
+                let client_config = self.config.lock().embedding_client_config()?;
+                let mut rag = Rag::build(&name, &path, None, &self.client, &client_config)?;
+                if rag.chunks.is_empty() {
+                    bail!("Error: Nothing to index in {path}");
+                }
+                rag.save(&Config::rags_dir()?)?;
+                print_now!("Built rag '{name}' with {} chunks\n\n", rag.chunks.len());
+                self.config.lock().rags = vec![WeightedRag { rag, weight: 1.0 }];
+            }
+            ReplCmd::AddRag(url, options) => {
+                let (client_config, mut crawl) = {
+                    let config = self.config.lock();
+                    (config.embedding_client_config()?, config.rag_crawl.clone())
+                };
+                for option in &options {
+                    crawl.set(option)?;
+                }
+                let name = match Url::parse(&url)
+                    .ok()
+                    .and_then(|v| v.host_str().map(|v| v.to_string()))
+                {
+                    Some(host) if url.starts_with("http://") || url.starts_with("https://") => host,
+                    _ => bail!("Error: Invalid url {url}"),
+                };
+                let mut rag = Rag::build(&name, &url, Some(crawl), &self.client, &client_config)?;
+                if rag.chunks.is_empty() {
+                    bail!("Error: Nothing to index in {url}");
+                }
+                rag.save(&Config::rags_dir()?)?;
+                print_now!("Built rag '{name}' with {} chunks\n\n", rag.chunks.len());
+                let mut config = self.config.lock();
+                if config.rags.iter().any(|v| v.rag.name == name) {
+                    config.replace_rag(rag);
+                } else {
+                    config.rags.push(WeightedRag { rag, weight: 1.0 });
+                }
+            }
+            ReplCmd::RebuildRag(name) => {
+                let rags = match name {
+                    Some(name) => vec![Rag::load(&Config::rags_dir()?, &name)?],
+                    None => {
+                        let config = self.config.lock();
+                        config.rags.iter().map(|v| v.rag.clone()).collect()
+                    }
+                };
+                if rags.is_empty() {
+                    bail!("Error: No rag in use");
+                }
+                for mut rag in rags {
+                    let client_config = self.config.lock().find_client(&rag.client)?;
+                    let output = rag.rebuild(&self.client, &client_config)?;
+                    rag.save(&Config::rags_dir()?)?;
+                    print_now!("{}: {output}\n", rag.name);
+                    self.config.lock().replace_rag(rag);
+                }
+                print_now!("\n");
+            }
+            ReplCmd::UseRag(name) => {
+                self.config.lock().use_rag(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::AttachRag(name, weight) => {
+                self.config.lock().attach_rag(&name, weight)?;
+                print_now!("\n");
+            }
+            ReplCmd::DetachRag(name) => {
+                self.config.lock().detach_rag(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::ListRags => {
+                let output = self.config.lock().list_rags()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::ClearRag => {
+                self.config.lock().rags.clear();
+                print_now!("\n");
+            }
+            ReplCmd::StartTranscript(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to create/append {path}"))?;
+                *self.transcript.borrow_mut() = Some(file);
+                print_now!("\n");
+            }
+            ReplCmd::EndTranscript => {
+                *self.transcript.borrow_mut() = None;
+                print_now!("\n");
+            }
+            ReplCmd::Copy(index) => {
+                let reply = self.reply.borrow().clone();
+                if reply.is_empty() {
+                    bail!("Error: No reply to copy");
+                }
+                let text = match index {
+                    Some(n) => extract_code_blocks(&reply)
+                        .into_iter()
+                        .nth(n.saturating_sub(1))
+                        .ok_or_else(|| anyhow!("Error: No code block #{n} in the last reply"))?,
+                    None => reply,
+                };
+                copy_text(&text)?;
+                print_now!("Copied to the clipboard\n\n");
+            }
+            ReplCmd::Continue => {
+                let input = match self.truncated.borrow_mut().take() {
+                    Some(v) => v,
+                    None => bail!("Error: The last reply was not cut off"),
+                };
+                let partial = self.reply.borrow().clone();
+                // A conversation already holds the partial reply
+                let input = if self.config.lock().conversation.is_some() {
+                    STEP_CONTINUE_PROMPT.into()
+                } else {
+                    format!("{input}\n\n{RESUME_PROMPT}\n\n{partial}")
+                };
+                self.submit(input)?;
+                let reply = format!("{partial}{}", self.reply.borrow());
+                *self.reply.borrow_mut() = reply;
+            }
+            ReplCmd::Compact => self.compact()?,
+            ReplCmd::Undo => {
+                self.config.lock().undo_conversation()?;
+                self.truncated.borrow_mut().take();
+                print_now!("Dropped the last exchange\n\n");
+            }
+            ReplCmd::Fork(name) => {
+                let output = self.config.lock().fork_session(&name)?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+        }
+        Ok(())
+    }
+
+    fn submit(&self, input: String) -> Result<()> {
+        self.truncated.borrow_mut().take();
+        let mut input = self.config.lock().smart_paste(input)?;
+        if self.config.lock().needs_compact(&input) {
+            self.compact()?;
+        }
+        self.config.lock().confirm_cost(&input)?;
+        let mut reply = String::new();
+        loop {
+            self.write_transcript(&format!("# USER [{}]\n{input}\n\n", now()))?;
+            let (buffer, paused, truncated) = self.render(&input)?;
+            self.write_transcript(&format!("# ASSISTANT [{}]\n{buffer}\n\n", now()))?;
+            self.config.lock().save_message(&input, &buffer)?;
+            self.config.lock().save_conversation(&input, &buffer)?;
+            reply.push_str(&buffer);
+            if truncated {
+                print_now!("The reply was cut off, type `.continue` to resume\n\n");
+                *self.truncated.borrow_mut() = Some(input);
+                break;
+            }
+            if !paused {
+                break;
+            }
+            // Step mode, wait for the user before generating the next paragraph
+            let feedback = Text::new("Press Enter to continue, or give feedback:")
+                .prompt()
+                .unwrap_or_default();
+            if self.abort.aborted() {
+                break;
+            }
+            input = if feedback.trim().is_empty() {
+                STEP_CONTINUE_PROMPT.into()
+            } else {
+                feedback
+            };
+            reply.push_str("\n\n");
+        }
+        self.check_grounding(&reply)?;
+        *self.reply.borrow_mut() = reply;
+        Ok(())
+    }
+
+    /// Run with the files attached and the chunks retrieved, as the input would be sent
+    fn with_message_context<T>(
+        &self,
+        files: &[String],
+        input: &str,
+        f: impl FnOnce(&str) -> Result<T>,
+    ) -> Result<T> {
+        let attachments = self.config.lock().load_attachments(files)?;
+        let (input, directives) = parse_directives(input)?;
+        let input = attachments.input(&input);
+        if let Some(model) = &directives.model {
+            self.config.lock().find_client(model)?;
+        }
+        let context = if input.is_empty() {
+            None
+        } else {
+            self.retrieve(&input)?
+        };
+        {
+            let mut config = self.config.lock();
+            config.message_temperature = directives.temperature;
+            config.message_model = directives.model;
+            config.rag_context = context;
+            config.attachments = attachments.images;
+        }
+        let ret = f(&input);
+        let mut config = self.config.lock();
+        config.message_temperature = None;
+        config.message_model = None;
+        config.rag_context = None;
+        config.attachments.clear();
+        ret
+    }
+
+    /// Ask the model which claims of the reply the retrieved chunks do not support
+    fn check_grounding(&self, reply: &str) -> Result<()> {
+        let context = {
+            let config = self.config.lock();
+            match config.rag_context.as_ref() {
+                Some(context) if config.rag_grounding_check && !reply.is_empty() => context.clone(),
+                _ => return Ok(()),
+            }
+        };
+        let prompt = format!(
+            "{GROUNDING_PROMPT}\n\n<context>\n{context}\n</context>\n\n<answer>\n{reply}\n</answer>"
+        );
+        let ret = self.send_standalone(&prompt, None);
+        print_now!("Grounding check:\n{}\n\n", ret?.trim());
+        Ok(())
+    }
+
+    /// Edit a role definition until it saves or the user gives up
+    fn edit_role(&self, old_name: Option<&str>, mut definition: String) -> Result<()> {
+        loop {
+            definition = edit_text(&definition, "yaml")?;
+            let ret = self.config.lock().save_role(old_name, &definition);
+            match ret {
+                Ok(name) => {
+                    print_now!("Saved role '{name}'\n\n");
+                    return Ok(());
+                }
+                Err(err) => {
+                    print_now!("{err:#}\n");
+                    let ans = Confirm::new("Edit again?").with_default(true).prompt()?;
+                    if !ans {
+                        print_now!("\n");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the older messages of the conversation by a summary, or drop them
+    fn compact(&self) -> Result<()> {
+        let (mode, text, model) = {
+            let config = self.config.lock();
+            let Some(conversation) = config.conversation.as_ref() else {
+                bail!("Error: Not in a conversation");
+            };
+            (
+                config.compact,
+                conversation.compactable_text(),
+                config.summary_model.clone(),
+            )
+        };
+        let summary = match (mode, text) {
+            (CompactMode::Summarize, Some(text)) => {
+                let prompt = format!("{COMPACT_PROMPT}\n\n{text}");
+                Some(self.send_standalone(&prompt, model)?)
+            }
+            _ => None,
+        };
+        let (before, after) = self
+            .config
+            .lock()
+            .compact_conversation(summary.as_deref().map(str::trim))?;
+        print_now!("Compacted the conversation from {before} to {after} tokens\n\n");
+        Ok(())
+    }
+
+    /// Send a message outside of the current role, conversation, rag and attachments, with the
+    /// model if given
+    fn send_standalone(&self, prompt: &str, model: Option<String>) -> Result<String> {
+        let (role, conversation, rag_context, attachments, current_model) = {
+            let mut config = self.config.lock();
+            let current_model = config.model.clone();
+            if model.is_some() {
+                config.model = model;
+            }
+            (
+                config.role.take(),
+                config.conversation.take(),
+                config.rag_context.take(),
+                std::mem::take(&mut config.attachments),
+                current_model,
+            )
+        };
+        let ret = self.client.send_helper_message(prompt);
+        {
+            let mut config = self.config.lock();
+            config.role = role;
+            config.conversation = conversation;
+            config.rag_context = rag_context;
+            config.attachments = attachments;
+            config.model = current_model;
+        }
+        ret
+    }
+
+    fn render(&self, input: &str) -> Result<(String, bool, bool)> {
+        let options = self.config.lock().render_options();
+        let wg = WaitGroup::new();
+        let ret = render_stream(
+            input,
+            &self.client,
+            options,
+            true,
+            self.abort.clone(),
+            wg.clone(),
+        );
+        wg.wait();
+        ret
+    }
+
+    /// Retrieve the chunks of the rags in use relevant to the input, the query embedded once
+    /// per client of the rags
+    fn retrieve(&self, input: &str) -> Result<Option<String>> {
+        let mut clients = vec![];
+        {
+            let config = self.config.lock();
+            for WeightedRag { rag, .. } in &config.rags {
+                if !clients.iter().any(|(name, _)| *name == rag.client) {
+                    clients.push((rag.client.clone(), config.find_client(&rag.client)?));
+                }
+            }
+        }
+        if clients.is_empty() {
+            return Ok(None);
+        }
+        let query = self.rewrite_query(input)?;
+        let mut vectors = vec![];
+        for (name, client_config) in clients {
+            let vector = self
+                .client
+                .embed(&client_config, std::slice::from_ref(&query))?
+                .pop()
+                .unwrap_or_default();
+            vectors.push((name, vector));
+        }
+        let config = self.config.lock();
+        let context = retrieve(&config.rags, &vectors, config.rag_max_tokens);
+        Ok(Some(context).filter(|v| !v.is_empty()))
+    }
+
+    /// Turn a follow-up into a standalone query with the recent messages of the conversation
+    fn rewrite_query(&self, input: &str) -> Result<String> {
+        let history = match self.config.lock().conversation.as_ref() {
+            Some(conversation) if !conversation.messages.is_empty() => {
+                conversation.recent_history(REWRITE_HISTORY_MESSAGES)
+            }
+            _ => return Ok(input.to_string()),
+        };
+        let prompt = format!(
+            "{REWRITE_PROMPT}\n\n<conversation>\n{history}\n</conversation>\n\nFollow-up: {input}"
+        );
+        let query = self.send_standalone(&prompt, None)?;
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(input.to_string());
+        }
+        Ok(query.to_string())
+    }
+
+    pub fn get_reply(&self) -> String {
+        self.reply.borrow().to_string()
+    }
+
+    pub fn get_quote(&self, index: Option<usize>) -> Result<String> {
+        let text = match index {
+            Some(index) => self.config.lock().get_message(index)?,
+            None => self.get_reply(),
+        };
+        if text.is_empty() {
+            bail!("Error: No reply to quote");
+        }
+        Ok(format!("{}\n\n", quote_text(&text)))
+    }
+
+    /// Print a saved chat and return its reply quoted, to put it back into the context
+    pub fn show_chat(&self, index: usize) -> Result<String> {
+        let (text, output) = self.config.lock().get_chat(index)?;
+        print_now!("{}\n\n", text.trim_end());
+        Ok(format!("{}\n\n", quote_text(&output)))
+    }
+
+    fn write_transcript(&self, text: &str) -> Result<()> {
+        if let Some(file) = self.transcript.borrow_mut().as_mut() {
+            file.write_all(text.as_bytes())
+                .with_context(|| "Failed to write transcript")?;
         }
         Ok(())
     }
@@ -104,6 +674,9 @@ pub struct ReplyStreamHandler {
     buffer: String,
     abort: SharedAbortSignal,
     repl: bool,
+    step: bool,
+    paused: bool,
+    truncated: bool,
 }
 
 impl ReplyStreamHandler {
@@ -117,10 +690,26 @@ impl ReplyStreamHandler {
             abort,
             buffer: String::new(),
             repl,
+            step: false,
+            paused: false,
+            truncated: false,
         }
     }
 
+    /// Pause the reply at the first paragraph boundary
+    pub fn with_step(mut self, step: bool) -> Self {
+        self.step = step;
+        self
+    }
+
     pub fn text(&mut self, text: &str) -> Result<()> {
+        let text = match self.paragraph_end(text) {
+            Some(index) => {
+                self.paused = true;
+                &text[..index]
+            }
+            None => text,
+        };
         match self.sender.as_ref() {
             Some(tx) => {
                 let ret = tx
@@ -163,6 +752,35 @@ impl ReplyStreamHandler {
         &self.buffer
     }
 
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Mark the reply as cut off by a dropped connection
+    pub fn truncate(&mut self) {
+        self.truncated = true;
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Find where the first paragraph outside of code blocks ends in the text
+    fn paragraph_end(&self, text: &str) -> Option<usize> {
+        if !self.step || self.paused {
+            return None;
+        }
+        let content = format!("{}{text}", self.buffer);
+        // From the last char of the buffer, a break may begin there
+        let start = self.buffer.char_indices().last().map_or(0, |(i, _)| i);
+        let index = content[start..].find("\n\n")? + start;
+        let paragraph = &content[..index];
+        if paragraph.trim().is_empty() || paragraph.matches("```").count() % 2 == 1 {
+            return None;
+        }
+        Some(index.saturating_sub(self.buffer.len()))
+    }
+
     pub fn get_abort(&self) -> SharedAbortSignal {
         self.abort.clone()
     }
@@ -179,3 +797,50 @@ pub enum ReplyStreamEvent {
     Text(String),
     Done,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repl::AbortSignal;
+    use parking_lot::Mutex;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_step_pause() {
+        let mut handler = ReplyStreamHandler::new(None, false, AbortSignal::new()).with_step(true);
+        for chunk in ["```\ncode\n\n", "```\nfirst", " paragraph\n", "\nsecond"] {
+            handler.text(chunk).unwrap();
+        }
+        assert!(handler.paused());
+        assert_eq!(handler.get_buffer(), "```\ncode\n\n```\nfirst paragraph\n");
+    }
+
+    #[test]
+    fn test_step_pause_multibyte() {
+        let mut handler = ReplyStreamHandler::new(None, false, AbortSignal::new()).with_step(true);
+        for chunk in ["Voilà", " café 🎉", "\n", "\n日本語"] {
+            handler.text(chunk).unwrap();
+        }
+        assert!(handler.paused());
+        assert_eq!(handler.get_buffer(), "Voilà café 🎉\n");
+    }
+
+    #[test]
+    fn test_role_test_releases_config() {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let content =
+                "clients:\n- type: openai\n  api_key: sk-xxx\nhighlight: false\ndry_run: true\n";
+            let mut config: Config = serde_yaml::from_str(content).unwrap();
+            config.roles = serde_yaml::from_str("- name: test\n  prompt: Be concise.\n").unwrap();
+            let config = Arc::new(Mutex::new(config));
+            let client = ChatGptClient::init(config.clone()).unwrap();
+            let handler = ReplCmdHandler::init(client, config, AbortSignal::new()).unwrap();
+            let ret = handler.handle(ReplCmd::TestRole("test".into(), "hi".into()));
+            tx.send(ret.is_ok()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(10)).unwrap());
+    }
+}
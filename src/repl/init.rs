@@ -1,14 +1,17 @@
 use super::REPL_COMMANDS;
 
-use crate::config::{Config, SharedConfig};
+use crate::config::{Config, EditMode as EditModeKind, SharedConfig};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use reedline::{
-    default_emacs_keybindings, ColumnarMenu, DefaultCompleter, Emacs, FileBackedHistory, KeyCode,
-    KeyModifiers, Keybindings, Prompt, PromptHistorySearch, PromptHistorySearchStatus, Reedline,
-    ReedlineEvent, ReedlineMenu, ValidationResult, Validator,
+    default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
+    ColumnarMenu, DefaultCompleter, EditCommand, EditMode, Emacs, FileBackedHistory, KeyCode,
+    KeyModifiers, Keybindings, Prompt, PromptEditMode, PromptHistorySearch,
+    PromptHistorySearchStatus, PromptViMode, Reedline, ReedlineEvent, ReedlineMenu,
+    ValidationResult, Validator, Vi,
 };
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 const MENU_NAME: &str = "completion_menu";
 const DEFAULT_MULTILINE_INDICATOR: &str = "::: ";
@@ -26,10 +29,12 @@ impl Repl {
             .map(|(v, _, _)| *v)
             .collect();
         let completer = Self::create_completer(config.clone());
-        let keybindings = Self::create_keybindings();
         let history = Self::create_history()?;
         let menu = Self::create_menu();
-        let edit_mode = Box::new(Emacs::new(keybindings));
+        let edit_mode = {
+            let config = config.lock();
+            Self::create_edit_mode(config.edit_mode, &config.keybindings)?
+        };
         let editor = Reedline::create()
             .with_completer(Box::new(completer))
             .with_history(history)
@@ -43,6 +48,13 @@ impl Repl {
         Ok(Self { editor, prompt })
     }
 
+    /// Pick up role names and other completions that changed since the start
+    pub fn reload_completer(&mut self) {
+        let completer = Self::create_completer(self.prompt.0.clone());
+        let editor = std::mem::replace(&mut self.editor, Reedline::create());
+        self.editor = editor.with_completer(Box::new(completer));
+    }
+
     fn create_completer(config: SharedConfig) -> DefaultCompleter {
         let mut completion: Vec<String> = REPL_COMMANDS
             .into_iter()
@@ -54,8 +66,39 @@ impl Repl {
         completer
     }
 
-    fn create_keybindings() -> Keybindings {
-        let mut keybindings = default_emacs_keybindings();
+    fn create_edit_mode(
+        mode: EditModeKind,
+        custom: &BTreeMap<String, String>,
+    ) -> Result<Box<dyn EditMode>> {
+        let mut bindings = vec![];
+        for (key, action) in custom {
+            let (modifiers, code) = parse_key(key)?;
+            let event = parse_action(action)
+                .with_context(|| format!("Invalid action for `{key}` in `keybindings`"))?;
+            bindings.push((modifiers, code, event));
+        }
+        let add_keybindings = |keybindings: &mut Keybindings| {
+            Self::add_keybindings(keybindings);
+            for (modifiers, code, event) in &bindings {
+                keybindings.add_binding(*modifiers, *code, event.clone());
+            }
+        };
+        let edit_mode: Box<dyn EditMode> = match mode {
+            EditModeKind::Emacs => {
+                let mut keybindings = default_emacs_keybindings();
+                add_keybindings(&mut keybindings);
+                Box::new(Emacs::new(keybindings))
+            }
+            EditModeKind::Vi => {
+                let mut insert_keybindings = default_vi_insert_keybindings();
+                add_keybindings(&mut insert_keybindings);
+                Box::new(Vi::new(insert_keybindings, default_vi_normal_keybindings()))
+            }
+        };
+        Ok(edit_mode)
+    }
+
+    fn add_keybindings(keybindings: &mut Keybindings) {
         keybindings.add_binding(
             KeyModifiers::NONE,
             KeyCode::Tab,
@@ -69,7 +112,21 @@ impl Repl {
             KeyCode::Char('l'),
             ReedlineEvent::ExecuteHostCommand(".clear screen".into()),
         );
-        keybindings
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('g'),
+            ReedlineEvent::ExecuteHostCommand(".session last".into()),
+        );
+        keybindings.add_binding(
+            KeyModifiers::ALT,
+            KeyCode::Char('.'),
+            ReedlineEvent::ExecuteHostCommand(".reply".into()),
+        );
+        keybindings.add_binding(
+            KeyModifiers::ALT,
+            KeyCode::Char(','),
+            ReedlineEvent::ExecuteHostCommand(".reply code".into()),
+        );
     }
 
     fn create_menu() -> ReedlineMenu {
@@ -85,6 +142,98 @@ impl Repl {
     }
 }
 
+/// Key such as `ctrl-o`, `alt-enter`, `ctrl-alt-x` or `f2`
+fn parse_key(key: &str) -> Result<(KeyModifiers, KeyCode)> {
+    let invalid = || {
+        anyhow!("Invalid key `{key}` in `keybindings`, expected e.g. `ctrl-o`, `alt-enter` or `f2`")
+    };
+    let mut modifiers = KeyModifiers::NONE;
+    let mut parts: Vec<&str> = key.split('-').collect();
+    let name = parts.pop().ok_or_else(invalid)?;
+    for modifier in parts {
+        modifiers |= match modifier.to_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return Err(invalid()),
+        };
+    }
+    let mut chars = name.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c.to_ascii_lowercase()),
+        _ => match name.to_lowercase().as_str() {
+            "enter" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "esc" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            name => match name.strip_prefix('f').and_then(|v| v.parse().ok()) {
+                Some(n @ 1..=12) => KeyCode::F(n),
+                _ => return Err(invalid()),
+            },
+        },
+    };
+    Ok((modifiers, code))
+}
+
+/// Names of the editor actions that keys can be bound to
+const KEY_ACTIONS: [&str; 9] = [
+    "submit",
+    "enter",
+    "newline",
+    "complete",
+    "clear-screen",
+    "history-search",
+    "undo",
+    "redo",
+    "none",
+];
+
+/// A repl command such as `.edit`, run at once, or one of [`KEY_ACTIONS`]
+fn parse_action(action: &str) -> Result<ReedlineEvent> {
+    if action.starts_with('.') {
+        let name = action.split_whitespace().next().unwrap_or_default();
+        let known = REPL_COMMANDS
+            .iter()
+            .any(|(v, _, _)| v.split_whitespace().next() == Some(name));
+        if !known {
+            bail!("Unknown repl command `{name}`, `.help` lists them");
+        }
+        return Ok(ReedlineEvent::ExecuteHostCommand(action.to_string()));
+    }
+    let event = match action {
+        // Submit even when the input looks incomplete
+        "submit" => ReedlineEvent::Submit,
+        "enter" => ReedlineEvent::Enter,
+        "newline" => ReedlineEvent::Edit(vec![EditCommand::InsertNewline]),
+        "complete" => ReedlineEvent::UntilFound(vec![
+            ReedlineEvent::Menu(MENU_NAME.to_string()),
+            ReedlineEvent::MenuNext,
+        ]),
+        "clear-screen" => ReedlineEvent::ClearScreen,
+        "history-search" => ReedlineEvent::SearchHistory,
+        "undo" => ReedlineEvent::Edit(vec![EditCommand::Undo]),
+        "redo" => ReedlineEvent::Edit(vec![EditCommand::Redo]),
+        "none" => ReedlineEvent::None,
+        _ => bail!(
+            "Unknown action `{action}`, expected a repl command like `.edit` or one of {}",
+            KEY_ACTIONS.join(", ")
+        ),
+    };
+    Ok(event)
+}
+
 struct ReplValidator {
     multiline_commands: Vec<&'static str>,
 }
@@ -136,16 +285,26 @@ impl Prompt for ReplPrompt {
 
     fn render_prompt_right(&self) -> Cow<str> {
         let config = self.0.lock();
+        let mut parts = vec![config.params_info()];
+        if !config.usage.is_empty() {
+            parts.push(format!("${:.4}", config.usage_cost()));
+        }
         if let Some(conversation) = config.conversation.as_ref() {
-            conversation.reamind_tokens().to_string().into()
-        } else {
-            Cow::Borrowed("")
+            parts.push(
+                conversation
+                    .reamind_tokens(config.context_size())
+                    .to_string(),
+            );
         }
+        parts.retain(|v| !v.is_empty());
+        parts.join(" ").into()
     }
 
-    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<str> {
+    fn render_prompt_indicator(&self, prompt_mode: PromptEditMode) -> Cow<str> {
         let config = self.0.lock();
-        if config.conversation.is_some() {
+        if let PromptEditMode::Vi(PromptViMode::Normal) = prompt_mode {
+            Cow::Borrowed(":")
+        } else if config.conversation.is_some() {
             Cow::Borrowed("＄")
         } else {
             Cow::Borrowed("〉")
@@ -172,3 +331,47 @@ impl Prompt for ReplPrompt {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("ctrl-o").unwrap(),
+            (KeyModifiers::CONTROL, KeyCode::Char('o'))
+        );
+        assert_eq!(
+            parse_key("Alt-Enter").unwrap(),
+            (KeyModifiers::ALT, KeyCode::Enter)
+        );
+        assert_eq!(
+            parse_key("ctrl-alt-x").unwrap(),
+            (
+                KeyModifiers::CONTROL | KeyModifiers::ALT,
+                KeyCode::Char('x')
+            )
+        );
+        assert_eq!(
+            parse_key("f2").unwrap(),
+            (KeyModifiers::NONE, KeyCode::F(2))
+        );
+        assert!(parse_key("hyper-o").is_err());
+        assert!(parse_key("ctrl-f13").is_err());
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert!(matches!(
+            parse_action(".edit").unwrap(),
+            ReedlineEvent::ExecuteHostCommand(v) if v == ".edit"
+        ));
+        assert!(matches!(
+            parse_action("submit").unwrap(),
+            ReedlineEvent::Submit
+        ));
+        assert!(parse_action(".nope").is_err());
+        assert!(parse_action("explode").is_err());
+    }
+}
@@ -7,25 +7,126 @@ pub use self::handler::*;
 pub use self::init::Repl;
 
 use crate::client::ChatGptClient;
-use crate::config::SharedConfig;
+use crate::config::{image_mime, Config, SharedConfig};
 use crate::print_now;
+use crate::rag::SESSIONS_PATH;
 use crate::term;
+use crate::utils::{edit_text, extract_code_block};
 
 use anyhow::{Context, Result};
-use reedline::Signal;
+use reedline::{EditCommand, Signal};
+use std::path::Path;
 use std::sync::Arc;
 
-pub const REPL_COMMANDS: [(&str, &str, bool); 12] = [
+pub const REPL_COMMANDS: [(&str, &str, bool); 38] = [
     (".info", "Print the information", false),
     (".set", "Modify the configuration temporarily", false),
     (".prompt", "Add a GPT prompt", true),
+    (".model", "List or select the client and model", false),
+    (
+        ".sh",
+        "Generate a shell command and offer to execute it",
+        false,
+    ),
     (".role", "Select a role", false),
+    (".role create", "Create a role in $EDITOR", false),
+    (".role edit", "Edit a role in $EDITOR", false),
+    (".role delete", "Delete a role", false),
+    (
+        ".role info",
+        "Print a role, the current one by default",
+        false,
+    ),
+    (
+        ".role test",
+        "Try a role on an input without selecting it",
+        false,
+    ),
     (".clear role", "Clear the currently selected role", false),
     (".conversation", "Start a conversation.", false),
     (".clear conversation", "End current conversation.", false),
-    (".history", "Print the history", false),
-    (".clear history", "Clear the history", false),
+    (".tokens", "Print token usage of the conversation", false),
+    (
+        ".undo",
+        "Drop the last exchange from the conversation",
+        false,
+    ),
+    (
+        ".fork",
+        "Save the conversation as a new session to come back to",
+        false,
+    ),
+    (
+        ".compact",
+        "Summarize the older messages of the conversation",
+        false,
+    ),
+    (
+        ".usage",
+        "Print tokens and cost of the session and month",
+        false,
+    ),
+    (
+        ".session",
+        "Save, load or list conversation sessions",
+        false,
+    ),
+    (
+        ".rag",
+        "Build, use or list indexes of files, sites or sessions",
+        false,
+    ),
+    (
+        ".clear rag",
+        "Stop retrieving from the current index",
+        false,
+    ),
+    (".transcript", "Log prompts and replies to a file", false),
+    (
+        ".clear transcript",
+        "Stop logging to the transcript file",
+        false,
+    ),
+    (".reply", "Insert the last reply into the input", false),
+    (
+        ".copy",
+        "Copy the last reply, or its code block #n, to the clipboard",
+        false,
+    ),
+    (
+        ".continue",
+        "Resume the last reply if it was cut off",
+        false,
+    ),
+    (
+        ".quote",
+        "Quote the last reply or message #n into the input",
+        false,
+    ),
+    (
+        ".history",
+        "List, search or show saved chats, or print the input history",
+        false,
+    ),
+    (".clear history", "Clear the input history", false),
     (".editor", "Enter editor mode for multiline input", true),
+    (".edit", "Compose the input in $EDITOR", false),
+    (".file", "Attach images or files to the input", false),
+    (
+        ".ask",
+        "Send an input, with -m to another model for this message only",
+        false,
+    ),
+    (
+        ".preview",
+        "Show what the next input would send, without sending",
+        false,
+    ),
+    (
+        ".payload",
+        "Print the request body the next input would send",
+        false,
+    ),
     (".help", "Print this help message", false),
     (".exit", "Exit the REPL", false),
 ];
@@ -33,9 +134,13 @@ pub const REPL_COMMANDS: [(&str, &str, bool); 12] = [
 impl Repl {
     pub fn run(&mut self, client: ChatGptClient, config: SharedConfig) -> Result<()> {
         let abort = AbortSignal::new();
+        let banner = config.lock().repl_banner();
         let handler = ReplCmdHandler::init(client, config, abort.clone())?;
         print_now!("Welcome to aichat {}\n", env!("CARGO_PKG_VERSION"));
         print_now!("Type \".help\" for more information.\n");
+        if let Some(banner) = banner {
+            print_now!("{banner}\n");
+        }
         let mut already_ctrlc = false;
         let handler = Arc::new(handler);
         loop {
@@ -105,19 +210,116 @@ impl Repl {
                     }
                     Some("role") => handler.handle(ReplCmd::ClearRole)?,
                     Some("conversation") => handler.handle(ReplCmd::EndConversatoin)?,
+                    Some("transcript") => handler.handle(ReplCmd::EndTranscript)?,
+                    Some("rag") => handler.handle(ReplCmd::ClearRag)?,
                     _ => dump_unknown_command(),
                 },
-                ".history" => {
-                    self.editor.print_history()?;
-                    print_now!("\n");
-                }
-                ".role" => match args {
-                    Some(name) => handler.handle(ReplCmd::SetRole(name.to_string()))?,
+                ".history" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    None | Some(("list", "")) => handler.handle(ReplCmd::SearchHistory(None))?,
+                    Some(("input", "")) => {
+                        self.editor.print_history()?;
+                        print_now!("\n");
+                    }
+                    Some(("search", term)) if !term.trim().is_empty() => {
+                        handler.handle(ReplCmd::SearchHistory(Some(term.trim().to_string())))?
+                    }
+                    Some(("show", index)) if !index.trim().is_empty() => {
+                        let index = index
+                            .trim()
+                            .parse()
+                            .with_context(|| "Usage: .history show <n>")?;
+                        let text = handler.show_chat(index)?;
+                        self.editor
+                            .run_edit_commands(&[EditCommand::InsertString(text)]);
+                    }
+                    _ => print_now!("Usage: .history [list|search <term>|show <n>|input]\n\n"),
+                },
+                ".role" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("create", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::CreateRole(name.trim().to_string()))?;
+                        self.reload_completer();
+                    }
+                    Some(("edit", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::EditRole(name.trim().to_string()))?;
+                        self.reload_completer();
+                    }
+                    Some(("delete", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::DeleteRole(name.trim().to_string()))?;
+                        self.reload_completer();
+                    }
+                    Some(("info", name)) => {
+                        let name = Some(name.trim()).filter(|v| !v.is_empty());
+                        handler.handle(ReplCmd::RoleInfo(name.map(|v| v.to_string())))?
+                    }
+                    Some(("test", args)) => match args.trim_start().split_once(' ') {
+                        Some((name, input)) => handler.handle(ReplCmd::TestRole(
+                            name.to_string(),
+                            input.trim().to_string(),
+                        ))?,
+                        None => print_now!("Usage: .role test <name> <input>\n\n"),
+                    },
+                    Some(("create" | "edit" | "delete", _)) => {
+                        print_now!("Usage: .role [create|edit|delete] <name>\n\n")
+                    }
+                    Some(_) => {
+                        let name = args.unwrap_or_default();
+                        handler.handle(ReplCmd::SetRole(name.to_string()))?
+                    }
                     None => print_now!("Usage: .role <name>\n\n"),
                 },
+                ".sh" => match args {
+                    Some(task) => handler.handle(ReplCmd::Shell(task.to_string()))?,
+                    None => print_now!("Usage: .sh <task>\n\n"),
+                },
+                ".model" => match args {
+                    Some(name) => handler.handle(ReplCmd::SetModel(name.to_string()))?,
+                    None => handler.handle(ReplCmd::ListModels)?,
+                },
                 ".info" => {
                     handler.handle(ReplCmd::ViewInfo)?;
                 }
+                ".copy" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    None => handler.handle(ReplCmd::Copy(None))?,
+                    Some(("code", index)) => {
+                        let index = match index.trim() {
+                            "" => 1,
+                            v => v.parse().with_context(|| "Usage: .copy [code [n]]")?,
+                        };
+                        handler.handle(ReplCmd::Copy(Some(index)))?
+                    }
+                    _ => print_now!("Usage: .copy [code [n]]\n\n"),
+                },
+                ".continue" => {
+                    handler.handle(ReplCmd::Continue)?;
+                }
+                ".compact" => {
+                    handler.handle(ReplCmd::Compact)?;
+                }
+                ".undo" => {
+                    handler.handle(ReplCmd::Undo)?;
+                }
+                ".fork" => match args {
+                    Some(name) => handler.handle(ReplCmd::Fork(name.to_string()))?,
+                    None => print_now!("Usage: .fork <name>\n\n"),
+                },
+                ".reply" => {
+                    let reply = handler.get_reply();
+                    let text = match args {
+                        Some("code") => extract_code_block(&reply).unwrap_or(reply),
+                        _ => reply,
+                    };
+                    self.editor
+                        .run_edit_commands(&[EditCommand::InsertString(text)]);
+                }
+                ".quote" => {
+                    let index = match args {
+                        Some(v) => Some(v.parse().with_context(|| "Usage: .quote [n]")?),
+                        None => None,
+                    };
+                    let text = handler.get_quote(index)?;
+                    self.editor
+                        .run_edit_commands(&[EditCommand::InsertString(text)]);
+                }
                 ".editor" => {
                     let mut text = args.unwrap_or_default().to_string();
                     if text.is_empty() {
@@ -129,6 +331,35 @@ impl Repl {
                         handler.handle(ReplCmd::Submit(text))?;
                     }
                 }
+                ".edit" => {
+                    let text = edit_text("", "md")?;
+                    if text.trim().is_empty() {
+                        print_now!("\n");
+                    } else {
+                        print_now!("{}\n", text.trim_end());
+                        handler.handle(ReplCmd::Submit(text))?;
+                    }
+                }
+                ".file" => {
+                    let (files, text) = split_files(args.unwrap_or_default());
+                    if files.is_empty() || text.is_empty() {
+                        print_now!("Usage: .file <file>... <text>\n\n");
+                    } else {
+                        handler.handle(ReplCmd::SubmitFiles(files, text))?;
+                    }
+                }
+                ".ask" => match ask_input(args.unwrap_or_default()) {
+                    Some(input) => handler.handle(ReplCmd::Submit(input))?,
+                    None => print_now!("Usage: .ask [-m <model>] <text>\n\n"),
+                },
+                ".preview" => {
+                    let (files, text) = split_files(args.unwrap_or_default());
+                    handler.handle(ReplCmd::Preview(files, text))?;
+                }
+                ".payload" => {
+                    let (files, text) = split_files(args.unwrap_or_default());
+                    handler.handle(ReplCmd::Payload(files, text))?;
+                }
                 ".set" => {
                     handler.handle(ReplCmd::UpdateConfig(args.unwrap_or_default().to_string()))?
                 }
@@ -146,6 +377,65 @@ impl Repl {
                 ".conversation" => {
                     handler.handle(ReplCmd::StartConversation)?;
                 }
+                ".tokens" => {
+                    handler.handle(ReplCmd::ViewTokens)?;
+                }
+                ".usage" => {
+                    handler.handle(ReplCmd::ViewUsage(args.map(|v| v.to_string())))?;
+                }
+                ".rag" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("build", args)) => match args.trim().split_once(' ') {
+                        Some((name, path)) => handler
+                            .handle(ReplCmd::BuildRag(name.to_string(), path.trim().to_string()))?,
+                        None => print_now!("Usage: .rag build <name> <path>\n\n"),
+                    },
+                    Some(("add", args)) if !args.trim().is_empty() => {
+                        let mut args = args.split_whitespace().map(|v| v.to_string());
+                        let url = args.next().unwrap_or_default();
+                        handler.handle(ReplCmd::AddRag(url, args.collect()))?
+                    }
+                    Some(("session", names)) => {
+                        let names: Vec<&str> = names.split_whitespace().collect();
+                        let path = format!("{SESSIONS_PATH}{}", names.join(","));
+                        handler.handle(ReplCmd::BuildRag("sessions".into(), path))?
+                    }
+                    Some(("use", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::UseRag(name.trim().to_string()))?
+                    }
+                    Some(("attach", args)) if !args.trim().is_empty() => {
+                        let (name, weight) = args.trim().split_once(' ').unwrap_or((args.trim(), "1"));
+                        let weight = weight.trim().parse().with_context(|| "Usage: .rag attach <name> [weight]")?;
+                        handler.handle(ReplCmd::AttachRag(name.to_string(), weight))?
+                    }
+                    Some(("detach", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::DetachRag(name.trim().to_string()))?
+                    }
+                    Some(("rebuild", name)) => {
+                        let name = Some(name.trim()).filter(|v| !v.is_empty());
+                        handler.handle(ReplCmd::RebuildRag(name.map(|v| v.to_string())))?
+                    }
+                    None | Some(("list", "")) => handler.handle(ReplCmd::ListRags)?,
+                    _ => print_now!(
+                        "Usage: .rag [build <name> <path>|add <url> [option=value]...|session [session]...|use <name>|attach <name> [weight]|detach <name>|rebuild [name]|list]\n\n"
+                    ),
+                },
+                ".session" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("save", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::SaveSession(name.trim().to_string()))?
+                    }
+                    Some(("load", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::LoadSession(name.trim().to_string()))?
+                    }
+                    Some(("last", "")) => {
+                        handler.handle(ReplCmd::LoadSession(Config::last_session()?))?
+                    }
+                    None | Some(("list", "")) => handler.handle(ReplCmd::ListSessions)?,
+                    _ => print_now!("Usage: .session [save <name>|load <name>|last|list]\n\n"),
+                },
+                ".transcript" => match args {
+                    Some(path) => handler.handle(ReplCmd::StartTranscript(path.to_string()))?,
+                    None => print_now!("Usage: .transcript <file>\n\n"),
+                },
                 _ => dump_unknown_command(),
             }
         } else {
@@ -156,6 +446,41 @@ impl Repl {
     }
 }
 
+/// Leading paths of images or files or image urls of the arguments, and the text after them
+fn split_files(args: &str) -> (Vec<String>, String) {
+    let mut files = vec![];
+    let mut rest = args.trim_start();
+    while let Some(word) = rest.split_whitespace().next() {
+        let is_url = word.starts_with("http://") || word.starts_with("https://");
+        if !is_url && image_mime(word).is_none() && !Path::new(word).is_file() {
+            break;
+        }
+        files.push(word.to_string());
+        rest = rest[word.len()..].trim_start();
+    }
+    (files, rest.trim_end().to_string())
+}
+
+/// Input of `.ask [-m <model>] <text>`, the model as an `@model` directive, the text unquoted
+fn ask_input(args: &str) -> Option<String> {
+    let (model, text) = match args.trim().strip_prefix("-m ") {
+        Some(rest) => {
+            let (model, text) = rest.trim_start().split_once(char::is_whitespace)?;
+            (Some(model), text.trim())
+        }
+        None => (None, args.trim()),
+    };
+    let text = text
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(text);
+    match model {
+        _ if text.is_empty() => None,
+        Some(model) => Some(format!("@{model} {text}")),
+        None => Some(text.to_string()),
+    }
+}
+
 fn dump_unknown_command() {
     print_now!("Error: Unknown command. Type \".help\" for more information.\n\n");
 }
@@ -167,7 +492,7 @@ fn dump_repl_help() {
         .collect::<Vec<String>>()
         .join("\n");
     print_now!(
-        "{}\n\nPress Ctrl+C to abort conversation, Ctrl+D to exit the REPL\n\n",
+        "{}\n\nPress Ctrl+C to abort conversation, Ctrl+D to exit the REPL, Ctrl+F to fast-forward output\nPress Alt+. to insert the last reply, Alt+, to insert its first code block, Ctrl+G to resume the last session\n\n",
         head,
     );
 }
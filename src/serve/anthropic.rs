@@ -0,0 +1,263 @@
+//! Anthropic messages endpoint, translated to and from the chat completions format
+
+use super::{prepare, respond_json, Rejection};
+
+use crate::client::ChatGptClient;
+use crate::config::{ApiKey, Config, SharedConfig};
+use crate::utils::count_tokens;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::error;
+
+pub async fn messages<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    key: Option<&ApiKey>,
+    body: &[u8],
+) -> Result<()> {
+    let request = match serde_json::from_slice::<Value>(body)
+        .with_context(|| "Invalid json")
+        .and_then(|v| to_chat_request(&v))
+    {
+        Ok(v) => v,
+        Err(err) => {
+            let rejection = Rejection::new(
+                "400 Bad Request",
+                "invalid_request_error",
+                format!("{err:#}"),
+            );
+            return respond_rejection(writer, &rejection).await;
+        }
+    };
+    let (client_config, body) = match prepare(config, key, request) {
+        Ok(v) => v,
+        Err(err) => return respond_rejection(writer, &err).await,
+    };
+    let model = client_config.id();
+    let started = Instant::now();
+    let key = key.map(|v| v.name.as_str());
+    let id = format!(
+        "msg_{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    );
+    let input_tokens = Config::count_body_tokens(&body);
+
+    if body["stream"].as_bool() != Some(true) {
+        return match client.send_body(&client_config, &body).await {
+            Ok(reply) => {
+                if let Err(err) = config
+                    .lock()
+                    .record_usage(&model, key, &body, &reply, started)
+                {
+                    error!("{err:#}");
+                }
+                let data = json!({
+                    "id": id,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": model,
+                    "content": [{ "type": "text", "text": reply }],
+                    "stop_reason": "end_turn",
+                    "stop_sequence": null,
+                    "usage": {
+                        "input_tokens": input_tokens,
+                        "output_tokens": count_tokens(&reply),
+                    },
+                });
+                respond_json(writer, "200 OK", &data).await
+            }
+            Err(err) => {
+                let rejection = Rejection::new("502 Bad Gateway", "api_error", format!("{err:#}"));
+                respond_rejection(writer, &rejection).await
+            }
+        };
+    }
+
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+Connection: close\r\n\r\n";
+    writer.write_all(head.as_bytes()).await?;
+    let message = json!({
+        "id": id,
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": [],
+        "stop_reason": null,
+        "stop_sequence": null,
+        "usage": { "input_tokens": input_tokens, "output_tokens": 0 },
+    });
+    write_event(writer, "message_start", &json!({ "message": message })).await?;
+    let block = json!({ "index": 0, "content_block": { "type": "text", "text": "" } });
+    write_event(writer, "content_block_start", &block).await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let send = client.send_body_streaming(&client_config, &body, tx);
+    let write = async {
+        let mut rx = rx;
+        let mut reply = String::new();
+        while let Some(text) = rx.recv().await {
+            let delta = json!({ "index": 0, "delta": { "type": "text_delta", "text": text } });
+            write_event(writer, "content_block_delta", &delta).await?;
+            reply.push_str(&text);
+        }
+        Ok::<String, anyhow::Error>(reply)
+    };
+    let (sent, written) = tokio::join!(send, write);
+    let reply = written?;
+    if let Err(err) = config
+        .lock()
+        .record_usage(&model, key, &body, &reply, started)
+    {
+        error!("{err:#}");
+    }
+    if let Err(err) = sent {
+        let data = json!({ "error": { "type": "api_error", "message": format!("{err:#}") } });
+        return write_event(writer, "error", &data).await;
+    }
+    write_event(writer, "content_block_stop", &json!({ "index": 0 })).await?;
+    let delta = json!({
+        "delta": { "stop_reason": "end_turn", "stop_sequence": null },
+        "usage": { "output_tokens": count_tokens(&reply) },
+    });
+    write_event(writer, "message_delta", &delta).await?;
+    write_event(writer, "message_stop", &json!({})).await
+}
+
+/// The request in the chat completions format, the system prompt as the first message
+fn to_chat_request(request: &Value) -> Result<Value> {
+    let mut messages = vec![];
+    match &request["system"] {
+        Value::Null => {}
+        Value::String(text) => messages.push(json!({ "role": "system", "content": text })),
+        Value::Array(blocks) => {
+            let texts: Vec<&str> = blocks.iter().filter_map(|v| v["text"].as_str()).collect();
+            messages.push(json!({ "role": "system", "content": texts.join("\n\n") }));
+        }
+        _ => bail!("Invalid system"),
+    }
+    let input = request["messages"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid messages"))?;
+    for message in input {
+        let content = match &message["content"] {
+            Value::String(text) => json!(text),
+            Value::Array(blocks) => blocks
+                .iter()
+                .map(to_content_part)
+                .collect::<Result<Vec<Value>>>()?
+                .into(),
+            _ => bail!("Invalid content"),
+        };
+        messages.push(json!({ "role": message["role"], "content": content }));
+    }
+    let mut output = json!({ "model": request["model"], "messages": messages });
+    for name in ["temperature", "top_p", "max_tokens", "stream"] {
+        if !request[name].is_null() {
+            output[name] = request[name].clone();
+        }
+    }
+    Ok(output)
+}
+
+fn to_content_part(block: &Value) -> Result<Value> {
+    match block["type"].as_str() {
+        Some("text") => Ok(json!({ "type": "text", "text": block["text"] })),
+        Some("image") => {
+            let source = &block["source"];
+            let url = match source["type"].as_str() {
+                Some("base64") => {
+                    let data = source["data"].as_str().unwrap_or_default();
+                    format!(
+                        "data:{};base64,{data}",
+                        source["media_type"].as_str().unwrap_or("image/png")
+                    )
+                }
+                Some("url") => source["url"].as_str().unwrap_or_default().to_string(),
+                _ => bail!("Invalid image source"),
+            };
+            Ok(json!({ "type": "image_url", "image_url": { "url": url } }))
+        }
+        Some(kind) => bail!("Unsupported content block '{kind}'"),
+        None => bail!("Invalid content block"),
+    }
+}
+
+/// `event:` lines name the event, and the data repeats it as `type`
+async fn write_event<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    event: &str,
+    data: &Value,
+) -> Result<()> {
+    let mut data = data.clone();
+    data["type"] = event.into();
+    writer
+        .write_all(format!("event: {event}\ndata: {data}\n\n").as_bytes())
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn respond_rejection<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    rejection: &Rejection,
+) -> Result<()> {
+    let kind = match rejection.code {
+        "invalid_api_key" => "authentication_error",
+        "model_not_allowed" => "permission_error",
+        "model_not_found" => "not_found_error",
+        "insufficient_quota" => "rate_limit_error",
+        "api_error" => "api_error",
+        _ => "invalid_request_error",
+    };
+    let data = json!({
+        "type": "error",
+        "error": { "type": kind, "message": rejection.message },
+    });
+    respond_json(writer, rejection.status, &data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_chat_request() {
+        let request = json!({
+            "model": "coder",
+            "system": [{ "type": "text", "text": "Be concise." }],
+            "max_tokens": 100,
+            "messages": [
+                { "role": "user", "content": "hello" },
+                { "role": "assistant", "content": [{ "type": "text", "text": "hi" }] },
+                { "role": "user", "content": [
+                    { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AA==" } },
+                    { "type": "text", "text": "what is it?" },
+                ] },
+            ],
+        });
+        let output = to_chat_request(&request).unwrap();
+        assert_eq!(
+            output,
+            json!({
+                "model": "coder",
+                "max_tokens": 100,
+                "messages": [
+                    { "role": "system", "content": "Be concise." },
+                    { "role": "user", "content": "hello" },
+                    { "role": "assistant", "content": [{ "type": "text", "text": "hi" }] },
+                    { "role": "user", "content": [
+                        { "type": "image_url", "image_url": { "url": "data:image/png;base64,AA==" } },
+                        { "type": "text", "text": "what is it?" },
+                    ] },
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,467 @@
+//! Api server speaking the OpenAI chat completions format, with a small web playground
+
+mod anthropic;
+mod ws;
+
+use crate::client::ChatGptClient;
+use crate::client::ClientConfig;
+use crate::config::{ApiKey, Config, SharedConfig};
+use crate::print_now;
+use crate::rag::{list_rags, Rag};
+use crate::utils::now;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+const PLAYGROUND: &str = include_str!("../../assets/playground.html");
+/// Large enough for a few images sent as data urls
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Secret given as `Authorization: Bearer <key>`, `x-api-key: <key>` or, for browsers
+    /// opening a websocket, `?api_key=<key>`
+    fn api_key(&self) -> Option<&str> {
+        self.header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| self.header("x-api-key"))
+            .or_else(|| {
+                self.query
+                    .split('&')
+                    .find_map(|v| v.strip_prefix("api_key="))
+            })
+            .map(|v| v.trim())
+    }
+}
+
+/// Why a request was turned down, shaped into the error format of each endpoint
+struct Rejection {
+    status: &'static str,
+    code: &'static str,
+    message: String,
+}
+
+impl Rejection {
+    fn new(status: &'static str, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Serve until the process is stopped
+pub fn run(client: ChatGptClient, config: SharedConfig, addr: &str) -> Result<()> {
+    let client = Arc::new(client);
+    let runtime = client.clone();
+    runtime.block_on(serve(client, config, addr))
+}
+
+async fn serve(client: Arc<ChatGptClient>, config: SharedConfig, addr: &str) -> Result<()> {
+    let keys = Arc::new(Config::api_keys()?);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(v) => v,
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            bail!("{addr} is already in use, serve on another port, e.g. `--serve 127.0.0.1:8001`")
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            bail!("Not allowed to listen on {addr}, ports below 1024 need privileges")
+        }
+        Err(err) => return Err(err).with_context(|| format!("Failed to listen on {addr}")),
+    };
+    print_now!("Serving the playground on http://{addr} and the api on http://{addr}/v1\n");
+    if !keys.is_empty() {
+        print_now!("The api takes the {} keys of keys.yaml\n", keys.len());
+    }
+    refresh_rags(&client, &config)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (client, config, keys) = (client.clone(), config.clone(), keys.clone());
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &client, &config, &keys).await {
+                error!("{err:#}");
+            }
+        });
+    }
+}
+
+/// Rebuild the rags of `rag_refresh` in the background, each at its own interval
+fn refresh_rags(client: &Arc<ChatGptClient>, config: &SharedConfig) -> Result<()> {
+    let rags = config.lock().rag_refresh.clone();
+    let dir = Config::rags_dir()?;
+    for (name, minutes) in rags {
+        if minutes == 0 {
+            bail!("The refresh of rag '{name}' must be at least 1 minute");
+        }
+        if !list_rags(&dir).contains(&name) {
+            bail!("Unknown rag '{name}' in `rag_refresh`, build it first with `.rag build`");
+        }
+        print_now!("Refreshing rag '{name}' every {minutes} min\n");
+        let (client, config, dir) = (client.clone(), config.clone(), dir.clone());
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(minutes * 60));
+            let refreshed = Rag::load(&dir, &name).and_then(|mut rag| {
+                let client_config = config.lock().find_client(&rag.client)?;
+                let before = rag.chunks.len();
+                let output = rag.rebuild(&client, &client_config)?;
+                rag.save(&dir)?;
+                let output = format!("{output}, {before} -> {} chunks", rag.chunks.len());
+                config.lock().replace_rag(rag);
+                Ok(output)
+            });
+            match refreshed {
+                Ok(output) => print_now!("{} Refreshed rag '{name}': {output}\n", now()),
+                Err(err) => error!("Failed to refresh rag '{name}': {err:#}"),
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    keys: &[ApiKey],
+) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let request = match read_request(&mut reader).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(()),
+        Err(err) => return respond_error(&mut writer, "400 Bad Request", &err).await,
+    };
+    info!("{} {}", request.method, request.path);
+    if request.path == "/" {
+        let content_type = "text/html; charset=utf-8";
+        return respond(&mut writer, "200 OK", content_type, PLAYGROUND.as_bytes()).await;
+    }
+    let key = match authorize(&request, keys) {
+        Ok(v) => v,
+        Err(err) if request.path == "/v1/messages" => {
+            return anthropic::respond_rejection(&mut writer, &err).await
+        }
+        Err(err) => return respond_rejection(&mut writer, &err).await,
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/models") => {
+            respond_json(&mut writer, "200 OK", &list_models(config, key)).await
+        }
+        ("POST", "/v1/chat/completions") => {
+            chat_completions(&mut writer, client, config, key, &request.body).await
+        }
+        ("POST", "/v1/messages") => {
+            anthropic::messages(&mut writer, client, config, key, &request.body).await
+        }
+        ("GET", "/ws/chat") => {
+            ws::chat(&mut reader, &mut writer, client, config, key, &request).await
+        }
+        _ => respond_error(&mut writer, "404 Not Found", &anyhow!("Not found")).await,
+    }
+}
+
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => bail!("Invalid request line"),
+    };
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (path.to_string(), String::new()),
+    };
+    let mut headers = vec![];
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .with_context(|| "Invalid content-length")?;
+            }
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        bail!("Request body is too large");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+/// The key of the request, any request passes when no keys are configured
+fn authorize<'a>(request: &Request, keys: &'a [ApiKey]) -> Result<Option<&'a ApiKey>, Rejection> {
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    let secret = request
+        .api_key()
+        .ok_or_else(|| Rejection::new("401 Unauthorized", "invalid_api_key", "Missing api key"))?;
+    keys.iter()
+        .find(|v| v.key == secret)
+        .map(Some)
+        .ok_or_else(|| Rejection::new("401 Unauthorized", "invalid_api_key", "Invalid api key"))
+}
+
+/// Clients under their `name:model` id, aliases and roles under their name, those of the key
+fn list_models(config: &SharedConfig, key: Option<&ApiKey>) -> Value {
+    let config = config.lock();
+    let data: Vec<Value> = config
+        .clients
+        .iter()
+        .map(|v| v.id())
+        .chain(config.aliases.keys().cloned())
+        .chain(config.roles.iter().map(|v| v.name.clone()))
+        .filter(|id| match key {
+            Some(key) => key.allows(id),
+            None => true,
+        })
+        .map(|id| json!({ "id": id, "object": "model", "owned_by": "aichat" }))
+        .collect();
+    json!({ "object": "list", "data": data })
+}
+
+async fn chat_completions<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    key: Option<&ApiKey>,
+    body: &[u8],
+) -> Result<()> {
+    let request = match serde_json::from_slice::<Value>(body).with_context(|| "Invalid json") {
+        Ok(v) => v,
+        Err(err) => return respond_error(writer, "400 Bad Request", &err).await,
+    };
+    let (client_config, body) = match prepare(config, key, request) {
+        Ok(v) => v,
+        Err(err) => return respond_rejection(writer, &err).await,
+    };
+    let model = client_config.id();
+    let started = Instant::now();
+    let key = key.map(|v| v.name.as_str());
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let id = format!("chatcmpl-{}", created.as_millis());
+    let created = created.as_secs();
+
+    if body["stream"].as_bool() != Some(true) {
+        return match client.send_body(&client_config, &body).await {
+            Ok(reply) => {
+                if let Err(err) = config
+                    .lock()
+                    .record_usage(&model, key, &body, &reply, started)
+                {
+                    error!("{err:#}");
+                }
+                let data = json!({
+                    "id": id,
+                    "object": "chat.completion",
+                    "created": created,
+                    "model": model,
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": reply },
+                        "finish_reason": "stop",
+                    }],
+                });
+                respond_json(writer, "200 OK", &data).await
+            }
+            Err(err) => respond_error(writer, "502 Bad Gateway", &err).await,
+        };
+    }
+
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+Connection: close\r\n\r\n";
+    writer.write_all(head.as_bytes()).await?;
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    let send = client.send_body_streaming(&client_config, &body, tx);
+    let write = async {
+        let mut rx = rx;
+        let mut reply = String::new();
+        write_event(writer, &chunk(json!({ "role": "assistant" }), Value::Null)).await?;
+        while let Some(text) = rx.recv().await {
+            write_event(writer, &chunk(json!({ "content": text }), Value::Null)).await?;
+            reply.push_str(&text);
+        }
+        Ok::<String, anyhow::Error>(reply)
+    };
+    let (sent, written) = tokio::join!(send, write);
+    let reply = written?;
+    if let Err(err) = config
+        .lock()
+        .record_usage(&model, key, &body, &reply, started)
+    {
+        error!("{err:#}");
+    }
+    if let Err(err) = sent {
+        write_event(
+            writer,
+            &json!({ "error": { "message": format!("{err:#}") } }),
+        )
+        .await?;
+    }
+    write_event(writer, &chunk(json!({}), "stop".into())).await?;
+    writer.write_all(b"data: [DONE]\n\n").await?;
+    Ok(())
+}
+
+/// Client and body of a request in the chat completions format, after the checks of the key
+fn prepare(
+    config: &SharedConfig,
+    key: Option<&ApiKey>,
+    mut request: Value,
+) -> Result<(ClientConfig, Value), Rejection> {
+    let config = config.lock();
+    let mut model = request["model"].as_str().unwrap_or_default().to_string();
+    if let Some(key) = key {
+        model = key.resolve_model(&model).unwrap_or_default();
+        if !key.allows(&model) {
+            let message = format!("The model '{model}' is not allowed for this key");
+            return Err(Rejection::new(
+                "403 Forbidden",
+                "model_not_allowed",
+                message,
+            ));
+        }
+        if let Some(quota) = key.quota {
+            let used = config
+                .key_tokens(&key.name)
+                .map_err(|err| Rejection::new("500 Internal Server Error", "api_error", err))?;
+            if used >= quota {
+                let message = format!("The key used {used} of its {quota} tokens this month");
+                return Err(Rejection::new(
+                    "429 Too Many Requests",
+                    "insufficient_quota",
+                    message,
+                ));
+            }
+        }
+        request["model"] = model.clone().into();
+    }
+    if !model.is_empty() && !config.has_model(&model) {
+        let message = format!("The model '{model}' does not exist");
+        return Err(Rejection::new("404 Not Found", "model_not_found", message));
+    }
+    config.build_server_body(&request).map_err(|err| {
+        Rejection::new(
+            "400 Bad Request",
+            "invalid_request_error",
+            format!("{err:#}"),
+        )
+    })
+}
+
+async fn write_event<W: AsyncWrite + Unpin>(writer: &mut W, data: &Value) -> Result<()> {
+    writer
+        .write_all(format!("data: {data}\n\n").as_bytes())
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn respond_json<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    data: &Value,
+) -> Result<()> {
+    respond(
+        writer,
+        status,
+        "application/json",
+        data.to_string().as_bytes(),
+    )
+    .await
+}
+
+async fn respond_error<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    err: &anyhow::Error,
+) -> Result<()> {
+    let data = json!({ "error": { "message": format!("{err:#}") } });
+    respond_json(writer, status, &data).await
+}
+
+async fn respond_rejection<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    rejection: &Rejection,
+) -> Result<()> {
+    let data = json!({
+        "error": {
+            "message": rejection.message,
+            "type": "invalid_request_error",
+            "code": rejection.code,
+        }
+    });
+    respond_json(writer, rejection.status, &data).await
+}
+
+async fn respond<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+Connection: close\r\n\r\n",
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
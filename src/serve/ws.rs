@@ -0,0 +1,228 @@
+//! WebSocket endpoint taking chat completions requests and streaming the replies as json frames
+
+use super::{prepare, respond_error, Request, MAX_BODY_SIZE};
+
+use crate::client::ChatGptClient;
+use crate::config::{ApiKey, SharedConfig};
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::io::ErrorKind;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Appended to the key of the client to prove the server speaks websocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Upgrade the connection, then answer every text message until the client closes it
+pub async fn chat<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    key: Option<&ApiKey>,
+    request: &Request,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let upgrade = request
+        .header("upgrade")
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or_default();
+    let secret = match request.header("sec-websocket-key") {
+        Some(v) if upgrade => v,
+        _ => {
+            let err = anyhow!("Expected a websocket upgrade");
+            return respond_error(writer, "400 Bad Request", &err).await;
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(secret)
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.flush().await?;
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    while let Some(frame) = read_frame(reader).await? {
+        match frame.opcode {
+            OP_PING => write_frame(writer, OP_PONG, &frame.payload).await?,
+            OP_PONG => {}
+            OP_CLOSE => return write_frame(writer, OP_CLOSE, &frame.payload).await,
+            OP_CONTINUATION => match message.as_mut() {
+                Some((_, payload)) if payload.len() + frame.payload.len() <= MAX_BODY_SIZE => {
+                    payload.extend(frame.payload)
+                }
+                Some(_) => bail!("Message is too large"),
+                None => bail!("Continuation frame without a message"),
+            },
+            opcode => message = Some((opcode, frame.payload)),
+        }
+        if !frame.fin || frame.opcode >= OP_CLOSE {
+            continue;
+        }
+        match message.take() {
+            Some((OP_TEXT, payload)) => reply(writer, client, config, key, &payload).await?,
+            Some(_) => {
+                write_error(writer, "invalid_request_error", "Expected a text frame").await?
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Stream the reply to one request as `delta` frames, ended by a `done` or an `error` frame
+async fn reply<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    key: Option<&ApiKey>,
+    payload: &[u8],
+) -> Result<()> {
+    let request = match serde_json::from_slice::<Value>(payload) {
+        Ok(v) => v,
+        Err(err) => return write_error(writer, "invalid_request_error", &err).await,
+    };
+    let (client_config, mut body) = match prepare(config, key, request) {
+        Ok(v) => v,
+        Err(err) => return write_error(writer, err.code, &err.message).await,
+    };
+    body["stream"] = true.into();
+    let model = client_config.id();
+    let started = Instant::now();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let send = client.send_body_streaming(&client_config, &body, tx);
+    let write = async {
+        let mut rx = rx;
+        let mut reply = String::new();
+        while let Some(text) = rx.recv().await {
+            write_json(writer, &json!({ "type": "delta", "content": text })).await?;
+            reply.push_str(&text);
+        }
+        Ok::<String, anyhow::Error>(reply)
+    };
+    let (sent, written) = tokio::join!(send, write);
+    let reply = written?;
+    let key = key.map(|v| v.name.as_str());
+    if let Err(err) = config
+        .lock()
+        .record_usage(&model, key, &body, &reply, started)
+    {
+        error!("{err:#}");
+    }
+    match sent {
+        Ok(()) => write_json(writer, &json!({ "type": "done", "model": model })).await,
+        Err(err) => write_error(writer, "api_error", format!("{err:#}")).await,
+    }
+}
+
+fn accept_key(secret: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(secret.trim().as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+/// Next frame of the client, unmasked, None once the connection is closed
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+    let mut head = [0; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as usize,
+        127 => reader.read_u64().await? as usize,
+        v => v as usize,
+    };
+    if head[1] & 0x80 == 0 {
+        bail!("Frames of the client must be masked");
+    }
+    if len > MAX_BODY_SIZE {
+        bail!("Message is too large");
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame {
+        fin: head[0] & 0x80 != 0,
+        opcode: head[0] & 0x0f,
+        payload,
+    }))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            head.push(126);
+            head.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend((len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn write_json<W: AsyncWrite + Unpin>(writer: &mut W, data: &Value) -> Result<()> {
+    write_frame(writer, OP_TEXT, data.to_string().as_bytes()).await
+}
+
+async fn write_error<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    code: &str,
+    message: impl ToString,
+) -> Result<()> {
+    let data = json!({
+        "type": "error",
+        "error": { "message": message.to_string(), "code": code },
+    });
+    write_json(writer, &data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // The example handshake of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Shell commands generated from a description of the task, run after confirmation
+
+use crate::client::ChatGptClient;
+use crate::clipboard::copy_text;
+use crate::config::{Config, SharedConfig};
+use crate::print_now;
+use crate::utils::{extract_code_block, now};
+
+use anyhow::{bail, Context, Result};
+use inquire::{Select, Text};
+use is_terminal::IsTerminal;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{stdin, Write};
+use std::process::Command;
+use tracing::{error, info};
+
+const DESCRIBE_PROMPT: &str = "Explain the shell command below: what it does, \
+then each flag and argument on its own line. Be brief and mention anything destructive.";
+
+/// Entry of the log of executed commands, one json object per line
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ExecutedCommand {
+    time: String,
+    /// Working directory the command ran in
+    dir: String,
+    /// Program of the shell that ran it
+    shell: String,
+    task: String,
+    command: String,
+    /// Exit code, none when killed by a signal
+    status: Option<i32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Shell {
+    /// Name given to the model, e.g. bash or powershell
+    pub name: String,
+    /// Program and argument that run a command
+    pub cmd: String,
+    pub arg: String,
+}
+
+/// Shell set in the config, or the one the user is working in
+pub fn resolve_shell(config: Option<&str>) -> Shell {
+    match config {
+        Some(shell) => Shell::from_program(shell),
+        None => detect_shell(),
+    }
+}
+
+/// Shell the user is working in
+fn detect_shell() -> Shell {
+    if cfg!(windows) {
+        // PSModulePath lists at least three entries inside powershell, fewer in cmd
+        match env::var("PSModulePath") {
+            Ok(v) if v.split(';').count() >= 3 => {
+                // pwsh keeps its modules under `PowerShell`, windows powershell under `WindowsPowerShell`
+                if v.split(';').any(|v| v.ends_with("\\PowerShell\\Modules")) {
+                    Shell::from_program("pwsh")
+                } else {
+                    Shell::from_program("powershell")
+                }
+            }
+            _ => Shell::from_program("cmd"),
+        }
+    } else {
+        Shell::from_program(&env::var("SHELL").unwrap_or_else(|_| "/bin/sh".into()))
+    }
+}
+
+impl Shell {
+    /// Shell run by a program given as a name or a path
+    fn from_program(program: &str) -> Self {
+        let name = program
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let name = name.trim_end_matches(".exe").to_string();
+        let arg = match name.as_str() {
+            "powershell" | "pwsh" => "-Command",
+            "cmd" => "/C",
+            _ => "-c",
+        };
+        Self {
+            name,
+            cmd: program.into(),
+            arg: arg.into(),
+        }
+    }
+
+    /// Syntax the commands should follow in this shell
+    fn idioms(&self) -> &'static str {
+        match self.name.as_str() {
+            "powershell" | "pwsh" => {
+                "Use PowerShell cmdlets and pipelines rather than unix tools, \
+and separate steps with ;."
+            }
+            "cmd" => "Use cmd.exe builtins and syntax, and combine steps with &&.",
+            "fish" => {
+                "Use fish syntax: `set` for variables, `(cmd)` for substitution, \
+and combine steps with && or `; and`."
+            }
+            "nu" => {
+                "Use nushell commands working on structured data, \
+and separate steps with ;."
+            }
+            _ => "Combine steps with && and use POSIX syntax the shell accepts.",
+        }
+    }
+
+    fn prompt(&self) -> String {
+        format!(
+            "Provide only {} commands for {} without any description. \
+If there is a lack of details, provide the most logical solution. \
+Ensure the output is a valid {} command. \
+{} \
+Provide only plain text without Markdown formatting.",
+            self.name,
+            os_version(),
+            self.name,
+            self.idioms()
+        )
+    }
+}
+
+/// Name and version of the operating system, as far as they can be found cheaply
+pub fn os_version() -> String {
+    let os = env::consts::OS;
+    let version = match os {
+        "linux" => fs::read_to_string("/etc/os-release").ok().and_then(|v| {
+            v.lines()
+                .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+                .map(|v| v.trim_matches('"').to_string())
+        }),
+        "macos" => Command::new("sw_vers")
+            .arg("-productVersion")
+            .output()
+            .ok()
+            .map(|v| format!("macOS {}", String::from_utf8_lossy(&v.stdout).trim())),
+        "windows" => Command::new("cmd")
+            .args(["/C", "ver"])
+            .output()
+            .ok()
+            .map(|v| String::from_utf8_lossy(&v.stdout).trim().to_string()),
+        _ => None,
+    };
+    version
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| os.into())
+}
+
+/// Generate a command for the task, then offer to execute, describe, revise or copy it
+pub fn execute(client: &ChatGptClient, config: &SharedConfig, task: &str) -> Result<()> {
+    let shell = resolve_shell(config.lock().shell.as_deref());
+    let command = generate(client, config, &shell, task)?;
+    if config.lock().dry_run {
+        // The command is the request body, there is nothing to run
+        print_now!("{command}\n");
+        return Ok(());
+    }
+    offer(client, config, &shell, task, command)
+}
+
+/// Pick a command from the log of executed commands and offer it again
+pub fn history(client: &ChatGptClient, config: &SharedConfig) -> Result<()> {
+    let mut entries = load_history()?;
+    if entries.is_empty() {
+        bail!("No executed commands yet");
+    }
+    entries.reverse();
+    if !stdin().is_terminal() {
+        for entry in entries.iter() {
+            print_now!("{}\t{}\n", entry.time, entry.command);
+        }
+        return Ok(());
+    }
+    let options: Vec<String> = entries
+        .iter()
+        .map(|v| format!("{}  {}", v.time, v.command.replace('\n', " ")))
+        .collect();
+    let index = match Select::new("Run again:", options).raw_prompt() {
+        Ok(v) => v.index,
+        Err(_) => return Ok(()),
+    };
+    let entry = entries.swap_remove(index);
+    let shell = Shell::from_program(&entry.shell);
+    offer(client, config, &shell, &entry.task, entry.command)
+}
+
+fn offer(
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    shell: &Shell,
+    task: &str,
+    mut command: String,
+) -> Result<()> {
+    loop {
+        print_now!("{command}\n");
+        if !stdin().is_terminal() {
+            return Ok(());
+        }
+        let answer = Text::new("[e]xecute, [d]escribe, [r]evise, [c]opy, [a]bort:")
+            .with_default("e")
+            .prompt()
+            .unwrap_or_else(|_| "a".into());
+        match answer.trim() {
+            "e" => return run(shell, task, &command),
+            "d" => {
+                let prompt = format!("{DESCRIBE_PROMPT} The command is for {}.", shell.name);
+                let description = ask(client, config, &prompt, &command)?;
+                print_now!("\n{}\n\n", description.trim());
+            }
+            "r" => {
+                let revision = Text::new("Revise:").prompt().unwrap_or_default();
+                if revision.trim().is_empty() {
+                    return Ok(());
+                }
+                let input = format!(
+                    "{task}\n\nThe previous command was:\n{command}\n\nRevise it: {revision}"
+                );
+                command = generate(client, config, shell, &input)?;
+            }
+            "c" => {
+                copy_text(&command)?;
+                print_now!("Copied to the clipboard\n");
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn generate(
+    client: &ChatGptClient,
+    config: &SharedConfig,
+    shell: &Shell,
+    input: &str,
+) -> Result<String> {
+    let reply = ask(client, config, &shell.prompt(), input)?;
+    let command = extract_code_block(&reply).unwrap_or(reply);
+    let command = command.trim();
+    if command.is_empty() {
+        bail!("Error: No command in the reply");
+    }
+    Ok(command.to_string())
+}
+
+/// Send the input under the prompt, leaving the current role and conversation untouched
+fn ask(client: &ChatGptClient, config: &SharedConfig, prompt: &str, input: &str) -> Result<String> {
+    let (role, conversation) = {
+        let mut config = config.lock();
+        (config.role.take(), config.conversation.take())
+    };
+    let ret = {
+        let mut config = config.lock();
+        config.create_temp_role(prompt).map(|_| {
+            let model = config.shell_model.clone();
+            if let Some(role) = config.role.as_mut() {
+                role.model = model;
+            }
+        })
+    };
+    let ret = ret.and_then(|_| client.send_message(input));
+    {
+        let mut config = config.lock();
+        config.role = role;
+        config.conversation = conversation;
+    }
+    ret
+}
+
+fn run(shell: &Shell, task: &str, command: &str) -> Result<()> {
+    info!("Running with {}: {command}", shell.cmd);
+    let status = Command::new(&shell.cmd)
+        .arg(&shell.arg)
+        .arg(command)
+        .status()
+        .with_context(|| format!("Failed to run {}", shell.cmd))?;
+    let entry = ExecutedCommand {
+        time: now(),
+        dir: env::current_dir()
+            .map(|v| v.display().to_string())
+            .unwrap_or_default(),
+        shell: shell.cmd.clone(),
+        task: task.to_string(),
+        command: command.to_string(),
+        status: status.code(),
+    };
+    if let Err(err) = save_history(&entry) {
+        error!("{err:#}");
+    }
+    if !status.success() {
+        bail!("Command exited with {status}");
+    }
+    Ok(())
+}
+
+fn load_history() -> Result<Vec<ExecutedCommand>> {
+    let path = Config::commands_file()?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to load commands at {}", path.display()))?;
+    // Skip lines that fail to parse, e.g. cut short by a crash, rather than the whole log
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn save_history(entry: &ExecutedCommand) -> Result<()> {
+    let path = Config::commands_file()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let line = serde_json::to_string(entry)?;
+    writeln!(file, "{line}").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_program() {
+        let shell = Shell::from_program("/usr/local/bin/fish");
+        assert_eq!((shell.name.as_str(), shell.arg.as_str()), ("fish", "-c"));
+        let shell = Shell::from_program("C:\\Program Files\\PowerShell\\7\\pwsh.exe");
+        assert_eq!(
+            (shell.name.as_str(), shell.arg.as_str()),
+            ("pwsh", "-Command")
+        );
+        let shell = Shell::from_program("cmd");
+        assert_eq!((shell.name.as_str(), shell.arg.as_str()), ("cmd", "/C"));
+    }
+}
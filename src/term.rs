@@ -3,8 +3,42 @@ use crossterm::{
     cursor, queue, style,
     terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
 };
+use is_terminal::IsTerminal;
 use std::io::{self, Stdout, Write};
 
+/// Set the terminal title temporarily, the previous title is restored on drop
+pub struct TerminalTitle {
+    enabled: bool,
+}
+
+impl TerminalTitle {
+    pub fn set(enabled: bool, title: &str) -> Self {
+        let mut stdout = io::stdout();
+        let enabled = enabled && stdout.is_terminal();
+        if enabled {
+            // push current title on the stack
+            let _ = queue!(
+                stdout,
+                style::Print("\x1b[22;0t"),
+                terminal::SetTitle(title)
+            );
+            let _ = stdout.flush();
+        }
+        Self { enabled }
+    }
+}
+
+impl Drop for TerminalTitle {
+    fn drop(&mut self) {
+        if self.enabled {
+            let mut stdout = io::stdout();
+            // pop previous title from the stack
+            let _ = queue!(stdout, style::Print("\x1b[23;0t"));
+            let _ = stdout.flush();
+        }
+    }
+}
+
 pub fn clear_screen(keep_lines: u16) -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
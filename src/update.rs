@@ -0,0 +1,118 @@
+//! Update aichat itself from github releases
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    env::consts::{ARCH, OS},
+    fs,
+    io::{Cursor, Read},
+};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/sigoden/aichat/releases/latest";
+
+pub fn self_update() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new().with_context(|| "Failed to init tokio")?;
+    runtime.block_on(self_update_inner())
+}
+
+async fn self_update_inner() -> Result<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("aichat/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .with_context(|| "Failed to build http client")?;
+    let release: Value = client
+        .get(RELEASES_URL)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| "Failed to fetch the latest release")?;
+    let tag = release["tag_name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Unexpected release {release}"))?;
+    if tag.trim_start_matches('v') == env!("CARGO_PKG_VERSION") {
+        println!("Already up to date ({tag})");
+        return Ok(());
+    }
+
+    let target = current_target()?;
+    let ext = if cfg!(windows) { "zip" } else { "tar.gz" };
+    let name = format!("aichat-{tag}-{target}.{ext}");
+    let find_asset = |name: &str| {
+        release["assets"]
+            .as_array()
+            .and_then(|assets| assets.iter().find(|v| v["name"].as_str() == Some(name)))
+            .and_then(|v| v["browser_download_url"].as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("No release asset '{name}'"))
+    };
+    let archive_url = find_asset(&name)?;
+    let checksum_url = find_asset(&format!("{name}.sha256"))?;
+
+    println!("Downloading {name}");
+    let archive = client
+        .get(&archive_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let checksum = client
+        .get(&checksum_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let expect = checksum
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("Invalid checksum file"))?;
+    let actual = format!("{:x}", Sha256::digest(&archive));
+    if !expect.eq_ignore_ascii_case(&actual) {
+        bail!("Checksum mismatch, expect {expect}, got {actual}");
+    }
+
+    let binary = extract_binary(&archive)?;
+    let tmp_path = std::env::temp_dir().join(format!("aichat-{tag}"));
+    fs::write(&tmp_path, binary)
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    self_replace::self_replace(&tmp_path).with_context(|| "Failed to replace executable")?;
+    let _ = fs::remove_file(&tmp_path);
+    println!("Updated to {tag}");
+    Ok(())
+}
+
+fn current_target() -> Result<String> {
+    let arch = match ARCH {
+        "x86" => "i686",
+        v => v,
+    };
+    let target = match OS {
+        "linux" => format!("{arch}-unknown-linux-musl"),
+        "macos" => format!("{arch}-apple-darwin"),
+        "windows" => format!("{arch}-pc-windows-msvc"),
+        _ => bail!("No prebuilt binary for {arch}-{OS}"),
+    };
+    Ok(target)
+}
+
+fn extract_binary(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut binary = vec![];
+    if cfg!(windows) {
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+        let mut file = zip.by_name("aichat.exe")?;
+        file.read_to_end(&mut binary)?;
+    } else {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+        let mut file = tar
+            .entries()?
+            .filter_map(|v| v.ok())
+            .find(|v| v.path().map(|p| p.ends_with("aichat")).unwrap_or_default())
+            .ok_or_else(|| anyhow!("No executable in the archive"))?;
+        file.read_to_end(&mut binary)?;
+    }
+    Ok(binary)
+}
@@ -0,0 +1,166 @@
+/// Marker standing for the masked part with the index
+pub fn mask_marker(index: usize) -> String {
+    format!("⟦{index}⟧")
+}
+
+/// Replace fenced code blocks, inline code and urls with numbered markers,
+/// return the text and the parts the markers stand for
+pub fn mask_text(text: &str) -> (String, Vec<String>) {
+    let mut output = String::with_capacity(text.len());
+    let mut masks = vec![];
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let line_start = i == 0 || text[..i].ends_with('\n');
+        let len = if line_start && (rest.starts_with("```") || rest.starts_with("~~~")) {
+            fence_len(rest)
+        } else if rest.starts_with('`') {
+            inline_code_len(rest)
+        } else if rest.starts_with("http://") || rest.starts_with("https://") {
+            Some(url_len(rest))
+        } else {
+            None
+        };
+        match len {
+            Some(len) => {
+                output.push_str(&mask_marker(masks.len()));
+                masks.push(rest[..len].to_string());
+                i += len;
+            }
+            None => {
+                let c = rest.chars().next().unwrap_or_default();
+                output.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    (output, masks)
+}
+
+/// Put the masked parts back in place of their markers
+pub fn unmask_text(text: &str, masks: &[String]) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((head, tail)) = rest.split_once('⟦') {
+        output.push_str(head);
+        let mask = tail.split_once('⟧').and_then(|(index, tail)| {
+            let mask = masks.get(index.parse::<usize>().ok()?)?;
+            Some((mask, tail))
+        });
+        match mask {
+            Some((mask, tail)) => {
+                output.push_str(mask);
+                rest = tail;
+            }
+            None => {
+                output.push('⟦');
+                rest = tail;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Restores the markers of a streamed reply, holding back a marker split between chunks
+#[derive(Debug, Default)]
+pub struct Unmasker {
+    masks: Vec<String>,
+    pending: String,
+}
+
+impl Unmasker {
+    pub fn new(masks: Vec<String>) -> Self {
+        Self {
+            masks,
+            pending: String::new(),
+        }
+    }
+
+    /// The text ready to be shown
+    pub fn push(&mut self, text: &str) -> String {
+        if self.masks.is_empty() {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+        let end = match self.pending.rfind('⟦') {
+            Some(i) if !self.pending[i..].contains('⟧') && self.pending.len() - i < 16 => i,
+            _ => self.pending.len(),
+        };
+        let ready: String = self.pending.drain(..end).collect();
+        unmask_text(&ready, &self.masks)
+    }
+
+    /// The text held back once the reply is done
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        unmask_text(&rest, &self.masks)
+    }
+}
+
+/// Up to the end of the closing fence line, or of the text when it is not closed
+fn fence_len(text: &str) -> Option<usize> {
+    let fence = &text[..3];
+    let first_line = text.find('\n')?;
+    let mut offset = first_line + 1;
+    for line in text[offset..].split_inclusive('\n') {
+        offset += line.len();
+        if line.trim_start().starts_with(fence) {
+            return Some(offset - (line.len() - line.trim_end().len()));
+        }
+    }
+    Some(text.len())
+}
+
+/// Up to the closing run of as many backticks
+fn inline_code_len(text: &str) -> Option<usize> {
+    let ticks = text.len() - text.trim_start_matches('`').len();
+    let end = text[ticks..].find(&text[..ticks])?;
+    let code = &text[ticks..ticks + end];
+    if code.contains("\n\n") {
+        return None;
+    }
+    Some(ticks + end + ticks)
+}
+
+/// Up to whitespace or a closing bracket or quote, leaving out trailing punctuation
+fn url_len(text: &str) -> usize {
+    let end = text
+        .find(|c: char| c.is_whitespace() || matches!(c, ')' | ']' | '>' | '"' | '\'' | '`'))
+        .unwrap_or(text.len());
+    text[..end]
+        .trim_end_matches(['.', ',', ';', ':', '!', '?'])
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_text() {
+        let text =
+            "Run `cargo build`, see https://doc.rust-lang.org.\n```rust\nfn main() {}\n```\nDone";
+        let (masked, masks) = mask_text(text);
+        assert_eq!(masked, "Run ⟦0⟧, see ⟦1⟧.\n⟦2⟧\nDone");
+        assert_eq!(
+            masks,
+            vec![
+                "`cargo build`",
+                "https://doc.rust-lang.org",
+                "```rust\nfn main() {}\n```"
+            ]
+        );
+        assert_eq!(unmask_text(&masked, &masks), text);
+    }
+
+    #[test]
+    fn test_unmasker() {
+        let masks = vec!["`a`".to_string(), "`b`".to_string()];
+        let mut unmasker = Unmasker::new(masks);
+        assert_eq!(unmasker.push("x ⟦"), "x ");
+        assert_eq!(unmasker.push("0⟧ y ⟦1"), "`a` y ");
+        assert_eq!(unmasker.push("⟧ ⟦9⟧"), "`b` ⟦9⟧");
+        assert_eq!(unmasker.finish(), "");
+    }
+}
@@ -1,10 +1,19 @@
+mod mask;
+mod profile;
 mod tiktoken;
 
+pub use self::mask::{mask_marker, mask_text, unmask_text, Unmasker};
+pub use self::profile::StartupProfile;
 pub use self::tiktoken::{cl100k_base_singleton, count_tokens, text_to_tokens, tokens_to_text};
 
+use anyhow::{anyhow, bail, Context};
 use chrono::prelude::*;
 use crossterm::style::{Color, Stylize};
+use std::env;
+use std::fs;
 use std::io::{stdout, Write};
+use std::path::Path;
+use std::process::{self, Command};
 
 #[macro_export]
 macro_rules! print_now {
@@ -23,7 +32,316 @@ pub fn now() -> String {
     now.to_rfc3339_opts(SecondsFormat::Secs, false)
 }
 
+/// Open the file in $VISUAL or $EDITOR and wait for it to close
+pub fn edit_file(path: &Path) -> anyhow::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".into()
+            } else {
+                "vi".into()
+            }
+        });
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or_else(|| anyhow!("Invalid editor"))?;
+    let status = Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor '{editor}'"))?;
+    if !status.success() {
+        bail!("Editor '{editor}' exited with {status}");
+    }
+    Ok(())
+}
+
+/// Let the user edit the text in a temporary file, `ext` helps the editor pick a syntax
+pub fn edit_text(text: &str, ext: &str) -> anyhow::Result<String> {
+    let path = env::temp_dir().join(format!("aichat-{}.{ext}", process::id()));
+    fs::write(&path, text).with_context(|| format!("Failed to create {}", path.display()))?;
+    let ret = edit_file(&path)
+        .and_then(|_| fs::read_to_string(&path).with_context(|| "Failed to read the edited text"));
+    let _ = fs::remove_file(&path);
+    ret
+}
+
 #[allow(unused)]
 pub fn emphasis(text: &str) -> String {
     text.stylize().with(Color::White).to_string()
 }
+
+/// Format text as a markdown blockquote
+pub fn quote_text(text: &str) -> String {
+    text.trim()
+        .lines()
+        .map(|v| {
+            if v.is_empty() {
+                ">".into()
+            } else {
+                format!("> {v}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Get the content of the first fenced code block
+pub fn extract_code_block(text: &str) -> Option<String> {
+    extract_code_blocks(text).into_iter().next()
+}
+
+/// Get the contents of the fenced code blocks, an unclosed block runs to the end
+pub fn extract_code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut code: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            match code.take() {
+                Some(lines) => blocks.push(lines.join("\n")),
+                None => code = Some(vec![]),
+            }
+        } else if let Some(lines) = code.as_mut() {
+            lines.push(line);
+        }
+    }
+    if let Some(lines) = code {
+        blocks.push(lines.join("\n"));
+    }
+    blocks
+}
+
+/// Guess the language of raw code, return None if the text does not look like code
+pub fn detect_code_language(text: &str) -> Option<&'static str> {
+    let text = text.trim();
+    if text.lines().count() < 2 || text.contains("```") {
+        return None;
+    }
+    let first_line = text.lines().next().unwrap_or_default();
+    if first_line.starts_with("#!") {
+        let lang = if first_line.contains("python") {
+            "python"
+        } else if first_line.contains("node") {
+            "javascript"
+        } else {
+            "bash"
+        };
+        return Some(lang);
+    }
+    if (text.starts_with('{') || text.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+    {
+        return Some("json");
+    }
+    let has = |patterns: &[&str]| patterns.iter().any(|v| text.contains(v));
+    let lang = if has(&["fn ", "let mut ", "impl ", "pub struct "]) && has(&["{"]) {
+        "rust"
+    } else if has(&["package main", "func "]) && has(&[":= ", "package "]) {
+        "go"
+    } else if has(&["#include"]) {
+        "cpp"
+    } else if has(&["public class ", "public static void "]) {
+        "java"
+    } else if has(&["def ", "import "]) && text.lines().any(|v| v.trim_end().ends_with(':')) {
+        "python"
+    } else if has(&["function ", "=> ", "const ", "console.log"]) && has(&[";", "{"]) {
+        "javascript"
+    } else if has(&["<html", "<div", "<body", "</"]) && text.starts_with('<') {
+        "html"
+    } else if has(&["SELECT ", "INSERT INTO ", "CREATE TABLE "]) {
+        "sql"
+    } else {
+        return None;
+    };
+    Some(lang)
+}
+
+/// Settings of a single message, given as inline directives
+#[derive(Debug, Default, PartialEq)]
+pub struct Directives {
+    /// Set by `/t=0.9`
+    pub temperature: Option<f64>,
+    /// Set by `@name`, `@name:model` or `@alias`
+    pub model: Option<String>,
+}
+
+/// Strip leading inline directives like `/t=0.9` and `@model` from the input
+pub fn parse_directives(input: &str) -> anyhow::Result<(String, Directives)> {
+    let mut input = input.trim_start();
+    let mut directives = Directives::default();
+    loop {
+        let (value, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        if let Some(value) = value.strip_prefix("/t=") {
+            let value: f64 = value
+                .parse()
+                .ok()
+                .filter(|v| (0.0..=2.0).contains(v))
+                .ok_or_else(|| anyhow!("Invalid temperature `{value}`, must be 0 to 2"))?;
+            directives.temperature = Some(value);
+        } else if let Some(value) = value.strip_prefix('@').filter(|v| !v.is_empty()) {
+            directives.model = Some(value.to_string());
+        } else {
+            break;
+        }
+        input = rest.trim_start();
+    }
+    Ok((input.to_string(), directives))
+}
+
+/// Whether the text matches a glob of `*`, any characters, and `?`, one character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    // The last `*` and the text position it was tried at, to backtrack to
+    let (mut p, mut t, mut star) = (0, 0, None);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The input between the texts of `--prepend` and `--append` or of a role, on their own lines
+pub fn wrap_input(prepend: Option<&str>, input: &str, append: Option<&str>) -> String {
+    let mut parts = vec![];
+    if let Some(prepend) = prepend {
+        parts.push(prepend.trim());
+    }
+    parts.push(input);
+    if let Some(append) = append {
+        parts.push(append.trim());
+    }
+    parts.join("\n")
+}
+
+/// Strip ANSI escape sequences and control characters other than newlines and tabs,
+/// e.g. the colors and progress bars of command output piped in
+pub fn strip_ansi(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI, parameters until a final byte in @ to ~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC, e.g. hyperlinks and titles, until BEL or ST
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\t' => output.push(c),
+            c if c.is_control() => {}
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_directives() {
+        let directives = |temperature, model: Option<&str>| Directives {
+            temperature,
+            model: model.map(|v| v.into()),
+        };
+        assert_eq!(
+            parse_directives("/t=0.9 write a poem").unwrap(),
+            ("write a poem".into(), directives(Some(0.9), None))
+        );
+        assert_eq!(
+            parse_directives("@openai:gpt-4o-mini /t=0 write a poem").unwrap(),
+            (
+                "write a poem".into(),
+                directives(Some(0.0), Some("openai:gpt-4o-mini"))
+            )
+        );
+        assert_eq!(
+            parse_directives("write a poem /t=0.9 @fast").unwrap(),
+            ("write a poem /t=0.9 @fast".into(), directives(None, None))
+        );
+        assert!(parse_directives("/t=3 write a poem").is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("src/*/*.rs", "src/config/mod.rs"));
+        assert!(glob_match("Dockerfile*", "Dockerfile"));
+        assert!(glob_match("test_?.py", "test_a.py"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert!(!glob_match("test_?.py", "test_ab.py"));
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("\x1b[1;31merror\x1b[0m: failed\r\n\tat main.rs"),
+            "error: failed\n\tat main.rs"
+        );
+        assert_eq!(
+            strip_ansi("\x1b]8;;https://a.b\x1b\\link\x1b]8;;\x07 50%\r100%\x08"),
+            "link 50%100%"
+        );
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let text = "Build it:\n```sh\ncargo build\n```\nthen run:\n```\ncargo run\n";
+        assert_eq!(extract_code_blocks(text), vec!["cargo build", "cargo run"]);
+        assert_eq!(extract_code_block(text), Some("cargo build".into()));
+    }
+
+    #[test]
+    fn test_detect_code_language() {
+        assert_eq!(
+            detect_code_language("fn main() {\n    println!(\"hi\");\n}"),
+            Some("rust")
+        );
+        assert_eq!(
+            detect_code_language("def add(a, b):\n    return a + b"),
+            Some("python")
+        );
+        assert_eq!(detect_code_language("{\n  \"a\": 1\n}"), Some("json"));
+        assert_eq!(detect_code_language("#!/bin/sh\necho hi"), Some("bash"));
+        assert_eq!(detect_code_language("fn main() {}"), None);
+        assert_eq!(
+            detect_code_language("explain this:\n```rust\nfn main() {}\n```"),
+            None
+        );
+        assert_eq!(
+            detect_code_language("Dear team,\nthe meeting moved to friday."),
+            None
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+/// Time spent in each phase of the startup, printed to stderr with `--profile-startup`
+#[derive(Debug)]
+pub struct StartupProfile {
+    enabled: bool,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Default for StartupProfile {
+    fn default() -> Self {
+        Self::new(false, Instant::now())
+    }
+}
+
+impl StartupProfile {
+    pub fn new(enabled: bool, start: Instant) -> Self {
+        Self {
+            enabled,
+            last: start,
+            phases: vec![],
+        }
+    }
+
+    /// End the phase that started with the previous one
+    pub fn phase(&mut self, name: &'static str) {
+        if self.enabled {
+            let now = Instant::now();
+            self.phases.push((name, now - self.last));
+            self.last = now;
+        }
+    }
+
+    /// Print the phases and their total, only the first time
+    pub fn report(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.enabled = false;
+        let width = self
+            .phases
+            .iter()
+            .map(|(v, _)| v.len())
+            .fold("total".len(), usize::max);
+        let mut output = String::from("Startup profile:\n");
+        for (name, duration) in &self.phases {
+            output.push_str(&format!(
+                "  {name:<width$}  {}\n",
+                format_duration(duration)
+            ));
+        }
+        let total: Duration = self.phases.iter().map(|(_, v)| *v).sum();
+        output.push_str(&format!(
+            "  {:<width$}  {}",
+            "total",
+            format_duration(&total)
+        ));
+        eprintln!("{output}");
+    }
+}
+
+fn format_duration(duration: &Duration) -> String {
+    format!("{:>9.2}ms", duration.as_secs_f64() * 1000.0)
+}